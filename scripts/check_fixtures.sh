@@ -6,42 +6,83 @@ cargo build --release >/dev/null
 
 BIN="./target/release/transactions-ledger"
 TMP="$(mktemp -t tl_out.XXXXXX.csv)"
+BIN_IN="$(mktemp -t tl_in.XXXXXX.bin)"
 
 # Collect inputs in a Bash 3.2 friendly way
 inputs=()
 while IFS= read -r f; do
   inputs+=("$f")
-done < <(find fixtures -type f -name 'input_*.csv' | sort)
+done < <(find fixtures -type f \( -name 'input_*.csv' -o -name 'input_*.jsonl' \) | sort)
 
 if [ "${#inputs[@]}" -eq 0 ]; then
   echo "No fixture inputs found under fixtures/"
-  rm -f "$TMP"
+  rm -f "$TMP" "$BIN_IN"
   exit 1
 fi
 
 for in_file in "${inputs[@]}"; do
+  # JSON Lines inputs are checked against the same CSV output
   exp_file="${in_file/input_/expected_}"
+  exp_file="${exp_file%.jsonl}"
+  exp_file="${exp_file%.csv}.csv"
 
   if [ ! -f "$exp_file" ]; then
     echo "Missing expected file for $in_file"
     echo "Looked for: $exp_file"
-    rm -f "$TMP"
+    rm -f "$TMP" "$BIN_IN"
     exit 1
   fi
 
-  echo "Checking $in_file"
-  "$BIN" "$in_file" > "$TMP"
+  # CSV in some other layout comes with a dialect.toml next to it
+  dialect=()
+  if [ -f "$(dirname "$in_file")/dialect.toml" ]; then
+    dialect=(--csv-dialect "$(dirname "$in_file")/dialect.toml")
+  fi
+
+  # sequentially, then sharded across workers; both must match byte for byte
+  for flags in "" "--workers 4"; do
+    echo "Checking $in_file ${flags}"
+    # shellcheck disable=SC2086
+    "$BIN" "$in_file" ${dialect[@]+"${dialect[@]}"} $flags > "$TMP"
+
+    if diff -u "$exp_file" "$TMP" >/dev/null; then
+      echo "  PASS"
+    else
+      echo "  FAIL"
+      diff -u "$exp_file" "$TMP" || true
+      rm -f "$TMP" "$BIN_IN"
+      exit 1
+    fi
+  done
+
+  # compressed on stdin
+  echo "Checking $in_file gzipped on stdin"
+  format="csv"
+  case "$in_file" in *.jsonl) format="jsonl" ;; esac
+  gzip -c "$in_file" | "$BIN" - --input-format "$format" ${dialect[@]+"${dialect[@]}"} > "$TMP"
+  if diff -u "$exp_file" "$TMP" >/dev/null; then
+    echo "  PASS"
+  else
+    echo "  FAIL"
+    diff -u "$exp_file" "$TMP" || true
+    rm -f "$TMP" "$BIN_IN"
+    exit 1
+  fi
 
+  # and once more from the binary format; rows that fail to convert would be skipped anyway
+  echo "Checking $in_file via binary"
+  "$BIN" convert "$in_file" "$BIN_IN" ${dialect[@]+"${dialect[@]}"} 2>/dev/null
+  "$BIN" "$BIN_IN" > "$TMP"
   if diff -u "$exp_file" "$TMP" >/dev/null; then
     echo "  PASS"
   else
     echo "  FAIL"
     diff -u "$exp_file" "$TMP" || true
-    rm -f "$TMP"
+    rm -f "$TMP" "$BIN_IN"
     exit 1
   fi
 done
 
-rm -f "$TMP"
+rm -f "$TMP" "$BIN_IN"
 echo "All fixtures passed."
 
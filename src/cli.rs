@@ -0,0 +1,314 @@
+use transactions_ledger::core::types::{Asset, Precisions, RoundingPolicy};
+use transactions_ledger::engine::{DisputeWindow, MAX_WORKERS};
+use transactions_ledger::io::CsvDialect;
+use transactions_ledger::io::input::STDIN;
+
+pub const USAGE: &str = "usage: transactions_ledger <input.csv|input.jsonl|input.bin|->... [--input-format <csv|jsonl|binary>] [--csv-dialect <dialect.toml>] [--output-format <csv|jsonl|binary|arrow|parquet>] [--rejects <rejects.csv>] [--journal <journal.csv|journal.arrow|journal.parquet>] [--resume <snapshot>] [--snapshot <snapshot>] [--store <txs.db>] [--precision <ASSET=PLACES>]... [--rounding <reject|half-even|half-up|truncate>] [--rounding-log <rounding.csv>] [--dispute-policy <deposits|deposits-and-withdrawals>] [--dispute-window <EVENTS|SECONDSs>] [--evict-expired] [--reorder <SECONDS>] [--workers <N>] [--strict]
+       transactions_ledger serve <ADDR> [--checkpoint <snapshot>] [--checkpoint-every <EVENTS>] [--precision <ASSET=PLACES>]... [--rounding <reject|half-even|half-up|truncate>] [--dispute-policy <deposits|deposits-and-withdrawals>] [--dispute-window <EVENTS|SECONDSs>] [--evict-expired]
+       transactions_ledger convert <INPUT>... <OUTPUT> [--input-format <csv|jsonl|binary>] [--csv-dialect <dialect.toml>] [--output-format <csv|binary>] [--precision <ASSET=PLACES>]... [--rounding <reject|half-even|half-up|truncate>] [--rejects <rejects.csv>] [--rounding-log <rounding.csv>] [--reorder <SECONDS>] [--strict]";
+
+/// A transaction or account file format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Csv,
+    /// Newline-delimited JSON.
+    JsonLines,
+    /// Fixed-width records, see `io::formats::binary`.
+    Binary,
+    /// The Arrow IPC file format; output only.
+    Arrow,
+    /// Output only.
+    Parquet,
+}
+
+impl Format {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "csv" => Some(Format::Csv),
+            "jsonl" | "ndjson" => Some(Format::JsonLines),
+            "binary" | "bin" => Some(Format::Binary),
+            "arrow" | "ipc" | "feather" => Some(Format::Arrow),
+            "parquet" => Some(Format::Parquet),
+            _ => None,
+        }
+    }
+
+    /// By extension, under any `.gz` or `.zst`; anything unrecognised is CSV.
+    pub fn of_path(path: &str) -> Self {
+        let mut path = std::path::Path::new(path);
+        if let Some(ext) = path.extension()
+            && (ext == "gz" || ext == "zst")
+        {
+            path = std::path::Path::new(path.file_stem().unwrap_or_default());
+        }
+        match path.extension() {
+            Some(ext) if ext == "jsonl" || ext == "ndjson" => Format::JsonLines,
+            Some(ext) if ext == "bin" => Format::Binary,
+            Some(ext) if ext == "arrow" || ext == "feather" => Format::Arrow,
+            Some(ext) if ext == "parquet" => Format::Parquet,
+            _ => Format::Csv,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Args {
+    /// Read one after another as a single stream; `-` is stdin.
+    pub inputs: Vec<String>,
+    /// Set by `--input-format`; see `input_format()`.
+    pub forced_input_format: Option<Format>,
+    /// Loaded from `--csv-dialect`; the default reads the standard layout.
+    pub csv_dialect: CsvDialect,
+    /// Taken from the output's extension when converting, otherwise CSV unless given.
+    pub output_format: Format,
+    pub rejects: Option<String>,
+    pub journal: Option<String>,
+    pub resume: Option<String>,
+    pub snapshot: Option<String>,
+    pub store: Option<String>,
+    pub precisions: Precisions,
+    pub rounding: RoundingPolicy,
+    pub rounding_log: Option<String>,
+    pub withdrawal_disputes: bool,
+    pub dispute_window: Option<DisputeWindow>,
+    pub evict_expired: bool,
+    pub reorder: Option<u64>,
+    pub workers: Option<usize>,
+    pub strict: bool,
+    /// Set by `serve <ADDR>`: listen there instead of processing a file.
+    pub serve: Option<String>,
+    pub checkpoint: Option<String>,
+    pub checkpoint_every: Option<u64>,
+    /// Set by `convert <INPUT> <OUTPUT>`: rewrite the input's transactions there.
+    pub convert: Option<String>,
+}
+
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut inputs = Vec::new();
+        let mut output_format = None;
+        let mut converting = false;
+        let mut parsed = Args::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--input-format" => {
+                    parsed.forced_input_format = Some(format(&value(&mut args, &arg)?)?)
+                }
+                "--csv-dialect" => {
+                    let path = value(&mut args, &arg)?;
+                    parsed.csv_dialect = CsvDialect::load(&path)
+                        .map_err(|e| format!("--csv-dialect: {}: {}", path, e))?;
+                }
+                "--output-format" => output_format = Some(format(&value(&mut args, &arg)?)?),
+                "--rejects" => parsed.rejects = Some(value(&mut args, &arg)?),
+                "--journal" => parsed.journal = Some(value(&mut args, &arg)?),
+                "--resume" => parsed.resume = Some(value(&mut args, &arg)?),
+                "--snapshot" => parsed.snapshot = Some(value(&mut args, &arg)?),
+                "--store" => parsed.store = Some(value(&mut args, &arg)?),
+                "--precision" => {
+                    let (asset, places) = precision(&value(&mut args, &arg)?)?;
+                    parsed
+                        .precisions
+                        .insert(asset, places)
+                        .map_err(|e| format!("--precision: {}", e))?;
+                }
+                "--rounding" => {
+                    let policy = value(&mut args, &arg)?;
+                    parsed.rounding = RoundingPolicy::parse(&policy)
+                        .ok_or_else(|| format!("unknown rounding policy {}", policy))?;
+                }
+                "--rounding-log" => parsed.rounding_log = Some(value(&mut args, &arg)?),
+                "--dispute-policy" => {
+                    parsed.withdrawal_disputes = match value(&mut args, &arg)?.as_str() {
+                        "deposits" => false,
+                        "deposits-and-withdrawals" => true,
+                        other => return Err(format!("unknown dispute policy {}", other)),
+                    }
+                }
+                "--dispute-window" => {
+                    parsed.dispute_window = Some(dispute_window(&value(&mut args, &arg)?)?)
+                }
+                "--evict-expired" => parsed.evict_expired = true,
+                "--reorder" => {
+                    let lateness = value(&mut args, &arg)?;
+                    let lateness = lateness.parse().map_err(|_| {
+                        format!("--reorder expects a number of seconds, got {}", lateness)
+                    })?;
+                    parsed.reorder = Some(lateness);
+                }
+                "--workers" => {
+                    let workers = value(&mut args, &arg)?;
+                    parsed.workers = match workers.parse() {
+                        Ok(n @ 1..=MAX_WORKERS) => Some(n),
+                        _ => {
+                            return Err(format!(
+                                "--workers expects 1 to {}, got {}",
+                                MAX_WORKERS, workers
+                            ));
+                        }
+                    };
+                }
+                "--strict" => parsed.strict = true,
+                "--checkpoint" => parsed.checkpoint = Some(value(&mut args, &arg)?),
+                "--checkpoint-every" => {
+                    let every = value(&mut args, &arg)?;
+                    parsed.checkpoint_every = match every.parse() {
+                        Ok(n @ 1..) => Some(n),
+                        _ => {
+                            return Err(format!(
+                                "--checkpoint-every expects a number of events, got {}",
+                                every
+                            ));
+                        }
+                    };
+                }
+                "serve" if inputs.is_empty() && parsed.serve.is_none() && !converting => {
+                    parsed.serve = Some(value(&mut args, &arg)?)
+                }
+                "convert" if inputs.is_empty() && parsed.serve.is_none() && !converting => {
+                    converting = true
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown flag {}", flag)),
+                _ => inputs.push(arg),
+            }
+        }
+
+        if parsed.evict_expired && parsed.dispute_window.is_none() {
+            return Err("--evict-expired needs --dispute-window".to_string());
+        }
+        if parsed.workers.is_some() {
+            let sequential_only = [
+                ("--journal", parsed.journal.is_some()),
+                ("--resume", parsed.resume.is_some()),
+                ("--snapshot", parsed.snapshot.is_some()),
+                ("--store", parsed.store.is_some()),
+                ("--evict-expired", parsed.evict_expired),
+            ];
+            if let Some((flag, _)) = sequential_only.iter().find(|(_, set)| *set) {
+                return Err(format!("--workers can't be combined with {}", flag));
+            }
+        }
+        if parsed.serve.is_some() {
+            let one_shot_only = [
+                ("--rejects", parsed.rejects.is_some()),
+                ("--journal", parsed.journal.is_some()),
+                ("--resume", parsed.resume.is_some()),
+                ("--snapshot", parsed.snapshot.is_some()),
+                ("--store", parsed.store.is_some()),
+                ("--rounding-log", parsed.rounding_log.is_some()),
+                ("--reorder", parsed.reorder.is_some()),
+                ("--workers", parsed.workers.is_some()),
+                ("--strict", parsed.strict),
+                ("--csv-dialect", parsed.csv_dialect != CsvDialect::default()),
+            ];
+            if let Some((flag, _)) = one_shot_only.iter().find(|(_, set)| *set) {
+                return Err(format!("serve can't be combined with {}", flag));
+            }
+            if parsed.checkpoint_every.is_some() && parsed.checkpoint.is_none() {
+                return Err("--checkpoint-every needs --checkpoint".to_string());
+            }
+            if let Some(input) = inputs.first() {
+                return Err(format!("unexpected argument {}", input));
+            }
+            return Ok(parsed);
+        }
+        if parsed.checkpoint.is_some() || parsed.checkpoint_every.is_some() {
+            return Err("--checkpoint and --checkpoint-every need serve".to_string());
+        }
+        if converting {
+            if inputs.len() < 2 {
+                return Err("convert needs input and output paths".to_string());
+            }
+            parsed.convert = inputs.pop();
+        }
+        if inputs.is_empty() {
+            return Err("missing input path".to_string());
+        }
+        if inputs.iter().filter(|i| *i == STDIN).count() > 1 {
+            return Err("stdin can only be read once".to_string());
+        }
+        parsed.inputs = inputs;
+        parsed.output_format = output_format.unwrap_or_default();
+        if parsed
+            .inputs
+            .iter()
+            .any(|i| matches!(parsed.input_format(i), Format::Arrow | Format::Parquet))
+        {
+            return Err("arrow and parquet are output formats".to_string());
+        }
+
+        if let Some(output) = &parsed.convert {
+            parsed.output_format = output_format.unwrap_or_else(|| Format::of_path(output));
+            if output.ends_with(".gz") || output.ends_with(".zst") {
+                return Err("convert doesn't compress its output".to_string());
+            }
+            if !matches!(parsed.output_format, Format::Csv | Format::Binary) {
+                return Err("convert writes csv or binary".to_string());
+            }
+            let run_only = [
+                ("--journal", parsed.journal.is_some()),
+                ("--resume", parsed.resume.is_some()),
+                ("--snapshot", parsed.snapshot.is_some()),
+                ("--store", parsed.store.is_some()),
+                ("--dispute-policy", parsed.withdrawal_disputes),
+                ("--dispute-window", parsed.dispute_window.is_some()),
+                ("--workers", parsed.workers.is_some()),
+            ];
+            if let Some((flag, _)) = run_only.iter().find(|(_, set)| *set) {
+                return Err(format!("convert can't be combined with {}", flag));
+            }
+        }
+
+        let arrow = [
+            Some(parsed.output_format),
+            parsed.journal.as_deref().map(Format::of_path),
+        ]
+        .into_iter()
+        .any(|f| matches!(f, Some(Format::Arrow | Format::Parquet)));
+        if arrow && cfg!(not(feature = "arrow")) {
+            return Err("arrow and parquet output need the arrow feature".to_string());
+        }
+        Ok(parsed)
+    }
+}
+
+impl Args {
+    /// `--input-format` if given, otherwise by the input's extension.
+    pub fn input_format(&self, input: &str) -> Format {
+        self.forced_input_format
+            .unwrap_or_else(|| Format::of_path(input))
+    }
+}
+
+fn format(spec: &str) -> Result<Format, String> {
+    Format::parse(spec).ok_or_else(|| {
+        format!(
+            "unknown format {}, expected csv, jsonl, binary, arrow or parquet",
+            spec
+        )
+    })
+}
+
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", flag))
+}
+
+// "100" is 100 events, "3600s" an hour by the input's timestamps
+fn dispute_window(spec: &str) -> Result<DisputeWindow, String> {
+    let err = || format!("--dispute-window expects EVENTS or SECONDSs, got {}", spec);
+    match spec.strip_suffix('s') {
+        Some(secs) => secs.parse().map(DisputeWindow::Seconds).map_err(|_| err()),
+        None => spec.parse().map(DisputeWindow::Events).map_err(|_| err()),
+    }
+}
+
+// "BTC=8"; an empty asset ("=2") sets the default asset's precision
+fn precision(spec: &str) -> Result<(Asset, u32), String> {
+    let err = || format!("--precision expects ASSET=PLACES, got {}", spec);
+    let (asset, places) = spec.split_once('=').ok_or_else(err)?;
+    let asset = Asset::parse(asset).map_err(|e| format!("--precision: {}", e))?;
+    let places = places.trim().parse().map_err(|_| err())?;
+    Ok((asset, places))
+}
@@ -1,10 +1,17 @@
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CoreError {
     ParseAmount,
     NegativeAmount,
+    TooManyDecimals,
+    AmountOutOfRange,
+    PrecisionOutOfRange,
+    InvalidAsset,
     UnknownTransactionType,
+    UnexpectedAmount,
+    InvalidTimestamp,
+    InvalidRecord(String), // the CSV reader's own description of what was wrong with the row
 }
 
 impl fmt::Display for CoreError {
@@ -12,34 +19,103 @@ impl fmt::Display for CoreError {
         match self {
             CoreError::ParseAmount => write!(f, "failed to parse amount"),
             CoreError::NegativeAmount => write!(f, "amount must be non-negative"),
+            CoreError::TooManyDecimals => write!(f, "too many decimal places"),
+            CoreError::AmountOutOfRange => write!(f, "amount out of range"),
+            CoreError::PrecisionOutOfRange => {
+                write!(f, "precision must be at most 18 decimal places")
+            }
+            CoreError::InvalidAsset => write!(f, "asset code must be up to 8 letters or digits"),
             CoreError::UnknownTransactionType => write!(f, "unknown transaction type"),
+            CoreError::UnexpectedAmount => write!(f, "amount given on a row that doesn't take one"),
+            CoreError::InvalidTimestamp => {
+                write!(f, "timestamp must be whole seconds since the Unix epoch")
+            }
+            CoreError::InvalidRecord(msg) => write!(f, "invalid record: {}", msg),
         }
     }
 }
 
 impl std::error::Error for CoreError {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LedgerError {
     DisputeOnWithdrawal,
+    NotAWithdrawal,
     InsufficientFunds,
     TxAlreadyDisputed,
     TxNotDisputed,
+    ExceedsUndisputed,
+    ExceedsDisputed,
     TxWrongClient,
+    NotChargedBack,
+    NotRepresented,
+    NotInPreArbitration,
+    DisputeEscalated,
+    DisputeClosed,
+    PartialEscalation,
+    DisputeWindowExpired,
+    AccountAlreadyLocked,
+    AccountNotLocked,
     Overflow,
 }
 
 impl fmt::Display for LedgerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            LedgerError::DisputeOnWithdrawal => write!(f, "you can't dispute a withdrawal"), // disputing a withdrawal breaks the spec. more in README.md assumptions
+            LedgerError::DisputeOnWithdrawal => write!(f, "you can't dispute a withdrawal"), // not under the default dispute policy. more in README.md assumptions
+            LedgerError::NotAWithdrawal => write!(f, "transaction is not a withdrawal"), // withdrawal dispute rules applied to a deposit
             LedgerError::InsufficientFunds => write!(f, "insufficient funds"),
             LedgerError::TxAlreadyDisputed => write!(f, "transaction already disputed"), // for disputing the same tx twice
             LedgerError::TxNotDisputed => write!(f, "transaction not disputed"), // for performing a chargeback or a resolve on a non-disputed tx
+            LedgerError::ExceedsUndisputed => {
+                write!(f, "amount exceeds the undisputed part of the transaction")
+            }
+            LedgerError::ExceedsDisputed => {
+                write!(f, "amount exceeds the disputed part of the transaction")
+            }
             LedgerError::TxWrongClient => write!(f, "transaction-client mismatch"),
+            LedgerError::NotChargedBack => write!(f, "transaction not charged back"), // representment needs a chargeback with nothing else under dispute
+            LedgerError::NotRepresented => write!(f, "transaction not re-presented"), // pre-arbitration follows a representment
+            LedgerError::NotInPreArbitration => write!(f, "transaction not in pre-arbitration"),
+            LedgerError::DisputeEscalated => {
+                write!(f, "dispute escalated past its first chargeback")
+            }
+            LedgerError::DisputeClosed => write!(f, "dispute already closed"), // upheld or reversed for good
+            LedgerError::PartialEscalation => {
+                write!(f, "escalated disputes cover the whole charged-back amount")
+            }
+            LedgerError::DisputeWindowExpired => write!(f, "transaction too old to dispute"), // outside the configured dispute window
+            LedgerError::AccountAlreadyLocked => write!(f, "account already locked"),
+            LedgerError::AccountNotLocked => write!(f, "account not locked"),
             LedgerError::Overflow => write!(f, "arithmetic overflow"), // could happen if amount is over 900 trillion
         }
     }
 }
 
+impl LedgerError {
+    /// Stable snake_case identifier for reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LedgerError::DisputeOnWithdrawal => "dispute_on_withdrawal",
+            LedgerError::NotAWithdrawal => "not_a_withdrawal",
+            LedgerError::InsufficientFunds => "insufficient_funds",
+            LedgerError::TxAlreadyDisputed => "tx_already_disputed",
+            LedgerError::TxNotDisputed => "tx_not_disputed",
+            LedgerError::ExceedsUndisputed => "exceeds_undisputed",
+            LedgerError::ExceedsDisputed => "exceeds_disputed",
+            LedgerError::TxWrongClient => "tx_wrong_client",
+            LedgerError::NotChargedBack => "not_charged_back",
+            LedgerError::NotRepresented => "not_represented",
+            LedgerError::NotInPreArbitration => "not_in_prearbitration",
+            LedgerError::DisputeEscalated => "dispute_escalated",
+            LedgerError::DisputeClosed => "dispute_closed",
+            LedgerError::PartialEscalation => "partial_escalation",
+            LedgerError::DisputeWindowExpired => "dispute_window_expired",
+            LedgerError::AccountAlreadyLocked => "account_already_locked",
+            LedgerError::AccountNotLocked => "account_not_locked",
+            LedgerError::Overflow => "overflow",
+        }
+    }
+}
+
 impl std::error::Error for LedgerError {}
@@ -1,6 +1,6 @@
 use crate::core::errors::LedgerError;
 use crate::core::types::Amount;
-use crate::engine::state::{AccountState, TxKind, TxRecord};
+use crate::engine::state::{AccountState, DisputeStage, TxKind, TxRecord};
 
 pub fn deposit(account: &mut AccountState, amount: Amount) -> Result<(), LedgerError> {
     let new_available = account.available.checked_add(amount)?;
@@ -17,54 +17,292 @@ pub fn withdrawal(account: &mut AccountState, amount: Amount) -> Result<(), Ledg
     Ok(())
 }
 
-pub fn dispute(account: &mut AccountState, rec: &mut TxRecord) -> Result<(), LedgerError> {
-    if rec.disputed {
-        return Err(LedgerError::TxAlreadyDisputed);
+/// Admin freeze, e.g. while compliance looks into the account.
+pub fn lock(account: &mut AccountState) -> Result<(), LedgerError> {
+    if account.locked {
+        return Err(LedgerError::AccountAlreadyLocked);
     }
-    if rec.kind != TxKind::Deposit {
-        return Err(LedgerError::DisputeOnWithdrawal);
+    account.locked = true;
+    Ok(())
+}
+
+/// Reinstates a locked account, whether it was locked by a chargeback or by `lock`.
+/// Balances stay as they are.
+pub fn unlock(account: &mut AccountState) -> Result<(), LedgerError> {
+    if !account.locked {
+        return Err(LedgerError::AccountNotLocked);
     }
-    account.available = account.available.checked_sub(rec.amount)?;
-    account.held = account.held.checked_add(rec.amount)?;
-    rec.disputed = true;
+    account.locked = false;
+    Ok(())
+}
+
+// Dispute, resolve and chargeback take an optional amount so a tx can be disputed in parts.
+// Without one, a dispute covers everything still undisputed, and a resolve or chargeback
+// everything currently under dispute. Once a dispute is escalated past its first chargeback
+// (see `DisputeStage`), resolve and chargeback end it and cover the whole charged-back amount.
+
+// Where a disputed amount sits. For a deposit the standing side is `available` and the
+// reversed side is gone; for a withdrawal it's the other way round. Every dispute step
+// moves an amount from one side to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Standing,
+    Held,
+    Reversed,
+}
 
+fn shift(
+    account: &mut AccountState,
+    kind: TxKind,
+    from: Side,
+    to: Side,
+    amount: Amount,
+) -> Result<(), LedgerError> {
+    let mut available = account.available;
+    let mut held = account.held;
+    for (side, credit) in [(from, false), (to, true)] {
+        let balance = match (side, kind) {
+            (Side::Held, _) => &mut held,
+            (Side::Standing, TxKind::Deposit) | (Side::Reversed, TxKind::Withdrawal) => {
+                &mut available
+            }
+            _ => continue,
+        };
+        *balance = if credit {
+            balance.checked_add(amount)?
+        } else {
+            balance.checked_sub(amount)?
+        };
+    }
+    account.available = available;
+    account.held = held;
     Ok(())
 }
 
-pub fn resolve(account: &mut AccountState, rec: &mut TxRecord) -> Result<(), LedgerError> {
-    if !rec.disputed {
+fn disputable(rec: &TxRecord, amount: Option<Amount>) -> Result<Amount, LedgerError> {
+    match rec.stage {
+        DisputeStage::Undisputed | DisputeStage::Disputed | DisputeStage::ChargedBack => {}
+        DisputeStage::Upheld | DisputeStage::Reversed => return Err(LedgerError::DisputeClosed),
+        _ => return Err(LedgerError::DisputeEscalated),
+    }
+    let remaining = rec.undisputed();
+    if remaining == Amount::zero() {
+        return Err(LedgerError::TxAlreadyDisputed);
+    }
+    match amount {
+        Some(a) if a > remaining => Err(LedgerError::ExceedsUndisputed),
+        Some(a) => Ok(a),
+        None => Ok(remaining),
+    }
+}
+
+fn settleable(rec: &TxRecord, amount: Option<Amount>) -> Result<Amount, LedgerError> {
+    if !rec.is_disputed() {
         return Err(LedgerError::TxNotDisputed);
     }
+    match amount {
+        Some(a) if a > rec.disputed => Err(LedgerError::ExceedsDisputed),
+        Some(a) => Ok(a),
+        None => Ok(rec.disputed),
+    }
+}
+
+fn open(
+    account: &mut AccountState,
+    rec: &mut TxRecord,
+    amount: Option<Amount>,
+) -> Result<(), LedgerError> {
+    let amount = disputable(rec, amount)?;
+    let new_disputed = rec.disputed.checked_add(amount)?;
+    shift(account, rec.kind, Side::Standing, Side::Held, amount)?;
+    rec.disputed = new_disputed;
+    rec.stage = DisputeStage::Disputed;
+    Ok(())
+}
+
+// resolve settles toward the standing side, chargeback toward the reversed one
+fn settle(
+    account: &mut AccountState,
+    rec: &mut TxRecord,
+    amount: Option<Amount>,
+    to: Side,
+) -> Result<(), LedgerError> {
+    let mut next = *rec;
+    let (from, amount) = match rec.stage {
+        DisputeStage::Undisputed | DisputeStage::Disputed | DisputeStage::ChargedBack => {
+            let amount = settleable(rec, amount)?;
+            next.disputed = rec.disputed.checked_sub(amount)?;
+            if to == Side::Reversed {
+                next.charged_back = rec.charged_back.checked_add(amount)?;
+            }
+            next.stage = next.first_round_stage();
+            (Side::Held, amount)
+        }
+        DisputeStage::Upheld | DisputeStage::Reversed => return Err(LedgerError::DisputeClosed),
+        stage => {
+            if amount.is_some_and(|a| a != rec.charged_back) {
+                return Err(LedgerError::PartialEscalation);
+            }
+            let from = if stage == DisputeStage::Represented {
+                Side::Held
+            } else {
+                Side::Reversed
+            };
+            // charging back a representment is what pre-arbitration is for
+            if from == Side::Held && to == Side::Reversed {
+                return Err(LedgerError::DisputeEscalated);
+            }
+            next.stage = if to == Side::Standing {
+                DisputeStage::Upheld
+            } else {
+                DisputeStage::Reversed
+            };
+            (from, rec.charged_back)
+        }
+    };
+    shift(account, rec.kind, from, to, amount)?;
+    *rec = next;
+    Ok(())
+}
+
+pub fn dispute(
+    account: &mut AccountState,
+    rec: &mut TxRecord,
+    amount: Option<Amount>,
+) -> Result<(), LedgerError> {
+    if rec.kind != TxKind::Deposit {
+        return Err(LedgerError::DisputeOnWithdrawal);
+    }
+    open(account, rec, amount)
+}
+
+pub fn resolve(
+    account: &mut AccountState,
+    rec: &mut TxRecord,
+    amount: Option<Amount>,
+) -> Result<(), LedgerError> {
     if rec.kind != TxKind::Deposit {
         // should be unreachable but just in case
         return Err(LedgerError::DisputeOnWithdrawal);
     }
-    account.available = account.available.checked_add(rec.amount)?;
-    account.held = account.held.checked_sub(rec.amount)?;
-    rec.disputed = false;
+    settle(account, rec, amount, Side::Standing)
+}
 
+/// A first-round chargeback locks the account. Ending an escalated dispute against the
+/// client doesn't lock it again: the money already left at the first chargeback.
+pub fn chargeback(
+    account: &mut AccountState,
+    rec: &mut TxRecord,
+    amount: Option<Amount>,
+) -> Result<(), LedgerError> {
+    if rec.kind != TxKind::Deposit {
+        return Err(LedgerError::DisputeOnWithdrawal);
+    }
+    let first_round = rec.stage == DisputeStage::Disputed;
+    settle(account, rec, amount, Side::Reversed)?;
+    if first_round {
+        account.locked = true;
+    }
     Ok(())
 }
 
-pub fn chargeback(account: &mut AccountState, rec: &mut TxRecord) -> Result<(), LedgerError> {
-    if !rec.disputed {
-        return Err(LedgerError::TxNotDisputed);
+// A disputed withdrawal is one the client says they never made, e.g. a card payment.
+// While it's investigated the amount is held on the client's behalf, so held and total go up;
+// resolving it means the withdrawal stands, a chargeback means the money comes back.
+
+pub fn dispute_withdrawal(
+    account: &mut AccountState,
+    rec: &mut TxRecord,
+    amount: Option<Amount>,
+) -> Result<(), LedgerError> {
+    if rec.kind != TxKind::Withdrawal {
+        return Err(LedgerError::NotAWithdrawal);
     }
-    if rec.kind != TxKind::Deposit {
-        return Err(LedgerError::DisputeOnWithdrawal);
+    open(account, rec, amount)
+}
+
+pub fn resolve_withdrawal(
+    account: &mut AccountState,
+    rec: &mut TxRecord,
+    amount: Option<Amount>,
+) -> Result<(), LedgerError> {
+    if rec.kind != TxKind::Withdrawal {
+        return Err(LedgerError::NotAWithdrawal);
     }
-    rec.disputed = false;
+    settle(account, rec, amount, Side::Standing)
+}
 
-    account.held = account.held.checked_sub(rec.amount)?;
-    account.locked = true;
+/// Unlike a deposit chargeback this doesn't lock the account: the client was the victim.
+pub fn chargeback_withdrawal(
+    account: &mut AccountState,
+    rec: &mut TxRecord,
+    amount: Option<Amount>,
+) -> Result<(), LedgerError> {
+    if rec.kind != TxKind::Withdrawal {
+        return Err(LedgerError::NotAWithdrawal);
+    }
+    settle(account, rec, amount, Side::Reversed)
+}
+
+// The escalation steps work the same for deposits and withdrawals, and only ever on the
+// whole charged-back amount. Which kinds get that far is up to the dispute policy.
+
+fn expect_stage(rec: &TxRecord, stage: DisputeStage, err: LedgerError) -> Result<(), LedgerError> {
+    if rec.stage == stage {
+        Ok(())
+    } else if rec.stage.is_final() {
+        Err(LedgerError::DisputeClosed)
+    } else {
+        Err(err)
+    }
+}
+
+/// The merchant re-presents a charged-back tx. The charged-back amount is held again
+/// until the cardholder accepts (`resolve`) or charges back a second time (`prearbitration`).
+pub fn representment(account: &mut AccountState, rec: &mut TxRecord) -> Result<(), LedgerError> {
+    expect_stage(rec, DisputeStage::ChargedBack, LedgerError::NotChargedBack)?;
+    shift(
+        account,
+        rec.kind,
+        Side::Reversed,
+        Side::Held,
+        rec.charged_back,
+    )?;
+    rec.stage = DisputeStage::Represented;
+    Ok(())
+}
+
+/// The second chargeback: the re-presented amount is reversed again. The merchant can accept
+/// it (`chargeback`), drop it (`resolve`), or take it to `arbitration`.
+pub fn prearbitration(account: &mut AccountState, rec: &mut TxRecord) -> Result<(), LedgerError> {
+    expect_stage(rec, DisputeStage::Represented, LedgerError::NotRepresented)?;
+    shift(
+        account,
+        rec.kind,
+        Side::Held,
+        Side::Reversed,
+        rec.charged_back,
+    )?;
+    rec.stage = DisputeStage::PreArbitration;
+    Ok(())
+}
 
+/// Hands the dispute to the card network. Nothing moves; the ruling comes as a `resolve`
+/// (the tx stands) or a `chargeback` (it stays reversed).
+pub fn arbitration(rec: &mut TxRecord) -> Result<(), LedgerError> {
+    expect_stage(
+        rec,
+        DisputeStage::PreArbitration,
+        LedgerError::NotInPreArbitration,
+    )?;
+    rec.stage = DisputeStage::Arbitration;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::core::types::Amount;
+    use crate::core::types::{Amount, Asset};
     use crate::engine::{AccountState, TxKind, TxRecord};
 
     fn amt(s: &str) -> Amount {
@@ -80,21 +318,18 @@ mod tests {
     }
 
     fn dep_record(client: u16, amount: &str) -> TxRecord {
-        TxRecord {
-            client,
-            kind: TxKind::Deposit,
-            amount: amt(amount),
-            disputed: false,
-        }
+        TxRecord::new(client, Asset::default(), TxKind::Deposit, amt(amount))
     }
 
     fn wd_record(client: u16, amount: &str) -> TxRecord {
-        TxRecord {
-            client,
-            kind: TxKind::Withdrawal,
-            amount: amt(amount),
-            disputed: false,
-        }
+        TxRecord::new(client, Asset::default(), TxKind::Withdrawal, amt(amount))
+    }
+
+    // the whole amount under dispute
+    fn disputed(mut rec: TxRecord) -> TxRecord {
+        rec.disputed = rec.amount;
+        rec.stage = DisputeStage::Disputed;
+        rec
     }
 
     #[test]
@@ -133,9 +368,9 @@ mod tests {
         let mut a = acct("2.0000", "0.0000", false);
         let mut rec = dep_record(1, "1.5000");
 
-        dispute(&mut a, &mut rec).unwrap();
+        dispute(&mut a, &mut rec, None).unwrap();
 
-        assert!(rec.disputed);
+        assert!(rec.is_disputed());
         assert_eq!(a.available, amt("0.5000"));
         assert_eq!(a.held, amt("1.5000"));
         assert_eq!(a.total(), amt("2.0000"));
@@ -146,24 +381,73 @@ mod tests {
         let mut a = acct("2.0000", "0.0000", false);
         let mut rec = wd_record(1, "1.0000");
 
-        let res = dispute(&mut a, &mut rec);
+        let res = dispute(&mut a, &mut rec, None);
         assert!(matches!(res, Err(LedgerError::DisputeOnWithdrawal)));
 
-        assert!(!rec.disputed);
+        assert!(!rec.is_disputed());
         assert_eq!(a.available, amt("2.0000"));
         assert_eq!(a.held, amt("0.0000"));
         assert_eq!(a.total(), amt("2.0000"));
     }
 
+    #[test]
+    fn withdrawal_dispute_holds_the_amount_for_the_client() {
+        let mut a = acct("2.0000", "0.0000", false);
+        let mut rec = wd_record(1, "1.0000");
+
+        dispute_withdrawal(&mut a, &mut rec, None).unwrap();
+
+        assert!(rec.is_disputed());
+        assert_eq!(a.available, amt("2.0000"));
+        assert_eq!(a.held, amt("1.0000"));
+        assert_eq!(a.total(), amt("3.0000"));
+    }
+
+    #[test]
+    fn withdrawal_resolve_lets_the_withdrawal_stand() {
+        let mut a = acct("2.0000", "1.0000", false);
+        let mut rec = disputed(wd_record(1, "1.0000"));
+
+        resolve_withdrawal(&mut a, &mut rec, None).unwrap();
+
+        assert!(!rec.is_disputed());
+        assert_eq!(a.available, amt("2.0000"));
+        assert_eq!(a.held, amt("0.0000"));
+    }
+
+    #[test]
+    fn withdrawal_chargeback_returns_the_money_without_locking() {
+        let mut a = acct("2.0000", "1.0000", false);
+        let mut rec = disputed(wd_record(1, "1.0000"));
+
+        chargeback_withdrawal(&mut a, &mut rec, None).unwrap();
+
+        assert!(!a.locked);
+        assert!(!rec.is_disputed());
+        assert_eq!(a.available, amt("3.0000"));
+        assert_eq!(a.held, amt("0.0000"));
+        assert_eq!(a.total(), amt("3.0000"));
+    }
+
+    #[test]
+    fn withdrawal_dispute_rules_refuse_deposits() {
+        let mut a = acct("2.0000", "0.0000", false);
+        let mut rec = dep_record(1, "1.0000");
+
+        let res = dispute_withdrawal(&mut a, &mut rec, None);
+        assert!(matches!(res, Err(LedgerError::NotAWithdrawal)));
+        assert!(!rec.is_disputed());
+        assert_eq!(a.held, amt("0.0000"));
+    }
+
     #[test]
     fn resolve_releases_held_back_to_available() {
         let mut a = acct("0.5000", "1.5000", false);
-        let mut rec = dep_record(1, "1.5000");
-        rec.disputed = true;
+        let mut rec = disputed(dep_record(1, "1.5000"));
 
-        resolve(&mut a, &mut rec).unwrap();
+        resolve(&mut a, &mut rec, None).unwrap();
 
-        assert!(!rec.disputed);
+        assert!(!rec.is_disputed());
         assert_eq!(a.available, amt("2.0000"));
         assert_eq!(a.held, amt("0.0000"));
         assert_eq!(a.total(), amt("2.0000"));
@@ -172,16 +456,207 @@ mod tests {
     #[test]
     fn chargeback_reduces_held_and_total_and_locks() {
         let mut a = acct("0.5000", "1.5000", false);
-        let mut rec = dep_record(1, "1.5000");
-        rec.disputed = true;
+        let mut rec = disputed(dep_record(1, "1.5000"));
 
-        chargeback(&mut a, &mut rec).unwrap();
+        chargeback(&mut a, &mut rec, None).unwrap();
 
         assert!(a.locked);
         assert_eq!(a.available, amt("0.5000"));
         assert_eq!(a.held, amt("0.0000"));
         assert_eq!(a.total(), amt("0.5000"));
-        assert!(!rec.disputed);
+        assert!(!rec.is_disputed());
+    }
+
+    #[test]
+    fn partial_disputes_add_up_to_the_amount() {
+        let mut a = acct("10.0000", "0.0000", false);
+        let mut rec = dep_record(1, "10.0000");
+
+        dispute(&mut a, &mut rec, Some(amt("3.0000"))).unwrap();
+        dispute(&mut a, &mut rec, Some(amt("4.0000"))).unwrap();
+        assert_eq!(rec.disputed, amt("7.0000"));
+        assert_eq!(rec.undisputed(), amt("3.0000"));
+
+        let res = dispute(&mut a, &mut rec, Some(amt("3.0001")));
+        assert!(matches!(res, Err(LedgerError::ExceedsUndisputed)));
+
+        // no amount means the rest
+        dispute(&mut a, &mut rec, None).unwrap();
+        assert_eq!(a.available, amt("0.0000"));
+        assert_eq!(a.held, amt("10.0000"));
+        let res = dispute(&mut a, &mut rec, None);
+        assert!(matches!(res, Err(LedgerError::TxAlreadyDisputed)));
+    }
+
+    #[test]
+    fn partial_chargeback_and_resolve_settle_parts_of_the_dispute() {
+        let mut a = acct("6.0000", "4.0000", false);
+        let mut rec = dep_record(1, "10.0000");
+        rec.disputed = amt("4.0000");
+        rec.stage = DisputeStage::Disputed;
+
+        let res = chargeback(&mut a, &mut rec, Some(amt("5.0000")));
+        assert!(matches!(res, Err(LedgerError::ExceedsDisputed)));
+        assert!(!a.locked);
+
+        chargeback(&mut a, &mut rec, Some(amt("1.0000"))).unwrap();
+        assert!(a.locked);
+        assert_eq!(rec.charged_back, amt("1.0000"));
+
+        resolve(&mut a, &mut rec, None).unwrap();
+        assert_eq!(a.available, amt("9.0000"));
+        assert_eq!(a.held, amt("0.0000"));
+        assert!(!rec.is_disputed());
+
+        // what was charged back is gone for good
+        assert_eq!(rec.undisputed(), amt("9.0000"));
+    }
+
+    #[test]
+    fn charged_back_withdrawal_cant_be_disputed_again() {
+        let mut a = acct("2.0000", "1.0000", false);
+        let mut rec = disputed(wd_record(1, "1.0000"));
+
+        chargeback_withdrawal(&mut a, &mut rec, None).unwrap();
+        let res = dispute_withdrawal(&mut a, &mut rec, None);
+
+        assert!(matches!(res, Err(LedgerError::TxAlreadyDisputed)));
+        assert_eq!(a.available, amt("3.0000"));
+        assert_eq!(a.held, amt("0.0000"));
+    }
+
+    // charged back in full, as after dispute then chargeback
+    fn charged_back(mut rec: TxRecord) -> TxRecord {
+        rec.charged_back = rec.amount;
+        rec.stage = DisputeStage::ChargedBack;
+        rec
+    }
+
+    #[test]
+    fn deposit_dispute_can_be_won_in_arbitration() {
+        let mut a = acct("1.0000", "0.0000", true);
+        let mut rec = charged_back(dep_record(1, "2.0000"));
+
+        representment(&mut a, &mut rec).unwrap();
+        assert_eq!(rec.stage, DisputeStage::Represented);
+        assert_eq!(a.held, amt("2.0000"));
+
+        prearbitration(&mut a, &mut rec).unwrap();
+        assert_eq!(a.held, amt("0.0000"));
+        assert_eq!(a.total(), amt("1.0000"));
+
+        arbitration(&mut rec).unwrap();
+        assert_eq!(rec.stage, DisputeStage::Arbitration);
+
+        resolve(&mut a, &mut rec, None).unwrap();
+        assert_eq!(rec.stage, DisputeStage::Upheld);
+        assert_eq!(a.available, amt("3.0000"));
+        assert_eq!(a.held, amt("0.0000"));
+
+        let res = dispute(&mut a, &mut rec, None);
+        assert!(matches!(res, Err(LedgerError::DisputeClosed)));
+    }
+
+    #[test]
+    fn accepted_representment_releases_the_hold() {
+        let mut a = acct("1.0000", "0.0000", true);
+        let mut rec = charged_back(dep_record(1, "2.0000"));
+
+        representment(&mut a, &mut rec).unwrap();
+        let res = chargeback(&mut a, &mut rec, None);
+        assert!(matches!(res, Err(LedgerError::DisputeEscalated)));
+
+        resolve(&mut a, &mut rec, None).unwrap();
+        assert_eq!(rec.stage, DisputeStage::Upheld);
+        assert_eq!(a.available, amt("3.0000"));
+        assert_eq!(a.held, amt("0.0000"));
+    }
+
+    #[test]
+    fn lost_arbitration_moves_nothing_and_does_not_relock() {
+        let mut a = acct("1.0000", "0.0000", false);
+        let mut rec = charged_back(dep_record(1, "2.0000"));
+        rec.stage = DisputeStage::Arbitration;
+
+        let res = chargeback(&mut a, &mut rec, Some(amt("1.0000")));
+        assert!(matches!(res, Err(LedgerError::PartialEscalation)));
+
+        chargeback(&mut a, &mut rec, None).unwrap();
+        assert_eq!(rec.stage, DisputeStage::Reversed);
+        assert!(!a.locked);
+        assert_eq!(a.available, amt("1.0000"));
+    }
+
+    #[test]
+    fn withdrawal_representment_takes_the_refund_back_into_held() {
+        let mut a = acct("3.0000", "0.0000", false);
+        let mut rec = charged_back(wd_record(1, "1.0000"));
+
+        representment(&mut a, &mut rec).unwrap();
+        assert_eq!(a.available, amt("2.0000"));
+        assert_eq!(a.held, amt("1.0000"));
+
+        // the client accepts: the withdrawal stands after all
+        resolve_withdrawal(&mut a, &mut rec, None).unwrap();
+        assert_eq!(a.available, amt("2.0000"));
+        assert_eq!(a.held, amt("0.0000"));
+    }
+
+    #[test]
+    fn escalation_steps_out_of_order_are_rejected() {
+        let mut a = acct("2.0000", "0.0000", false);
+        let mut rec = dep_record(1, "1.0000");
+
+        assert!(matches!(
+            representment(&mut a, &mut rec),
+            Err(LedgerError::NotChargedBack)
+        ));
+        assert!(matches!(
+            prearbitration(&mut a, &mut rec),
+            Err(LedgerError::NotRepresented)
+        ));
+        assert!(matches!(
+            arbitration(&mut rec),
+            Err(LedgerError::NotInPreArbitration)
+        ));
+
+        let mut rec = charged_back(dep_record(1, "1.0000"));
+        representment(&mut a, &mut rec).unwrap();
+        assert!(matches!(
+            dispute(&mut a, &mut rec, None),
+            Err(LedgerError::DisputeEscalated)
+        ));
+        assert!(matches!(
+            representment(&mut a, &mut rec),
+            Err(LedgerError::NotChargedBack)
+        ));
+
+        rec.stage = DisputeStage::Reversed;
+        assert!(matches!(
+            representment(&mut a, &mut rec),
+            Err(LedgerError::DisputeClosed)
+        ));
+        assert_eq!(a.available, amt("2.0000"));
+        assert_eq!(a.held, amt("1.0000"));
+    }
+
+    #[test]
+    fn lock_and_unlock_flip_only_the_flag() {
+        let mut a = acct("1.0000", "2.0000", false);
+
+        lock(&mut a).unwrap();
+        assert!(a.locked);
+        assert!(matches!(
+            lock(&mut a),
+            Err(LedgerError::AccountAlreadyLocked)
+        ));
+
+        unlock(&mut a).unwrap();
+        assert!(!a.locked);
+        assert!(matches!(unlock(&mut a), Err(LedgerError::AccountNotLocked)));
+
+        assert_eq!(a.available, amt("1.0000"));
+        assert_eq!(a.held, amt("2.0000"));
     }
 
     #[test]
@@ -189,20 +664,37 @@ mod tests {
         let mut a = acct("2.0000", "0.0000", false);
         let mut rec = dep_record(1, "1.0000");
 
-        dispute(&mut a, &mut rec).unwrap();
-        let res = dispute(&mut a, &mut rec);
+        dispute(&mut a, &mut rec, None).unwrap();
+        let res = dispute(&mut a, &mut rec, None);
 
         assert!(res.is_err());
         assert_eq!(a.available, amt("1.0000"));
         assert_eq!(a.held, amt("1.0000"));
     }
 
+    #[test]
+    fn dispute_overflow_leaves_account_and_record_untouched() {
+        let mut a = AccountState {
+            available: amt("1.0000"),
+            held: Amount::from_scaled(i64::MAX),
+            locked: false,
+        };
+        let mut rec = dep_record(1, "1.0000");
+
+        let res = dispute(&mut a, &mut rec, None);
+        assert!(matches!(res, Err(LedgerError::Overflow)));
+
+        assert!(!rec.is_disputed());
+        assert_eq!(a.available, amt("1.0000"));
+        assert_eq!(a.held, Amount::from_scaled(i64::MAX));
+    }
+
     #[test]
     fn resolve_without_dispute_errors_and_no_change() {
         let mut a = acct("2.0000", "0.0000", false);
         let mut rec = dep_record(1, "1.0000");
 
-        let res = resolve(&mut a, &mut rec);
+        let res = resolve(&mut a, &mut rec, None);
         assert!(res.is_err());
 
         assert_eq!(a.available, amt("2.0000"));
@@ -214,7 +706,7 @@ mod tests {
         let mut a = acct("2.0000", "0.0000", false);
         let mut rec = dep_record(1, "1.0000");
 
-        let res = chargeback(&mut a, &mut rec);
+        let res = chargeback(&mut a, &mut rec, None);
         assert!(res.is_err());
 
         assert!(!a.locked);
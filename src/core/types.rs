@@ -1,17 +1,25 @@
 use crate::core::errors::{CoreError, LedgerError};
+use std::collections::HashMap;
 use std::fmt;
 use std::ops::{Add, AddAssign, Sub, SubAssign};
 
 pub type ClientId = u16;
 pub type TxId = u32;
+/// Whole seconds since the Unix epoch.
+pub type Timestamp = u64;
 
-/// Fixed-precision amount newtype in 10^-4 units.
+/// Fixed-precision amount newtype in minor units of its asset: 10^-4 by default,
+/// or whatever `Precisions` says for the asset. The amount itself doesn't know its scale;
+/// amounts are only ever combined with others of the same asset.
 /// Stored as scaled i64 to avoid underflow hazards during subtraction.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub struct Amount(i64);
 
 impl Amount {
+    pub const DECIMALS: u32 = 4;
     pub const SCALE: i64 = 10_000;
+    /// 10^18 is the largest power of ten that fits an i64.
+    pub const MAX_DECIMALS: u32 = 18;
 
     pub fn zero() -> Self {
         Amount(0)
@@ -21,7 +29,31 @@ impl Amount {
         self.0
     }
 
+    /// Inverse of `as_i64`, for restoring amounts that were stored scaled.
+    pub fn from_scaled(units: i64) -> Self {
+        Amount(units)
+    }
+
     pub fn from_str_4dp(s: &str) -> Result<Self, CoreError> {
+        Self::parse(s, Self::DECIMALS)
+    }
+
+    /// Parses a decimal with up to `decimals` places into units of 10^-decimals.
+    pub fn parse(s: &str, decimals: u32) -> Result<Self, CoreError> {
+        Self::parse_rounded(s, decimals, RoundingPolicy::Reject).map(|(amount, _)| amount)
+    }
+
+    /// Like `parse`, but places beyond `decimals` are rounded by `policy` instead of rejected.
+    /// When rounding changed the value, the delta comes back alongside the amount.
+    pub fn parse_rounded(
+        s: &str,
+        decimals: u32,
+        policy: RoundingPolicy,
+    ) -> Result<(Self, Option<RoundingDelta>), CoreError> {
+        let scale = 10i64
+            .checked_pow(decimals)
+            .ok_or(CoreError::PrecisionOutOfRange)?;
+
         let s = s.trim();
         if s.is_empty() {
             return Err(CoreError::ParseAmount);
@@ -41,32 +73,82 @@ impl Amount {
             return Err(CoreError::ParseAmount);
         }
 
-        let whole: i64 = whole_str.parse().map_err(|_| CoreError::ParseAmount)?;
+        // digits were checked above, so the only way this fails is overflow
+        let whole: i64 = whole_str.parse().map_err(|_| CoreError::AmountOutOfRange)?;
 
         // allow "1." or no decimals as "1.0000"
         let decimals_str = if parts.len() == 2 { parts[1] } else { "" };
         let decimals_len = decimals_str.len();
-        // we're allowed to assume that there are 4 decimal points precision, but if for some reason there are more, it's an error
-        // I could truncate this but silent truncation is changing money and rounding rules weren't specified
-        if decimals_len > 4 {
-            return Err(CoreError::ParseAmount);
+        // more places than the asset's precision is an error unless a rounding policy says otherwise;
+        // silent truncation is changing money
+        if decimals_len > decimals as usize && policy == RoundingPolicy::Reject {
+            return Err(CoreError::TooManyDecimals);
         }
-
-        if decimals_len == 0 {
-            return Ok(Amount(whole * Self::SCALE));
+        // the residue must be representable too, so that it can be reported
+        if decimals_len > Self::MAX_DECIMALS as usize {
+            return Err(CoreError::TooManyDecimals);
         }
 
         if !decimals_str.chars().all(|c| c.is_ascii_digit()) {
             return Err(CoreError::ParseAmount);
         }
 
-        let mut decimals: i64 = decimals_str.parse().map_err(|_| CoreError::ParseAmount)?;
-
-        for _ in 0..(4 - decimals_len) {
-            decimals *= 10;
+        let (kept, excess) = decimals_str.split_at(decimals_len.min(decimals as usize));
+
+        let whole = whole
+            .checked_mul(scale)
+            .ok_or(CoreError::AmountOutOfRange)?;
+
+        // pad right: "1.2" at 4dp is 2000 units; fits since frac < 10^kept.len()
+        let frac: i64 = if kept.is_empty() {
+            0
+        } else {
+            let frac: i64 = kept.parse().map_err(|_| CoreError::ParseAmount)?;
+            frac * 10i64.pow(decimals - kept.len() as u32)
+        };
+
+        let amount = whole.checked_add(frac).ok_or(CoreError::AmountOutOfRange)?;
+
+        // residue in units of the last input place, e.g. "1.23456" at 4dp leaves 6 of 10
+        let residue: i64 = if excess.is_empty() {
+            0
+        } else {
+            excess.parse().map_err(|_| CoreError::ParseAmount)?
+        };
+        if residue == 0 {
+            return Ok((Amount(amount), None));
         }
 
-        Ok(Amount(whole * Self::SCALE + decimals))
+        let unit = 10i64.pow(excess.len() as u32);
+        let half = unit / 2;
+        let up = match policy {
+            RoundingPolicy::Reject | RoundingPolicy::Truncate => false,
+            RoundingPolicy::HalfUp => residue >= half,
+            RoundingPolicy::HalfEven => residue > half || (residue == half && amount % 2 == 1),
+        };
+
+        let (amount, delta) = if up {
+            let amount = amount.checked_add(1).ok_or(CoreError::AmountOutOfRange)?;
+            (amount, unit - residue)
+        } else {
+            (amount, -residue)
+        };
+
+        Ok((
+            Amount(amount),
+            Some(RoundingDelta {
+                units: delta,
+                decimals: decimals_len as u32,
+            }),
+        ))
+    }
+
+    /// Formats with exactly `decimals` places, e.g. for an asset from `Precisions`.
+    pub fn display(self, decimals: u32) -> AmountDisplay {
+        AmountDisplay {
+            amount: self,
+            decimals,
+        }
     }
 
     pub fn checked_add(self, rhs: Amount) -> Result<Amount, LedgerError> {
@@ -111,9 +193,154 @@ impl SubAssign for Amount {
 
 impl fmt::Display for Amount {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let whole = self.0 / Self::SCALE;
-        let frac = (self.0 % Self::SCALE).abs();
-        write!(f, "{}.{:04}", whole, frac)
+        self.display(Self::DECIMALS).fmt(f)
+    }
+}
+
+/// An amount paired with the number of places to print it with. See `Amount::display`.
+#[derive(Debug, Clone, Copy)]
+pub struct AmountDisplay {
+    amount: Amount,
+    decimals: u32,
+}
+
+impl fmt::Display for AmountDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = self.amount.0;
+        let Some(scale) = 10u64.checked_pow(self.decimals) else {
+            return write!(f, "{}e-{}", units, self.decimals);
+        };
+
+        // work on the magnitude so amounts between -1 and 0 keep their sign
+        let sign = if units < 0 { "-" } else { "" };
+        let whole = units.unsigned_abs() / scale;
+        let frac = units.unsigned_abs() % scale;
+        if self.decimals == 0 {
+            write!(f, "{}{}", sign, whole)
+        } else {
+            let width = self.decimals as usize;
+            write!(f, "{}{}.{:0width$}", sign, whole, frac)
+        }
+    }
+}
+
+/// What to do with an amount that has more places than its asset's precision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingPolicy {
+    /// Refuse the amount. Nothing is ever rounded.
+    #[default]
+    Reject,
+    /// Round to nearest, ties to even (banker's rounding).
+    HalfEven,
+    /// Round to nearest, ties away from zero.
+    HalfUp,
+    /// Drop the excess places.
+    Truncate,
+}
+
+impl RoundingPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoundingPolicy::Reject => "reject",
+            RoundingPolicy::HalfEven => "half-even",
+            RoundingPolicy::HalfUp => "half-up",
+            RoundingPolicy::Truncate => "truncate",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "reject" => Some(RoundingPolicy::Reject),
+            "half-even" => Some(RoundingPolicy::HalfEven),
+            "half-up" => Some(RoundingPolicy::HalfUp),
+            "truncate" => Some(RoundingPolicy::Truncate),
+            _ => None,
+        }
+    }
+}
+
+/// How far rounding moved a parsed amount: rounded minus exact, in units of the
+/// input's last decimal place (10^-decimals). Positive when rounded up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoundingDelta {
+    pub units: i64,
+    pub decimals: u32,
+}
+
+impl fmt::Display for RoundingDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Amount(self.units).display(self.decimals).fmt(f)
+    }
+}
+
+/// Decimal places per asset, e.g. 2 for USD, 8 for BTC, 0 for JPY.
+/// Assets not listed use `Amount::DECIMALS`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Precisions(HashMap<Asset, u32>);
+
+impl Precisions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, asset: Asset, decimals: u32) -> Result<(), CoreError> {
+        if decimals > Amount::MAX_DECIMALS {
+            return Err(CoreError::PrecisionOutOfRange);
+        }
+        self.0.insert(asset, decimals);
+        Ok(())
+    }
+
+    pub fn decimals(&self, asset: Asset) -> u32 {
+        self.0.get(&asset).copied().unwrap_or(Amount::DECIMALS)
+    }
+
+    /// The assets given a precision explicitly, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (Asset, u32)> + '_ {
+        self.0.iter().map(|(&asset, &decimals)| (asset, decimals))
+    }
+
+    pub fn parse_amount(&self, s: &str, asset: Asset) -> Result<Amount, CoreError> {
+        Amount::parse(s, self.decimals(asset))
+    }
+}
+
+/// Currency or asset code, e.g. `USD`, `EUR`, `BTC`. Up to 8 ASCII alphanumerics,
+/// stored upper-cased inline so it stays `Copy`.
+/// The default (empty) asset is what rows without an asset column get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Asset([u8; Asset::MAX_LEN]);
+
+impl Asset {
+    pub const MAX_LEN: usize = 8;
+
+    pub fn parse(s: &str) -> Result<Self, CoreError> {
+        let s = s.trim();
+        if s.len() > Self::MAX_LEN || !s.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(CoreError::InvalidAsset);
+        }
+
+        let mut code = [0u8; Self::MAX_LEN];
+        for (dst, src) in code.iter_mut().zip(s.bytes()) {
+            *dst = src.to_ascii_uppercase();
+        }
+        Ok(Asset(code))
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.0[0] == 0
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(Self::MAX_LEN);
+        // only ASCII alphanumerics ever get in
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
@@ -124,6 +351,46 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    /// The merchant re-presents a charged-back tx.
+    Representment,
+    /// The cardholder charges a re-presented tx back a second time.
+    PreArbitration,
+    /// The dispute goes to the card network for a ruling.
+    Arbitration,
+    /// Admin freeze of an account.
+    Lock,
+    /// Admin reinstatement of a locked account.
+    Unlock,
+}
+
+impl TransactionType {
+    /// The keyword used for this type in input and output files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Representment => "representment",
+            TransactionType::PreArbitration => "prearbitration",
+            TransactionType::Arbitration => "arbitration",
+            TransactionType::Lock => "lock",
+            TransactionType::Unlock => "unlock",
+        }
+    }
+
+    /// Lock, unlock and the dispute escalation steps don't take an amount.
+    pub fn takes_amount(&self) -> bool {
+        !matches!(
+            self,
+            TransactionType::Representment
+                | TransactionType::PreArbitration
+                | TransactionType::Arbitration
+                | TransactionType::Lock
+                | TransactionType::Unlock
+        )
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -132,11 +399,16 @@ pub struct Transaction {
     pub client: ClientId,
     pub tx: TxId,
     pub amount: Option<Amount>,
+    /// For dispute/resolve/chargeback the default asset means "whatever the original tx was in".
+    pub asset: Asset,
+    /// Only set if the input has a timestamp column.
+    pub timestamp: Option<Timestamp>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AccountRow {
     pub client: ClientId,
+    pub asset: Asset,
     pub available: Amount,
     pub held: Amount,
     pub total: Amount,
@@ -178,13 +450,13 @@ mod tests {
     #[test]
     fn parse_rejects_more_than_4_decimal_digits() {
         let err = Amount::from_str_4dp("1.23456").unwrap_err();
-        matches!(err, CoreError::ParseAmount);
+        assert_eq!(err, CoreError::TooManyDecimals);
     }
 
     #[test]
     fn parse_rejects_negative() {
         let err = Amount::from_str_4dp("-1.0000").unwrap_err();
-        matches!(err, CoreError::NegativeAmount);
+        assert_eq!(err, CoreError::NegativeAmount);
     }
 
     #[test]
@@ -195,12 +467,130 @@ mod tests {
         assert!(Amount::from_str_4dp("1.2.3").is_err());
     }
 
+    #[test]
+    fn parse_rejects_out_of_range_instead_of_overflowing() {
+        let err = Amount::from_str_4dp("9223372036854775807").unwrap_err();
+        assert_eq!(err, CoreError::AmountOutOfRange);
+    }
+
     #[test]
     fn display_always_4dp() {
         let a = Amount::from_str_4dp("10.5").unwrap();
         assert_eq!(a.to_string(), "10.5000");
     }
 
+    #[test]
+    fn parse_honours_the_given_precision() {
+        assert_eq!(Amount::parse("0.00000001", 8).unwrap().as_i64(), 1);
+        assert_eq!(Amount::parse("1.5", 8).unwrap().as_i64(), 150_000_000);
+        assert_eq!(Amount::parse("1500", 0).unwrap().as_i64(), 1500);
+        assert_eq!(Amount::parse("1500.", 0).unwrap().as_i64(), 1500);
+        assert_eq!(Amount::parse("1.5", 0), Err(CoreError::TooManyDecimals));
+        assert_eq!(Amount::parse("1.001", 2), Err(CoreError::TooManyDecimals));
+        assert_eq!(Amount::parse("1", 19), Err(CoreError::PrecisionOutOfRange));
+        assert_eq!(
+            Amount::parse("100000000000", 8),
+            Err(CoreError::AmountOutOfRange)
+        );
+    }
+
+    #[test]
+    fn display_honours_the_given_precision() {
+        assert_eq!(Amount(150_000_001).display(8).to_string(), "1.50000001");
+        assert_eq!(Amount(1500).display(0).to_string(), "1500");
+        assert_eq!(Amount(-5).display(2).to_string(), "-0.05");
+        assert_eq!(Amount(-5_000).to_string(), "-0.5000");
+    }
+
+    fn rounded(s: &str, policy: RoundingPolicy) -> (String, Option<String>) {
+        let (amount, delta) = Amount::parse_rounded(s, 2, policy).unwrap();
+        (amount.display(2).to_string(), delta.map(|d| d.to_string()))
+    }
+
+    #[test]
+    fn rounding_policies() {
+        use RoundingPolicy::*;
+
+        let cases = [
+            // input, half-even, half-up, truncate
+            ("1.005", "1.00", "1.01", "1.00"),
+            ("1.015", "1.02", "1.02", "1.01"),
+            ("1.0051", "1.01", "1.01", "1.00"),
+            ("1.0049", "1.00", "1.00", "1.00"),
+            ("0.999", "1.00", "1.00", "0.99"),
+        ];
+        for (input, even, up, trunc) in cases {
+            assert_eq!(rounded(input, HalfEven).0, even, "half-even {}", input);
+            assert_eq!(rounded(input, HalfUp).0, up, "half-up {}", input);
+            assert_eq!(rounded(input, Truncate).0, trunc, "truncate {}", input);
+        }
+
+        assert_eq!(
+            Amount::parse_rounded("1.005", 2, Reject),
+            Err(CoreError::TooManyDecimals)
+        );
+    }
+
+    #[test]
+    fn rounding_reports_its_delta() {
+        use RoundingPolicy::*;
+
+        assert_eq!(
+            rounded("1.005", HalfUp),
+            ("1.01".into(), Some("0.005".into()))
+        );
+        assert_eq!(
+            rounded("1.0049", HalfUp),
+            ("1.00".into(), Some("-0.0049".into()))
+        );
+        // nothing was lost, so nothing to report
+        assert_eq!(rounded("1.2300", Truncate), ("1.23".into(), None));
+        assert_eq!(rounded("1.2", Truncate), ("1.20".into(), None));
+
+        // the residue has to fit the delta
+        assert_eq!(
+            Amount::parse_rounded("1.0000000000000000001", 2, Truncate),
+            Err(CoreError::TooManyDecimals)
+        );
+    }
+
+    #[test]
+    fn precisions_default_to_4dp() {
+        let btc = Asset::parse("BTC").unwrap();
+        let mut p = Precisions::new();
+        p.insert(btc, 8).unwrap();
+
+        assert_eq!(p.decimals(btc), 8);
+        assert_eq!(p.decimals(Asset::default()), Amount::DECIMALS);
+        assert_eq!(p.insert(btc, 19), Err(CoreError::PrecisionOutOfRange));
+        assert_eq!(
+            p.parse_amount("1.23456", btc).unwrap().as_i64(),
+            123_456_000
+        );
+    }
+
+    #[test]
+    fn asset_parse_normalises_case() {
+        let a = Asset::parse(" usdt ").unwrap();
+        assert_eq!(a.as_str(), "USDT");
+        assert_eq!(a, Asset::parse("USDT").unwrap());
+        assert!(!a.is_default());
+    }
+
+    #[test]
+    fn asset_empty_is_default() {
+        let a = Asset::parse("").unwrap();
+        assert!(a.is_default());
+        assert_eq!(a, Asset::default());
+        assert_eq!(a.to_string(), "");
+    }
+
+    #[test]
+    fn asset_rejects_long_or_odd_codes() {
+        assert_eq!(Asset::parse("TOOLONGCODE"), Err(CoreError::InvalidAsset));
+        assert_eq!(Asset::parse("US-D"), Err(CoreError::InvalidAsset));
+    }
+
     #[test]
     fn checked_add_overflow_detected() {
         let a = Amount(i64::MAX);
@@ -0,0 +1,85 @@
+use crate::core::types::*;
+use crate::engine::state::AccountState;
+
+/// The parts of an account a journal entry captures on either side of a change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Balances {
+    pub available: Amount,
+    pub held: Amount,
+    pub locked: bool,
+}
+
+impl From<&AccountState> for Balances {
+    fn from(acc: &AccountState) -> Self {
+        Self {
+            available: acc.available,
+            held: acc.held,
+            locked: acc.locked,
+        }
+    }
+}
+
+/// One applied event. Rejected events never reach the journal since they change nothing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub line: u64,
+    pub kind: TransactionType,
+    pub client: ClientId,
+    pub asset: Asset,
+    pub tx: TxId,
+    pub amount: Option<Amount>,
+    pub before: Balances,
+    pub after: Balances,
+}
+
+/// Append-only record of state transitions, numbered from 1.
+/// Entries accumulate in memory until drained, so long runs should drain
+/// them to a `JournalSink` as they go.
+#[derive(Debug, Default)]
+pub struct Journal {
+    entries: Vec<JournalEntry>,
+    last_seq: u64,
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `asset` is the account that moved, which for a dispute is the original tx's asset.
+    pub fn append(
+        &mut self,
+        line: u64,
+        tx: &Transaction,
+        asset: Asset,
+        before: Balances,
+        after: Balances,
+    ) {
+        self.last_seq += 1;
+        self.entries.push(JournalEntry {
+            seq: self.last_seq,
+            line,
+            kind: tx.kind,
+            client: tx.client,
+            asset,
+            tx: tx.tx,
+            amount: tx.amount,
+            before,
+            after,
+        });
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Removes and returns the buffered entries. Sequence numbers keep counting.
+    pub fn drain(&mut self) -> std::vec::Drain<'_, JournalEntry> {
+        self.entries.drain(..)
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+}
@@ -1,3 +1,5 @@
+use crate::engine::outcome::{ApplyOutcome, RejectReason};
+
 #[derive(Debug, Default, Clone)]
 pub struct Metrics {
     pub malformed_rows: u64,
@@ -6,6 +8,41 @@ pub struct Metrics {
     pub duplicate_tx: u64,
     pub tx_not_found: u64,
     pub wrong_client_ref: u64,
+    pub wrong_asset_ref: u64,
     pub ledger_errors: u64,
     pub locked_ignored: u64,
+    pub unknown_account: u64,
+    pub late_events: u64,
+}
+
+impl Metrics {
+    pub fn record(&mut self, outcome: &ApplyOutcome) {
+        let reason = match outcome {
+            ApplyOutcome::Applied => return,
+            ApplyOutcome::Rejected(r) => r,
+        };
+
+        match reason {
+            RejectReason::MalformedRow => self.malformed_rows += 1,
+            RejectReason::UnknownType => self.unknown_type += 1,
+            RejectReason::MissingAmount => self.missing_amount += 1,
+            RejectReason::DuplicateTx => self.duplicate_tx += 1,
+            RejectReason::TxNotFound => self.tx_not_found += 1,
+            RejectReason::WrongClient => self.wrong_client_ref += 1,
+            RejectReason::WrongAsset => self.wrong_asset_ref += 1,
+            RejectReason::AccountLocked => self.locked_ignored += 1,
+            RejectReason::UnknownAccount => self.unknown_account += 1,
+            RejectReason::Late => self.late_events += 1,
+            RejectReason::Ledger(_) => self.ledger_errors += 1,
+        }
+    }
+}
+
+impl<'a> FromIterator<&'a ApplyOutcome> for Metrics {
+    fn from_iter<I: IntoIterator<Item = &'a ApplyOutcome>>(iter: I) -> Self {
+        iter.into_iter().fold(Metrics::default(), |mut m, o| {
+            m.record(o);
+            m
+        })
+    }
 }
@@ -1,9 +1,23 @@
+pub mod journal;
 pub mod metrics;
+pub mod outcome;
+pub mod parallel;
+pub mod policy;
 pub mod processor;
+pub mod snapshot;
+pub mod sqlite_store;
 pub mod state;
 pub mod store;
+#[cfg(feature = "async")]
+pub mod streaming;
 
+pub use journal::*;
 pub use metrics::*;
+pub use outcome::*;
+pub use parallel::*;
+pub use policy::*;
 pub use processor::*;
+pub use snapshot::*;
+pub use sqlite_store::*;
 pub use state::*;
 pub use store::*;
@@ -0,0 +1,107 @@
+use std::fmt;
+
+use crate::core::errors::LedgerError;
+
+/// What happened to a single ingested event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyOutcome {
+    Applied,
+    Rejected(RejectReason),
+}
+
+impl ApplyOutcome {
+    pub fn is_applied(&self) -> bool {
+        matches!(self, ApplyOutcome::Applied)
+    }
+
+    pub fn reject_reason(&self) -> Option<RejectReason> {
+        match self {
+            ApplyOutcome::Applied => None,
+            ApplyOutcome::Rejected(r) => Some(*r),
+        }
+    }
+}
+
+/// Why an event was dropped without changing any state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    MalformedRow,
+    UnknownType,
+    MissingAmount,
+    DuplicateTx,
+    TxNotFound,
+    WrongClient,
+    WrongAsset,
+    AccountLocked,
+    /// Lock or unlock for a client that has no account in that asset.
+    UnknownAccount,
+    /// Arrived too far out of timestamp order to be put back in place.
+    Late,
+    Ledger(LedgerError),
+}
+
+impl RejectReason {
+    /// Problems with the input itself rather than with the ledger rules.
+    /// These are the ones strict mode refuses to skip.
+    pub fn is_validation(&self) -> bool {
+        matches!(
+            self,
+            RejectReason::MalformedRow | RejectReason::UnknownType | RejectReason::MissingAmount
+        )
+    }
+
+    /// Stable snake_case identifier, used as the reason code in the rejects report.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectReason::MalformedRow => "malformed_row",
+            RejectReason::UnknownType => "unknown_type",
+            RejectReason::MissingAmount => "missing_amount",
+            RejectReason::DuplicateTx => "duplicate_tx",
+            RejectReason::TxNotFound => "tx_not_found",
+            RejectReason::WrongClient => "wrong_client",
+            RejectReason::WrongAsset => "wrong_asset",
+            RejectReason::AccountLocked => "account_locked",
+            RejectReason::UnknownAccount => "unknown_account",
+            RejectReason::Late => "late_event",
+            RejectReason::Ledger(e) => e.code(),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::MalformedRow => write!(f, "malformed row"),
+            RejectReason::UnknownType => write!(f, "unknown transaction type"),
+            RejectReason::MissingAmount => write!(f, "missing amount"),
+            RejectReason::DuplicateTx => write!(f, "duplicate transaction id"),
+            RejectReason::TxNotFound => write!(f, "referenced transaction not found"),
+            RejectReason::WrongClient => {
+                write!(f, "referenced transaction belongs to another client")
+            }
+            RejectReason::WrongAsset => {
+                write!(f, "referenced transaction is in a different asset")
+            }
+            RejectReason::AccountLocked => write!(f, "account is locked"),
+            RejectReason::UnknownAccount => write!(f, "no account for this client and asset"),
+            RejectReason::Late => write!(f, "arrived later than the reordering window"),
+            RejectReason::Ledger(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Raised by a strict `Processor` on the first event that fails validation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrictViolation {
+    pub line: u64,
+    pub reason: RejectReason,
+    pub detail: String,
+}
+
+impl fmt::Display for StrictViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.reason, self.detail)
+    }
+}
+
+impl std::error::Error for StrictViolation {}
@@ -0,0 +1,424 @@
+//! Runs the `Processor` on several threads, one shard of clients each.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread;
+
+use crate::core::types::*;
+use crate::engine::metrics::Metrics;
+use crate::engine::outcome::{ApplyOutcome, StrictViolation};
+use crate::engine::policy::{DepositsOnly, DisputePolicy, DisputeWindow};
+use crate::engine::processor::Processor;
+use crate::engine::state::TxRecord;
+use crate::engine::store::{HashMapStore, TxStore};
+use crate::io::IngestEvent;
+
+/// Shards are tracked in a 64-bit mask per tx id.
+pub const MAX_WORKERS: usize = 64;
+
+/// Events queued per worker before the reader waits for it to catch up.
+pub const DEFAULT_SHARD_CAPACITY: usize = 1024;
+
+/// A shard's own records, plus copies of other shards' records it has been handed.
+///
+/// The copies are only there for the checks that look across clients: duplicate tx ids,
+/// and disputes naming another client's tx. A shard never changes a record it doesn't own.
+#[derive(Debug, Default)]
+pub struct ShardStore {
+    local: HashMapStore,
+    foreign: HashMap<TxId, TxRecord>,
+}
+
+impl ShardStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The record for `tx` if this shard owns it.
+    pub fn local(&self, tx: TxId) -> Option<TxRecord> {
+        self.local.get(tx)
+    }
+
+    /// Replaces the copy of another shard's record for `tx`; `None` says it has none.
+    pub fn set_foreign(&mut self, tx: TxId, rec: Option<TxRecord>) {
+        match rec {
+            Some(rec) => self.foreign.insert(tx, rec),
+            None => self.foreign.remove(&tx),
+        };
+    }
+}
+
+impl TxStore for ShardStore {
+    fn get(&self, tx: TxId) -> Option<TxRecord> {
+        self.local
+            .get(tx)
+            .or_else(|| self.foreign.get(&tx).copied())
+    }
+    fn insert(&mut self, tx: TxId, rec: TxRecord) {
+        self.local.insert(tx, rec);
+    }
+    fn contains(&self, tx: TxId) -> bool {
+        self.local.contains(tx) || self.foreign.contains_key(&tx)
+    }
+    fn remove(&mut self, tx: TxId) {
+        self.local.remove(tx);
+    }
+    fn for_each(&self, f: &mut dyn FnMut(TxId, &TxRecord)) {
+        self.local.for_each(f);
+    }
+}
+
+/// What a `ParallelProcessor` run ends with.
+#[derive(Debug)]
+pub struct ParallelResults {
+    /// Every shard's accounts, sorted like `Processor::results`. After a strict violation
+    /// they may include events past it that a shard had already applied.
+    pub rows: Vec<AccountRow>,
+    pub metrics: Metrics,
+    /// The strict mode violation the run stopped at, if any. Outcomes after it aren't
+    /// reported or counted.
+    pub violation: Option<StrictViolation>,
+}
+
+enum Msg {
+    /// The event's position in the input, the latest timestamp before it, and the event.
+    Event(u64, Option<Timestamp>, IngestEvent),
+    Lookup(TxId, Sender<Option<TxRecord>>),
+    Foreign(TxId, Option<TxRecord>),
+}
+
+type Done = (u64, IngestEvent, Result<ApplyOutcome, StrictViolation>);
+
+/// Partitions events by client across worker threads, each with its own `Processor`.
+///
+/// An account is only ever touched by its own client's events, so shards share nothing but
+/// tx ids. When an event's tx id has been used by a deposit or withdrawal in another shard,
+/// the reader asks that shard for its record first (after it has applied everything before
+/// the event) and hands a copy to the event's shard; duplicates and wrong-client references
+/// are rejected exactly as the sequential `Processor` would.
+///
+/// Outcomes come back in input order and the results match a sequential run. Journals,
+/// snapshots, durable stores and eviction aren't supported.
+pub struct ParallelProcessor {
+    workers: usize,
+    capacity: usize,
+    strict: bool,
+    policy: Arc<dyn DisputePolicy>,
+    window: Option<DisputeWindow>,
+}
+
+impl ParallelProcessor {
+    /// `workers` is clamped to 1..=`MAX_WORKERS`.
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: workers.clamp(1, MAX_WORKERS),
+            capacity: DEFAULT_SHARD_CAPACITY,
+            strict: false,
+            policy: Arc::new(DepositsOnly),
+            window: None,
+        }
+    }
+
+    /// See `Processor::with_strict`. The run stops at the first violation in input order.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// See `Processor::with_dispute_policy`; the workers share it.
+    pub fn with_dispute_policy(mut self, policy: impl DisputePolicy + 'static) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// See `Processor::with_dispute_window`. Windows count events across all shards.
+    pub fn with_dispute_window(mut self, window: DisputeWindow) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Caps how many events are queued per worker.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    fn shard(&self, event: &IngestEvent) -> usize {
+        event.client().unwrap_or(0) as usize % self.workers
+    }
+
+    /// Applies `events`, calling `on_outcome` for each in input order. A strict violation
+    /// is passed to it too, and ends the run. An error from `on_outcome` stops the run and
+    /// is returned.
+    pub fn run<E>(
+        &self,
+        events: impl Iterator<Item = IngestEvent>,
+        mut on_outcome: impl FnMut(
+            &IngestEvent,
+            &Result<ApplyOutcome, StrictViolation>,
+        ) -> Result<(), E>,
+    ) -> Result<ParallelResults, E> {
+        thread::scope(|scope| {
+            let (done_tx, done_rx) = mpsc::channel();
+            let mut inputs = Vec::with_capacity(self.workers);
+            let mut handles = Vec::with_capacity(self.workers);
+            for _ in 0..self.workers {
+                let (input, rx) = mpsc::sync_channel(self.capacity);
+                let processor = self.processor();
+                let done = done_tx.clone();
+                handles.push(scope.spawn(move || work(processor, rx, done)));
+                inputs.push(input);
+            }
+            drop(done_tx);
+
+            let mut order = InOrder::default();
+            let (reply_tx, reply_rx) = mpsc::channel();
+            // shards that have seen a deposit or withdrawal with each tx id
+            let mut creators: HashMap<TxId, u64> = HashMap::new();
+            let mut time = None;
+
+            for (seq, event) in (0u64..).zip(events) {
+                while let Ok(done) = done_rx.try_recv() {
+                    order.push(done);
+                }
+                order.deliver(&mut on_outcome)?;
+                if order.violation.is_some() {
+                    break;
+                }
+
+                let shard = self.shard(&event);
+                let before = time;
+                if let IngestEvent::Tx { tx, .. } = &event {
+                    time = time.max(tx.timestamp);
+
+                    let mine = 1u64 << shard;
+                    if !matches!(tx.kind, TransactionType::Lock | TransactionType::Unlock) {
+                        let others = creators.get(&tx.tx).copied().unwrap_or(0) & !mine;
+                        if others != 0 {
+                            let rec = lookup(&inputs, others, tx.tx, &reply_tx, &reply_rx);
+                            send(&inputs[shard], Msg::Foreign(tx.tx, rec));
+                        }
+                    }
+                    if matches!(
+                        tx.kind,
+                        TransactionType::Deposit | TransactionType::Withdrawal
+                    ) {
+                        *creators.entry(tx.tx).or_default() |= mine;
+                    }
+                }
+                send(&inputs[shard], Msg::Event(seq, before, event));
+            }
+
+            drop(inputs);
+            for done in done_rx {
+                order.push(done);
+            }
+            order.deliver(&mut on_outcome)?;
+
+            let mut rows: Vec<_> = handles
+                .into_iter()
+                .flat_map(|h| h.join().expect("worker panicked"))
+                .collect();
+            rows.sort_by_key(|r| (r.client, r.asset));
+
+            Ok(ParallelResults {
+                rows,
+                metrics: order.metrics,
+                violation: order.violation,
+            })
+        })
+    }
+
+    fn processor(&self) -> Processor<ShardStore> {
+        let mut processor = Processor::new(ShardStore::new())
+            .with_strict(self.strict)
+            .with_dispute_policy(Arc::clone(&self.policy));
+        if let Some(window) = self.window {
+            processor = processor.with_dispute_window(window);
+        }
+        processor
+    }
+}
+
+fn work(
+    mut processor: Processor<ShardStore>,
+    input: Receiver<Msg>,
+    done: Sender<Done>,
+) -> Vec<AccountRow> {
+    for msg in input {
+        match msg {
+            Msg::Event(seq, time, event) => {
+                // counters as of the whole input, so windows match a sequential run
+                let state = processor.state_mut();
+                state.events = seq;
+                state.time = time;
+
+                let source = event.clone();
+                let res = processor.try_apply_event(event);
+                // the reader only hangs up early when it's given up on the run
+                let _ = done.send((seq, source, res));
+            }
+            Msg::Lookup(tx, reply) => {
+                let _ = reply.send(processor.state().store.local(tx));
+            }
+            Msg::Foreign(tx, rec) => processor.state_mut().store.set_foreign(tx, rec),
+        }
+    }
+    processor.results()
+}
+
+// a tx id is owned by at most one shard, so the first record found is the one
+fn lookup(
+    inputs: &[SyncSender<Msg>],
+    shards: u64,
+    tx: TxId,
+    reply_tx: &Sender<Option<TxRecord>>,
+    reply_rx: &Receiver<Option<TxRecord>>,
+) -> Option<TxRecord> {
+    let mut found = None;
+    for (shard, input) in inputs.iter().enumerate() {
+        if shards & (1 << shard) != 0 {
+            send(input, Msg::Lookup(tx, reply_tx.clone()));
+            let rec = reply_rx.recv().expect("worker hung up");
+            found = found.or(rec);
+        }
+    }
+    found
+}
+
+fn send(input: &SyncSender<Msg>, msg: Msg) {
+    input.send(msg).expect("worker hung up");
+}
+
+/// Puts finished events back in input order.
+#[derive(Default)]
+struct InOrder {
+    pending: BTreeMap<u64, (IngestEvent, Result<ApplyOutcome, StrictViolation>)>,
+    next: u64,
+    metrics: Metrics,
+    violation: Option<StrictViolation>,
+}
+
+impl InOrder {
+    fn push(&mut self, (seq, event, res): Done) {
+        self.pending.insert(seq, (event, res));
+    }
+
+    fn deliver<E>(
+        &mut self,
+        on_outcome: &mut impl FnMut(
+            &IngestEvent,
+            &Result<ApplyOutcome, StrictViolation>,
+        ) -> Result<(), E>,
+    ) -> Result<(), E> {
+        while self.violation.is_none() {
+            let Some((event, res)) = self.pending.remove(&self.next) else {
+                break;
+            };
+            self.next += 1;
+            on_outcome(&event, &res)?;
+            match res {
+                Ok(outcome) => self.metrics.record(&outcome),
+                Err(violation) => {
+                    self.metrics
+                        .record(&ApplyOutcome::Rejected(violation.reason));
+                    self.violation = Some(violation);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::errors::LedgerError;
+    use crate::engine::outcome::RejectReason;
+    use crate::io::SourcePos;
+
+    fn tx(kind: TransactionType, client: ClientId, tx: TxId, amount: Option<&str>) -> IngestEvent {
+        IngestEvent::Tx {
+            tx: Transaction {
+                kind,
+                client,
+                tx,
+                amount: amount.map(|a| Amount::from_str_4dp(a).unwrap()),
+                asset: Asset::default(),
+                timestamp: None,
+            },
+            pos: SourcePos::default(),
+            rounding: None,
+        }
+    }
+
+    fn outcomes(p: &ParallelProcessor, events: Vec<IngestEvent>) -> Vec<ApplyOutcome> {
+        let mut out = Vec::new();
+        p.run(events.into_iter(), |_, res| {
+            out.push(res.clone().unwrap());
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn tx_ids_are_checked_across_shards() {
+        use TransactionType::*;
+        let events = vec![
+            tx(Deposit, 1, 1, Some("5.0")),
+            // client 2 lands on the other shard
+            tx(Deposit, 2, 1, Some("5.0")),
+            tx(Dispute, 2, 1, None),
+            // rejected, so tx 2 is still free for client 2
+            tx(Withdrawal, 1, 2, Some("9.0")),
+            tx(Deposit, 2, 2, Some("1.0")),
+            tx(Deposit, 1, 2, Some("1.0")),
+        ];
+
+        let mut sequential = Processor::new(HashMapStore::new());
+        let expected: Vec<_> = events
+            .iter()
+            .map(|e| sequential.apply_event(e.clone()))
+            .collect();
+        assert_eq!(
+            expected,
+            vec![
+                ApplyOutcome::Applied,
+                ApplyOutcome::Rejected(RejectReason::DuplicateTx),
+                ApplyOutcome::Rejected(RejectReason::WrongClient),
+                ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::InsufficientFunds)),
+                ApplyOutcome::Applied,
+                ApplyOutcome::Rejected(RejectReason::DuplicateTx),
+            ]
+        );
+        assert_eq!(outcomes(&ParallelProcessor::new(2), events), expected);
+    }
+
+    #[test]
+    fn a_strict_violation_ends_the_run_in_input_order() {
+        use TransactionType::*;
+        let events = vec![
+            tx(Deposit, 1, 1, Some("5.0")),
+            tx(Deposit, 2, 2, None),
+            tx(Deposit, 3, 3, Some("1.0")),
+        ];
+
+        let mut seen = Vec::new();
+        let res = ParallelProcessor::new(4)
+            .with_strict(true)
+            .run(events.into_iter(), |event, _| {
+                seen.push(event.tx_id());
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+
+        assert_eq!(seen, vec![Some(1), Some(2)]);
+        assert_eq!(
+            res.violation.map(|v| v.reason),
+            Some(RejectReason::MissingAmount)
+        );
+        assert_eq!(res.metrics.missing_amount, 1);
+    }
+}
@@ -0,0 +1,217 @@
+use std::sync::Arc;
+
+use crate::core::errors::LedgerError;
+use crate::core::ledger;
+use crate::core::types::{Amount, Timestamp};
+use crate::engine::state::{AccountState, TxKind, TxRecord};
+
+/// How long after a tx it can still be disputed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeWindow {
+    /// Counted in events the processor saw since, rejected ones included.
+    Events(u64),
+    /// Counted in seconds from the tx's timestamp to the latest one seen. Txs without a
+    /// timestamp never expire.
+    Seconds(u64),
+}
+
+impl DisputeWindow {
+    /// Whether a tx applied at event `seq` and time `time` is out of the window as of
+    /// event `now` and the latest timestamp `latest`.
+    pub fn expired(
+        &self,
+        (seq, time): (u64, Option<Timestamp>),
+        (now, latest): (u64, Option<Timestamp>),
+    ) -> bool {
+        match (self, time, latest) {
+            (DisputeWindow::Events(n), _, _) => now.saturating_sub(seq) > *n,
+            (DisputeWindow::Seconds(n), Some(time), Some(latest)) => {
+                latest.saturating_sub(time) > *n
+            }
+            (DisputeWindow::Seconds(_), _, _) => false,
+        }
+    }
+}
+
+/// Decides what dispute, resolve and chargeback do to an account, given the tx they refer to
+/// and the amount on the row, if any (see `core::ledger` for what a missing one means).
+/// The escalation steps after a chargeback default to the `core::ledger` ones, which work
+/// for either kind of tx; only txs the policy let get charged back can reach them.
+/// The processor has already checked that the tx exists and belongs to the client, and
+/// applies the result only if the call succeeds, so implementations may leave the account
+/// and record half-changed on error.
+pub trait DisputePolicy: Send + Sync {
+    fn dispute(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError>;
+    fn resolve(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError>;
+    fn chargeback(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError>;
+
+    fn representment(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+    ) -> Result<(), LedgerError> {
+        ledger::representment(account, rec)
+    }
+
+    fn prearbitration(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+    ) -> Result<(), LedgerError> {
+        ledger::prearbitration(account, rec)
+    }
+
+    fn arbitration(
+        &self,
+        _account: &mut AccountState,
+        rec: &mut TxRecord,
+    ) -> Result<(), LedgerError> {
+        ledger::arbitration(rec)
+    }
+}
+
+/// The default: only deposits can be disputed, and a chargeback locks the account.
+/// Disputing a withdrawal is rejected with `LedgerError::DisputeOnWithdrawal`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DepositsOnly;
+
+impl DisputePolicy for DepositsOnly {
+    fn dispute(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError> {
+        ledger::dispute(account, rec, amount)
+    }
+
+    fn resolve(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError> {
+        ledger::resolve(account, rec, amount)
+    }
+
+    fn chargeback(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError> {
+        ledger::chargeback(account, rec, amount)
+    }
+}
+
+/// Deposits as in `DepositsOnly`, plus withdrawal disputes for card issuing: the amount is
+/// held for the client while investigated, and a chargeback credits it back without locking.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DepositsAndWithdrawals;
+
+impl DisputePolicy for DepositsAndWithdrawals {
+    fn dispute(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError> {
+        match rec.kind {
+            TxKind::Deposit => ledger::dispute(account, rec, amount),
+            TxKind::Withdrawal => ledger::dispute_withdrawal(account, rec, amount),
+        }
+    }
+
+    fn resolve(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError> {
+        match rec.kind {
+            TxKind::Deposit => ledger::resolve(account, rec, amount),
+            TxKind::Withdrawal => ledger::resolve_withdrawal(account, rec, amount),
+        }
+    }
+
+    fn chargeback(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError> {
+        match rec.kind {
+            TxKind::Deposit => ledger::chargeback(account, rec, amount),
+            TxKind::Withdrawal => ledger::chargeback_withdrawal(account, rec, amount),
+        }
+    }
+}
+
+/// Lets one policy be shared, e.g. by the workers of a `ParallelProcessor`.
+impl<P: DisputePolicy + ?Sized> DisputePolicy for Arc<P> {
+    fn dispute(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError> {
+        (**self).dispute(account, rec, amount)
+    }
+
+    fn resolve(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError> {
+        (**self).resolve(account, rec, amount)
+    }
+
+    fn chargeback(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+        amount: Option<Amount>,
+    ) -> Result<(), LedgerError> {
+        (**self).chargeback(account, rec, amount)
+    }
+
+    fn representment(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+    ) -> Result<(), LedgerError> {
+        (**self).representment(account, rec)
+    }
+
+    fn prearbitration(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+    ) -> Result<(), LedgerError> {
+        (**self).prearbitration(account, rec)
+    }
+
+    fn arbitration(
+        &self,
+        account: &mut AccountState,
+        rec: &mut TxRecord,
+    ) -> Result<(), LedgerError> {
+        (**self).arbitration(account, rec)
+    }
+}
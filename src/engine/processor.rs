@@ -1,162 +1,324 @@
+use std::collections::VecDeque;
+
+use crate::core::errors::LedgerError;
 use crate::core::ledger;
 use crate::core::types::*;
+use crate::engine::journal::{Balances, Journal};
 use crate::engine::metrics::Metrics;
-use crate::engine::state::{AccountState, EngineState, TxKind, TxRecord};
+use crate::engine::outcome::{ApplyOutcome, RejectReason, StrictViolation};
+use crate::engine::policy::{DepositsOnly, DisputePolicy, DisputeWindow};
+use crate::engine::state::{DisputeStage, EngineState, TxKind, TxRecord};
 use crate::engine::store::TxStore;
 use crate::io::IngestEvent;
 
+/// The account an applied event touched, and its balances either side of it.
+struct Change {
+    asset: Asset,
+    before: Balances,
+    after: Balances,
+}
+
+// when a tx was applied: its event count and timestamp
+type Stamp = (u64, Option<Timestamp>);
+
 pub struct Processor<S: TxStore> {
     state: EngineState<S>,
     metrics: Metrics,
+    strict: bool,
+    journal: Option<Journal>,
+    policy: Box<dyn DisputePolicy>,
+    window: Option<DisputeWindow>,
+    // stored txs oldest first, when evicting
+    expiry: Option<VecDeque<(Stamp, TxId)>>,
 }
 
 impl<S: TxStore> Processor<S> {
     pub fn new(store: S) -> Self {
+        Self::from_state(EngineState::new(store))
+    }
+
+    /// Continues from previously saved state, e.g. one restored with `EngineState::load`.
+    pub fn from_state(state: EngineState<S>) -> Self {
         Self {
-            state: EngineState::new(store),
+            state,
             metrics: Metrics::default(),
+            strict: false,
+            journal: None,
+            policy: Box::new(DepositsOnly),
+            window: None,
+            expiry: None,
         }
     }
 
+    /// In strict mode `try_apply_event` fails on the first malformed row, unknown type
+    /// or missing amount instead of skipping it. Ledger rule rejections are unaffected.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Replaces the default `DepositsOnly` rules for dispute, resolve and chargeback.
+    pub fn with_dispute_policy(mut self, policy: impl DisputePolicy + 'static) -> Self {
+        self.policy = Box::new(policy);
+        self
+    }
+
+    /// Rejects disputes on txs older than `window` with `LedgerError::DisputeWindowExpired`.
+    /// Resolves, chargebacks and escalations of disputes opened in time are unaffected.
+    pub fn with_dispute_window(mut self, window: DisputeWindow) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// With a dispute window, drops tx records from the store once they're out of it, so
+    /// memory stays bounded. Records with a dispute still going on are kept until it closes.
+    /// An evicted tx id is no longer recognised as a duplicate.
+    pub fn with_eviction(mut self) -> Self {
+        let mut stored = Vec::new();
+        self.state
+            .store
+            .for_each(&mut |tx, rec| stored.push(((rec.seq, rec.time), tx)));
+        stored.sort_unstable();
+        self.expiry = Some(stored.into());
+        self
+    }
+
     pub fn metrics(&self) -> &Metrics {
         &self.metrics
     }
 
-    pub fn apply_event(&mut self, event: IngestEvent) {
-        match event {
-            IngestEvent::Tx(tx) => self.apply(tx),
-            IngestEvent::MalformedRow => self.metrics.malformed_rows += 1,
-            IngestEvent::UnknownType => self.metrics.unknown_type += 1,
+    pub fn apply_event(&mut self, event: IngestEvent) -> ApplyOutcome {
+        self.state.events += 1;
+        if let IngestEvent::Tx { tx, .. } = &event {
+            self.state.time = self.state.time.max(tx.timestamp);
         }
-    }
-
-    fn apply(&mut self, tx: Transaction) {
-        let (accounts, store) = (&mut self.state.accounts, &mut self.state.store);
+        let res = match event {
+            IngestEvent::Tx { tx, pos, .. } => self.apply_journaled(tx, pos.line),
+            IngestEvent::MalformedRow { .. } => Err(RejectReason::MalformedRow),
+            IngestEvent::UnknownType { .. } => Err(RejectReason::UnknownType),
+            IngestEvent::Late { .. } => Err(RejectReason::Late),
+        };
 
-        // get or create account
-        let account = accounts.entry(tx.client).or_insert_with(AccountState::new);
+        let outcome = match res {
+            Ok(()) => ApplyOutcome::Applied,
+            Err(reason) => ApplyOutcome::Rejected(reason),
+        };
+        self.metrics.record(&outcome);
+        self.evict_expired();
+        outcome
+    }
 
-        if account.locked {
-            self.metrics.locked_ignored += 1;
+    // records still in a dispute are looked at again a window later
+    fn evict_expired(&mut self) {
+        let (Some(window), Some(expiry)) = (self.window, self.expiry.as_mut()) else {
             return;
+        };
+        let now = (self.state.events, self.state.time);
+        while let Some(&(stamp, tx)) = expiry.front() {
+            // untimestamped txs never leave a window in seconds
+            if matches!(window, DisputeWindow::Seconds(_)) && stamp.1.is_none() {
+                expiry.pop_front();
+                continue;
+            }
+            if !window.expired(stamp, now) {
+                break;
+            }
+            expiry.pop_front();
+            match self.state.store.get(tx) {
+                Some(rec) if rec.stage == DisputeStage::Undisputed || rec.stage.is_final() => {
+                    self.state.store.remove(tx)
+                }
+                Some(_) => expiry.push_back((now, tx)),
+                None => {}
+            }
         }
+    }
 
-        match tx.kind {
-            TransactionType::Deposit => {
-                let amount = match tx.amount {
-                    Some(a) => a,
-                    None => {
-                        self.metrics.missing_amount += 1;
-                        return;
-                    }
-                };
+    /// Records every applied event with the account balances before and after it.
+    pub fn with_journal(mut self) -> Self {
+        self.journal = Some(Journal::new());
+        self
+    }
 
-                if store.contains(tx.tx) {
-                    self.metrics.duplicate_tx += 1;
-                    return;
-                }
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
 
-                if ledger::deposit(account, amount).is_ok() {
-                    store.insert(
-                        tx.tx,
-                        TxRecord {
-                            client: tx.client,
-                            amount,
-                            kind: TxKind::Deposit,
-                            disputed: false,
-                        },
-                    );
-                } else {
-                    self.metrics.ledger_errors += 1;
-                }
+    pub fn journal_mut(&mut self) -> Option<&mut Journal> {
+        self.journal.as_mut()
+    }
+
+    /// Like `apply_event`, but honours strict mode. The violating event changes no balance
+    /// or tx record, but it has been counted, in the metrics and in the event count dispute
+    /// windows use. The caller is expected to stop feeding events.
+    pub fn try_apply_event(&mut self, event: IngestEvent) -> Result<ApplyOutcome, StrictViolation> {
+        // only a malformed row owns heap data, and strict mode stops at those anyway
+        let source = self.strict.then(|| event.clone());
+
+        let outcome = self.apply_event(event);
+        match (outcome, source) {
+            (ApplyOutcome::Rejected(reason), Some(source)) if reason.is_validation() => {
+                Err(StrictViolation {
+                    line: source.pos().line,
+                    reason,
+                    detail: source.to_string(),
+                })
             }
+            _ => Ok(outcome),
+        }
+    }
 
-            TransactionType::Withdrawal => {
-                let amount = match tx.amount {
-                    Some(a) => a,
-                    None => {
-                        self.metrics.missing_amount += 1;
-                        return;
-                    }
-                };
+    fn apply_journaled(&mut self, tx: Transaction, line: u64) -> Result<(), RejectReason> {
+        let change = self.apply(tx)?;
+
+        if let Some(journal) = self.journal.as_mut() {
+            journal.append(line, &tx, change.asset, change.before, change.after);
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, tx: Transaction) -> Result<Change, RejectReason> {
+        let (accounts, store) = (&mut self.state.accounts, &mut self.state.store);
+        let policy = &*self.policy;
+        let now = (self.state.events, self.state.time);
+
+        // the dispute lifecycle refers back to a stored tx
+        let referenced = match tx.kind {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Lock
+            | TransactionType::Unlock => None,
+            _ => store.get(tx.tx),
+        };
+
+        // a row without an asset belongs to the referenced tx's account, if it has one
+        let asset = match referenced {
+            Some(rec) if tx.asset.is_default() && rec.client == tx.client => rec.asset,
+            _ => tx.asset,
+        };
+
+        // admin rows act on an existing account; any other first sight of a client opens
+        // one, even if that first event is rejected
+        let admin = matches!(tx.kind, TransactionType::Lock | TransactionType::Unlock);
+        if admin && !accounts.contains_key(&(tx.client, asset)) {
+            return Err(RejectReason::UnknownAccount);
+        }
+        let current = accounts.entry((tx.client, asset)).or_default();
+
+        // a locked account still takes lock and unlock, and an escalated dispute runs its
+        // course: a representment usually follows the chargeback that caused the lock
+        let escalation = matches!(
+            tx.kind,
+            TransactionType::Representment
+                | TransactionType::PreArbitration
+                | TransactionType::Arbitration
+        ) || (matches!(
+            tx.kind,
+            TransactionType::Resolve | TransactionType::Chargeback
+        ) && referenced.is_some_and(|rec| rec.stage.is_escalated()));
+        if current.locked && !admin && !escalation {
+            return Err(RejectReason::AccountLocked);
+        }
+
+        // work on copies and write both back only once every rule has passed,
+        // so a failure part way through an operation leaves no trace
+        let (account, rec) = match tx.kind {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let mut account = current.clone();
+                let amount = tx.amount.ok_or(RejectReason::MissingAmount)?;
 
                 if store.contains(tx.tx) {
-                    self.metrics.duplicate_tx += 1;
-                    return;
+                    return Err(RejectReason::DuplicateTx);
                 }
 
-                if ledger::withdrawal(account, amount).is_ok() {
-                    store.insert(
-                        tx.tx,
-                        TxRecord {
-                            client: tx.client,
-                            amount,
-                            kind: TxKind::Withdrawal,
-                            disputed: false,
-                        },
-                    );
+                let kind = if tx.kind == TransactionType::Deposit {
+                    ledger::deposit(&mut account, amount).map_err(RejectReason::Ledger)?;
+                    TxKind::Deposit
                 } else {
-                    self.metrics.ledger_errors += 1;
-                }
-            }
-
-            TransactionType::Dispute => {
-                let rec = match store.get_mut(tx.tx) {
-                    Some(r) => r,
-                    None => {
-                        self.metrics.tx_not_found += 1;
-                        return;
-                    }
+                    ledger::withdrawal(&mut account, amount).map_err(RejectReason::Ledger)?;
+                    TxKind::Withdrawal
                 };
 
-                if rec.client != tx.client {
-                    self.metrics.wrong_client_ref += 1;
-                    return;
-                }
-
-                if ledger::dispute(account, rec).is_err() {
-                    self.metrics.ledger_errors += 1;
+                if let Some(expiry) = self.expiry.as_mut() {
+                    expiry.push_back(((now.0, tx.timestamp), tx.tx));
                 }
-            }
-
-            TransactionType::Resolve => {
-                let rec = match store.get_mut(tx.tx) {
-                    Some(r) => r,
-                    None => {
-                        self.metrics.tx_not_found += 1;
-                        return;
-                    }
+                let rec = TxRecord {
+                    seq: now.0,
+                    time: tx.timestamp,
+                    ..TxRecord::new(tx.client, tx.asset, kind, amount)
                 };
+                (account, Some(rec))
+            }
 
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Representment
+            | TransactionType::PreArbitration
+            | TransactionType::Arbitration => {
+                let mut rec = referenced.ok_or(RejectReason::TxNotFound)?;
                 if rec.client != tx.client {
-                    self.metrics.wrong_client_ref += 1;
-                    return;
+                    return Err(RejectReason::WrongClient);
+                }
+                // a partial amount is in units of its asset, so it has to name it
+                if rec.asset != asset || (tx.amount.is_some() && tx.asset != rec.asset) {
+                    return Err(RejectReason::WrongAsset);
                 }
 
-                if ledger::resolve(account, rec).is_err() {
-                    self.metrics.ledger_errors += 1;
+                if tx.kind == TransactionType::Dispute
+                    && self
+                        .window
+                        .is_some_and(|w| w.expired((rec.seq, rec.time), now))
+                {
+                    return Err(RejectReason::Ledger(LedgerError::DisputeWindowExpired));
                 }
-            }
 
-            TransactionType::Chargeback => {
-                let rec = match store.get_mut(tx.tx) {
-                    Some(r) => r,
-                    None => {
-                        self.metrics.tx_not_found += 1;
-                        return;
+                let mut account = current.clone();
+                match tx.kind {
+                    TransactionType::Dispute => policy.dispute(&mut account, &mut rec, tx.amount),
+                    TransactionType::Resolve => policy.resolve(&mut account, &mut rec, tx.amount),
+                    TransactionType::Chargeback => {
+                        policy.chargeback(&mut account, &mut rec, tx.amount)
                     }
-                };
-
-                if rec.client != tx.client {
-                    self.metrics.wrong_client_ref += 1;
-                    return;
+                    TransactionType::Representment => policy.representment(&mut account, &mut rec),
+                    TransactionType::PreArbitration => {
+                        policy.prearbitration(&mut account, &mut rec)
+                    }
+                    _ => policy.arbitration(&mut account, &mut rec),
                 }
+                .map_err(RejectReason::Ledger)?;
+                (account, Some(rec))
+            }
 
-                if ledger::chargeback(account, rec).is_err() {
-                    self.metrics.ledger_errors += 1;
+            // admin rows don't refer to or create a tx; their tx id is ignored
+            TransactionType::Lock | TransactionType::Unlock => {
+                let mut account = current.clone();
+                if tx.kind == TransactionType::Lock {
+                    ledger::lock(&mut account)
+                } else {
+                    ledger::unlock(&mut account)
                 }
+                .map_err(RejectReason::Ledger)?;
+                (account, None)
             }
+        };
+
+        let key = (tx.client, asset);
+        let before = Balances::from(&accounts[&key]);
+        let after = Balances::from(&account);
+
+        // the store goes first: it's the only write that can fail (a durable backend panics),
+        // and the account must not move if it does
+        if let Some(rec) = rec {
+            store.insert(tx.tx, rec);
         }
+        accounts.insert(key, account);
+
+        Ok(Change {
+            asset,
+            before,
+            after,
+        })
     }
 
     pub fn results(&self) -> Vec<AccountRow> {
@@ -164,8 +326,9 @@ impl<S: TxStore> Processor<S> {
             .state
             .accounts
             .iter()
-            .map(|(&client, acc)| AccountRow {
+            .map(|(&(client, asset), acc)| AccountRow {
                 client,
+                asset,
                 available: acc.available,
                 held: acc.held,
                 total: acc.total(),
@@ -173,11 +336,692 @@ impl<S: TxStore> Processor<S> {
             })
             .collect();
 
-        rows.sort_by_key(|r| r.client);
+        rows.sort_by_key(|r| (r.client, r.asset));
         rows
     }
 
     pub fn state(&self) -> &EngineState<S> {
         &self.state
     }
+
+    pub(crate) fn state_mut(&mut self) -> &mut EngineState<S> {
+        &mut self.state
+    }
+
+    /// Makes the tx store's buffered writes durable; see `TxStore::flush`.
+    pub fn flush_store(&mut self) -> std::io::Result<()> {
+        self.state.store.flush()
+    }
+
+    pub fn into_state(self) -> EngineState<S> {
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::errors::{CoreError, LedgerError};
+    use crate::engine::policy::{DepositsAndWithdrawals, DisputeWindow};
+    use crate::engine::state::{AccountState, DisputeStage};
+    use crate::engine::store::HashMapStore;
+    use crate::io::SourcePos;
+
+    fn malformed() -> IngestEvent {
+        IngestEvent::MalformedRow {
+            pos: SourcePos::default(),
+            raw: String::new(),
+            error: CoreError::ParseAmount,
+            client: None,
+            tx: None,
+        }
+    }
+
+    fn amt(s: &str) -> Amount {
+        Amount::from_str_4dp(s).unwrap()
+    }
+
+    fn tx(kind: TransactionType, client: ClientId, tx: TxId, amount: Option<&str>) -> IngestEvent {
+        IngestEvent::Tx {
+            tx: Transaction {
+                kind,
+                client,
+                tx,
+                amount: amount.map(amt),
+                asset: Asset::default(),
+                timestamp: None,
+            },
+            pos: SourcePos::default(),
+            rounding: None,
+        }
+    }
+
+    fn at(time: Timestamp, mut event: IngestEvent) -> IngestEvent {
+        if let IngestEvent::Tx { tx, .. } = &mut event {
+            tx.timestamp = Some(time);
+        }
+        event
+    }
+
+    #[test]
+    fn deposit_is_applied() {
+        let mut p = Processor::new(HashMapStore::new());
+        let out = p.apply_event(tx(TransactionType::Deposit, 1, 1, Some("1.0")));
+        assert_eq!(out, ApplyOutcome::Applied);
+    }
+
+    #[test]
+    fn rejections_carry_their_reason() {
+        let mut p = Processor::new(HashMapStore::new());
+        p.apply_event(tx(TransactionType::Deposit, 1, 1, Some("1.0")));
+
+        let cases = [
+            (
+                tx(TransactionType::Deposit, 1, 1, Some("1.0")),
+                RejectReason::DuplicateTx,
+            ),
+            (
+                tx(TransactionType::Deposit, 1, 2, None),
+                RejectReason::MissingAmount,
+            ),
+            (
+                tx(TransactionType::Dispute, 1, 99, None),
+                RejectReason::TxNotFound,
+            ),
+            (
+                tx(TransactionType::Dispute, 2, 1, None),
+                RejectReason::WrongClient,
+            ),
+            (
+                tx(TransactionType::Resolve, 1, 1, None),
+                RejectReason::Ledger(LedgerError::TxNotDisputed),
+            ),
+            (
+                tx(TransactionType::Withdrawal, 1, 3, Some("5.0")),
+                RejectReason::Ledger(LedgerError::InsufficientFunds),
+            ),
+            (malformed(), RejectReason::MalformedRow),
+            (
+                IngestEvent::UnknownType {
+                    pos: SourcePos::default(),
+                    client: 1,
+                    tx: 5,
+                },
+                RejectReason::UnknownType,
+            ),
+        ];
+
+        for (event, reason) in cases {
+            assert_eq!(p.apply_event(event), ApplyOutcome::Rejected(reason));
+        }
+    }
+
+    #[test]
+    fn locked_account_rejects_everything() {
+        let mut p = Processor::new(HashMapStore::new());
+        p.apply_event(tx(TransactionType::Deposit, 1, 1, Some("1.0")));
+        p.apply_event(tx(TransactionType::Dispute, 1, 1, None));
+        p.apply_event(tx(TransactionType::Chargeback, 1, 1, None));
+
+        let out = p.apply_event(tx(TransactionType::Deposit, 1, 2, Some("1.0")));
+        assert_eq!(out, ApplyOutcome::Rejected(RejectReason::AccountLocked));
+    }
+
+    #[test]
+    fn strict_mode_fails_on_validation_but_not_ledger_rejects() {
+        let mut p = Processor::new(HashMapStore::new()).with_strict(true);
+
+        let out = p.try_apply_event(tx(TransactionType::Withdrawal, 1, 1, Some("1.0")));
+        assert_eq!(
+            out,
+            Ok(ApplyOutcome::Rejected(RejectReason::Ledger(
+                LedgerError::InsufficientFunds
+            )))
+        );
+
+        let err = p
+            .try_apply_event(tx(TransactionType::Deposit, 1, 2, None))
+            .unwrap_err();
+        assert_eq!(err.reason, RejectReason::MissingAmount);
+
+        let err = p.try_apply_event(malformed()).unwrap_err();
+        assert_eq!(err.reason, RejectReason::MalformedRow);
+    }
+
+    #[test]
+    fn lenient_mode_never_fails() {
+        let mut p = Processor::new(HashMapStore::new());
+        let out = p.try_apply_event(malformed());
+        assert_eq!(out, Ok(ApplyOutcome::Rejected(RejectReason::MalformedRow)));
+    }
+
+    #[test]
+    fn journal_records_applied_events_with_before_and_after() {
+        let mut p = Processor::new(HashMapStore::new()).with_journal();
+        p.apply_event(tx(TransactionType::Deposit, 1, 1, Some("2.0")));
+        p.apply_event(tx(TransactionType::Withdrawal, 1, 2, Some("5.0")));
+        p.apply_event(tx(TransactionType::Dispute, 1, 1, None));
+
+        let entries = p.journal().unwrap().entries();
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].seq, 1);
+        assert_eq!(entries[0].kind, TransactionType::Deposit);
+        assert_eq!(entries[0].before, Balances::default());
+        assert_eq!(entries[0].after.available, amt("2.0"));
+
+        assert_eq!(entries[1].seq, 2);
+        assert_eq!(entries[1].kind, TransactionType::Dispute);
+        assert_eq!(entries[1].before.available, amt("2.0"));
+        assert_eq!(entries[1].after.available, amt("0"));
+        assert_eq!(entries[1].after.held, amt("2.0"));
+    }
+
+    #[test]
+    fn journal_drain_keeps_sequence() {
+        let mut p = Processor::new(HashMapStore::new()).with_journal();
+        p.apply_event(tx(TransactionType::Deposit, 1, 1, Some("1.0")));
+        assert_eq!(p.journal_mut().unwrap().drain().count(), 1);
+
+        p.apply_event(tx(TransactionType::Deposit, 1, 2, Some("1.0")));
+        assert_eq!(p.journal().unwrap().entries()[0].seq, 2);
+    }
+
+    #[test]
+    fn resumed_processor_can_dispute_earlier_deposits() {
+        let mut day1 = Processor::new(HashMapStore::new());
+        day1.apply_event(tx(TransactionType::Deposit, 1, 1, Some("3.0")));
+        day1.apply_event(tx(TransactionType::Deposit, 1, 2, Some("1.0")));
+        day1.apply_event(tx(TransactionType::Dispute, 1, 2, None));
+
+        let mut buf = Vec::new();
+        day1.state().save(&mut buf).unwrap();
+
+        let state = EngineState::load(buf.as_slice(), HashMapStore::new()).unwrap();
+        let mut day2 = Processor::from_state(state);
+
+        let out = day2.apply_event(tx(TransactionType::Dispute, 1, 1, None));
+        assert_eq!(out, ApplyOutcome::Applied);
+        let out = day2.apply_event(tx(TransactionType::Resolve, 1, 2, None));
+        assert_eq!(out, ApplyOutcome::Applied);
+        let out = day2.apply_event(tx(TransactionType::Deposit, 1, 2, Some("1.0")));
+        assert_eq!(out, ApplyOutcome::Rejected(RejectReason::DuplicateTx));
+
+        let rows = day2.results();
+        assert_eq!(rows[0].available, amt("1.0"));
+        assert_eq!(rows[0].held, amt("3.0"));
+    }
+
+    fn tx_in(kind: TransactionType, id: TxId, amount: Option<&str>, asset: &str) -> IngestEvent {
+        let mut ev = tx(kind, 1, id, amount);
+        if let IngestEvent::Tx { tx, .. } = &mut ev {
+            tx.asset = Asset::parse(asset).unwrap();
+        }
+        ev
+    }
+
+    #[test]
+    fn balances_are_kept_per_asset() {
+        let mut p = Processor::new(HashMapStore::new());
+        p.apply_event(tx_in(TransactionType::Deposit, 1, Some("5.0"), "usd"));
+        p.apply_event(tx_in(TransactionType::Deposit, 2, Some("1.0"), "btc"));
+
+        let out = p.apply_event(tx_in(TransactionType::Withdrawal, 3, Some("2.0"), "btc"));
+        assert_eq!(
+            out,
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::InsufficientFunds))
+        );
+
+        let rows = p.results();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            (rows[0].asset.as_str(), rows[0].available),
+            ("BTC", amt("1.0"))
+        );
+        assert_eq!(
+            (rows[1].asset.as_str(), rows[1].available),
+            ("USD", amt("5.0"))
+        );
+    }
+
+    #[test]
+    fn dispute_without_asset_acts_on_the_original_asset() {
+        let mut p = Processor::new(HashMapStore::new());
+        p.apply_event(tx_in(TransactionType::Deposit, 1, Some("5.0"), "usd"));
+
+        let out = p.apply_event(tx(TransactionType::Dispute, 1, 1, None));
+        assert_eq!(out, ApplyOutcome::Applied);
+        let out = p.apply_event(tx(TransactionType::Chargeback, 1, 1, None));
+        assert_eq!(out, ApplyOutcome::Applied);
+
+        // no stray account for the default asset
+        let rows = p.results();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].asset.as_str(), "USD");
+        assert_eq!(rows[0].total, Amount::zero());
+        assert!(rows[0].locked);
+    }
+
+    #[test]
+    fn dispute_naming_another_asset_is_rejected() {
+        let mut p = Processor::new(HashMapStore::new());
+        p.apply_event(tx_in(TransactionType::Deposit, 1, Some("5.0"), "usd"));
+
+        let out = p.apply_event(tx_in(TransactionType::Dispute, 1, None, "eur"));
+        assert_eq!(out, ApplyOutcome::Rejected(RejectReason::WrongAsset));
+        assert_eq!(p.metrics().wrong_asset_ref, 1);
+        assert!(!p.state().store.get(1).unwrap().is_disputed());
+    }
+
+    #[test]
+    fn withdrawal_disputes_depend_on_the_policy() {
+        let events = || {
+            vec![
+                tx(TransactionType::Deposit, 1, 1, Some("5.0")),
+                tx(TransactionType::Withdrawal, 1, 2, Some("2.0")),
+                tx(TransactionType::Dispute, 1, 2, None),
+                tx(TransactionType::Chargeback, 1, 2, None),
+            ]
+        };
+
+        let mut p = Processor::new(HashMapStore::new());
+        let outcomes: Vec<_> = events().into_iter().map(|e| p.apply_event(e)).collect();
+        assert_eq!(
+            outcomes[2],
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::DisputeOnWithdrawal))
+        );
+
+        let mut p = Processor::new(HashMapStore::new()).with_dispute_policy(DepositsAndWithdrawals);
+        let mut events = events().into_iter();
+        for e in events.by_ref().take(3) {
+            assert!(p.apply_event(e).is_applied());
+        }
+        assert_eq!(p.results()[0].held, amt("2.0"));
+        assert!(p.apply_event(events.next().unwrap()).is_applied());
+
+        // the money came back and the client, being the victim, isn't locked
+        let row = &p.results()[0];
+        assert_eq!(row.available, amt("5.0"));
+        assert_eq!(row.held, Amount::zero());
+        assert!(!row.locked);
+    }
+
+    #[test]
+    fn partial_amounts_must_name_a_named_asset() {
+        let mut p = Processor::new(HashMapStore::new());
+        p.apply_event(tx_in(TransactionType::Deposit, 1, Some("5.0"), "usd"));
+
+        let out = p.apply_event(tx(TransactionType::Dispute, 1, 1, Some("1.0")));
+        assert_eq!(out, ApplyOutcome::Rejected(RejectReason::WrongAsset));
+
+        let out = p.apply_event(tx_in(TransactionType::Dispute, 1, Some("1.0"), "usd"));
+        assert_eq!(out, ApplyOutcome::Applied);
+        assert_eq!(p.results()[0].held, amt("1.0"));
+    }
+
+    #[test]
+    fn partial_disputes_on_one_deposit() {
+        let mut p = Processor::new(HashMapStore::new());
+        let events = vec![
+            tx(TransactionType::Deposit, 1, 1, Some("10.0")),
+            tx(TransactionType::Dispute, 1, 1, Some("4.0")),
+            tx(TransactionType::Dispute, 1, 1, Some("3.0")),
+            tx(TransactionType::Resolve, 1, 1, Some("5.0")),
+            // the rest of the dispute; the account locks, so this has to come last
+            tx(TransactionType::Chargeback, 1, 1, None),
+        ];
+        for e in events {
+            assert!(p.apply_event(e).is_applied());
+        }
+
+        let row = &p.results()[0];
+        assert_eq!(row.available, amt("8.0"));
+        assert_eq!(row.held, Amount::zero());
+        assert!(row.locked);
+
+        let rec = p.state().store.get(1).unwrap();
+        assert_eq!(rec.charged_back, amt("2.0"));
+        assert_eq!(rec.undisputed(), amt("8.0"));
+    }
+
+    #[test]
+    fn unlock_reinstates_but_does_not_replay_dropped_rows() {
+        let mut p = Processor::new(HashMapStore::new()).with_journal();
+        let outcomes: Vec<_> = vec![
+            tx(TransactionType::Deposit, 1, 1, Some("5.0")),
+            tx(TransactionType::Lock, 1, 0, None),
+            tx(TransactionType::Deposit, 1, 2, Some("1.0")),
+            tx(TransactionType::Lock, 1, 0, None),
+            tx(TransactionType::Unlock, 1, 0, None),
+            tx(TransactionType::Unlock, 1, 0, None),
+            tx(TransactionType::Deposit, 1, 3, Some("2.0")),
+        ]
+        .into_iter()
+        .map(|e| p.apply_event(e))
+        .collect();
+
+        assert_eq!(
+            outcomes[2],
+            ApplyOutcome::Rejected(RejectReason::AccountLocked)
+        );
+        assert_eq!(
+            outcomes[3],
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::AccountAlreadyLocked))
+        );
+        assert_eq!(
+            outcomes[5],
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::AccountNotLocked))
+        );
+        assert!(outcomes[6].is_applied());
+
+        // tx 2 arrived while locked and stays dropped, so its id was never taken
+        assert_eq!(p.results()[0].available, amt("7.0"));
+        assert!(!p.state().store.contains(2));
+        assert!(!p.state().store.contains(0));
+
+        let kinds: Vec<_> = p
+            .journal()
+            .unwrap()
+            .entries()
+            .iter()
+            .map(|e| (e.kind, e.before.locked, e.after.locked))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (TransactionType::Deposit, false, false),
+                (TransactionType::Lock, false, true),
+                (TransactionType::Unlock, true, false),
+                (TransactionType::Deposit, false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn admin_rows_need_an_existing_account() {
+        let mut p = Processor::new(HashMapStore::new());
+        p.apply_event(tx_in(TransactionType::Deposit, 1, Some("5.0"), "EUR"));
+
+        for event in [
+            tx(TransactionType::Lock, 1, 0, None),
+            tx(TransactionType::Unlock, 2, 0, None),
+            tx_in(TransactionType::Lock, 0, None, "USD"),
+        ] {
+            assert_eq!(
+                p.apply_event(event),
+                ApplyOutcome::Rejected(RejectReason::UnknownAccount)
+            );
+        }
+        assert_eq!(p.results().len(), 1);
+
+        let out = p.apply_event(tx_in(TransactionType::Lock, 0, None, "EUR"));
+        assert_eq!(out, ApplyOutcome::Applied);
+        assert!(p.results()[0].locked);
+    }
+
+    #[test]
+    fn escalated_dispute_runs_its_course_on_a_locked_account() {
+        let mut p = Processor::new(HashMapStore::new());
+        let outcomes: Vec<_> = vec![
+            tx(TransactionType::Deposit, 1, 1, Some("5.0")),
+            tx(TransactionType::Dispute, 1, 1, None),
+            tx(TransactionType::Chargeback, 1, 1, None),
+            tx(TransactionType::Representment, 1, 1, None),
+            tx(TransactionType::Deposit, 1, 2, Some("1.0")),
+            tx(TransactionType::PreArbitration, 1, 1, None),
+            tx(TransactionType::Arbitration, 1, 1, None),
+            tx(TransactionType::Arbitration, 1, 1, None),
+            tx(TransactionType::Resolve, 1, 1, None),
+            tx(TransactionType::Dispute, 1, 1, None),
+        ]
+        .into_iter()
+        .map(|e| p.apply_event(e))
+        .collect();
+
+        assert!(outcomes[3].is_applied());
+        assert_eq!(
+            outcomes[4],
+            ApplyOutcome::Rejected(RejectReason::AccountLocked)
+        );
+        assert!(outcomes[5].is_applied());
+        assert!(outcomes[6].is_applied());
+        assert_eq!(
+            outcomes[7],
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::NotInPreArbitration))
+        );
+        assert!(outcomes[8].is_applied());
+        // a closed dispute is no longer escalated, so the lock applies again
+        assert_eq!(
+            outcomes[9],
+            ApplyOutcome::Rejected(RejectReason::AccountLocked)
+        );
+
+        let row = &p.results()[0];
+        assert_eq!(row.available, amt("5.0"));
+        assert_eq!(row.held, amt("0"));
+        assert!(row.locked);
+        assert_eq!(p.state().store.get(1).unwrap().stage, DisputeStage::Upheld);
+    }
+
+    #[test]
+    fn disputes_outside_the_window_are_rejected() {
+        let mut p =
+            Processor::new(HashMapStore::new()).with_dispute_window(DisputeWindow::Events(2));
+        let outcomes: Vec<_> = vec![
+            tx(TransactionType::Deposit, 1, 1, Some("5.0")),
+            tx(TransactionType::Deposit, 1, 2, Some("1.0")),
+            tx(TransactionType::Dispute, 1, 2, None),
+            tx(TransactionType::Dispute, 1, 1, None),
+            // a dispute opened in time can still be settled later
+            tx(TransactionType::Deposit, 1, 3, Some("1.0")),
+            tx(TransactionType::Resolve, 1, 2, None),
+        ]
+        .into_iter()
+        .map(|e| p.apply_event(e))
+        .collect();
+
+        assert!(outcomes[2].is_applied());
+        assert_eq!(
+            outcomes[3],
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::DisputeWindowExpired))
+        );
+        assert!(outcomes[5].is_applied());
+        // without eviction everything stays
+        assert!(p.state().store.contains(1));
+    }
+
+    #[test]
+    fn dispute_window_in_seconds_follows_the_timestamps() {
+        let mut p =
+            Processor::new(HashMapStore::new()).with_dispute_window(DisputeWindow::Seconds(120));
+        let outcomes: Vec<_> = vec![
+            at(100, tx(TransactionType::Deposit, 1, 1, Some("5.0"))),
+            tx(TransactionType::Deposit, 1, 2, Some("1.0")),
+            at(200, tx(TransactionType::Deposit, 1, 3, Some("1.0"))),
+            at(250, tx(TransactionType::Dispute, 1, 1, None)),
+            // untimestamped txs never expire; a row without one is as of the latest time
+            tx(TransactionType::Dispute, 1, 2, None),
+            tx(TransactionType::Dispute, 1, 3, None),
+        ]
+        .into_iter()
+        .map(|e| p.apply_event(e))
+        .collect();
+
+        assert_eq!(
+            outcomes[3],
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::DisputeWindowExpired))
+        );
+        assert!(outcomes[4].is_applied());
+        assert!(outcomes[5].is_applied());
+        assert_eq!(p.state().time, Some(250));
+    }
+
+    #[test]
+    fn late_events_are_rejected_as_such() {
+        let mut p = Processor::new(HashMapStore::new());
+        let late = match tx(TransactionType::Deposit, 1, 1, Some("5.0")) {
+            IngestEvent::Tx { tx, pos, .. } => IngestEvent::Late { tx, pos },
+            _ => unreachable!(),
+        };
+
+        assert_eq!(
+            p.apply_event(late),
+            ApplyOutcome::Rejected(RejectReason::Late)
+        );
+        assert_eq!(p.metrics().late_events, 1);
+        assert!(!p.state().store.contains(1));
+    }
+
+    #[test]
+    fn eviction_drops_expired_records_but_keeps_open_disputes() {
+        let mut p = Processor::new(HashMapStore::new())
+            .with_dispute_window(DisputeWindow::Events(2))
+            .with_eviction();
+        for event in [
+            tx(TransactionType::Deposit, 1, 1, Some("5.0")),
+            tx(TransactionType::Deposit, 1, 2, Some("1.0")),
+            tx(TransactionType::Dispute, 1, 2, None),
+            tx(TransactionType::Deposit, 1, 3, Some("1.0")),
+            tx(TransactionType::Deposit, 1, 4, Some("1.0")),
+        ] {
+            p.apply_event(event);
+        }
+
+        let store = &p.state().store;
+        assert!(!store.contains(1));
+        assert!(store.contains(2));
+        assert!(store.contains(3));
+
+        // an evicted id reads as unknown
+        let out = p.apply_event(tx(TransactionType::Dispute, 1, 1, None));
+        assert_eq!(out, ApplyOutcome::Rejected(RejectReason::TxNotFound));
+    }
+
+    fn state_with(available: i64, held: i64, rec: TxRecord) -> EngineState<HashMapStore> {
+        let mut state = EngineState::new(HashMapStore::new());
+        state.accounts.insert(
+            (1, Asset::default()),
+            AccountState {
+                available: Amount::from_scaled(available),
+                held: Amount::from_scaled(held),
+                locked: false,
+            },
+        );
+        state.store.insert(1, rec);
+        state
+    }
+
+    fn deposit_rec(amount: i64, disputed: bool) -> TxRecord {
+        let amount = Amount::from_scaled(amount);
+        let mut rec = TxRecord::new(1, Asset::default(), TxKind::Deposit, amount);
+        if disputed {
+            rec.disputed = amount;
+            rec.stage = DisputeStage::Disputed;
+        }
+        rec
+    }
+
+    #[test]
+    fn dispute_failing_half_way_changes_nothing() {
+        // available can be debited, but crediting held overflows
+        let state = state_with(10_000, i64::MAX - 5, deposit_rec(10_000, false));
+        let mut p = Processor::from_state(state).with_journal();
+
+        let out = p.apply_event(tx(TransactionType::Dispute, 1, 1, None));
+        assert_eq!(
+            out,
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::Overflow))
+        );
+
+        let acc = &p.state().accounts[&(1, Asset::default())];
+        assert_eq!(acc.available.as_i64(), 10_000);
+        assert_eq!(acc.held.as_i64(), i64::MAX - 5);
+        assert!(!p.state().store.get(1).unwrap().is_disputed());
+        assert!(p.journal().unwrap().entries().is_empty());
+    }
+
+    #[test]
+    fn resolve_failing_half_way_changes_nothing() {
+        // held can be released, but crediting available overflows
+        let state = state_with(i64::MAX - 5, 10_000, deposit_rec(10_000, true));
+        let mut p = Processor::from_state(state);
+
+        let out = p.apply_event(tx(TransactionType::Resolve, 1, 1, None));
+        assert_eq!(
+            out,
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::Overflow))
+        );
+
+        let acc = &p.state().accounts[&(1, Asset::default())];
+        assert_eq!(acc.available.as_i64(), i64::MAX - 5);
+        assert_eq!(acc.held.as_i64(), 10_000);
+        assert!(p.state().store.get(1).unwrap().is_disputed());
+    }
+
+    #[test]
+    fn chargeback_failing_half_way_does_not_lock() {
+        // held would underflow past i64::MIN
+        let state = state_with(0, i64::MIN + 5, deposit_rec(10_000, true));
+        let mut p = Processor::from_state(state);
+
+        let out = p.apply_event(tx(TransactionType::Chargeback, 1, 1, None));
+        assert_eq!(
+            out,
+            ApplyOutcome::Rejected(RejectReason::Ledger(LedgerError::Overflow))
+        );
+
+        let acc = &p.state().accounts[&(1, Asset::default())];
+        assert!(!acc.locked);
+        assert_eq!(acc.held.as_i64(), i64::MIN + 5);
+        assert!(p.state().store.get(1).unwrap().is_disputed());
+    }
+
+    #[test]
+    fn aborted_run_resumes_from_the_last_snapshot() {
+        use crate::engine::sqlite_store::SqliteStore;
+
+        let path = std::env::temp_dir().join(format!("tl_abort_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut day1 = Processor::new(SqliteStore::open(&path).unwrap());
+        day1.apply_event(tx(TransactionType::Deposit, 1, 1, Some("3.0")));
+        let mut snap = Vec::new();
+        day1.state().save(&mut snap).unwrap();
+        day1.flush_store().unwrap();
+        drop(day1);
+
+        // stops before writing a snapshot, so its store writes must not stick
+        let state = EngineState::load(snap.as_slice(), SqliteStore::open(&path).unwrap()).unwrap();
+        let mut day2 = Processor::from_state(state);
+        day2.apply_event(tx(TransactionType::Deposit, 1, 2, Some("1.0")));
+        drop(day2);
+
+        let state = EngineState::load(snap.as_slice(), SqliteStore::open(&path).unwrap()).unwrap();
+        let mut rerun = Processor::from_state(state);
+        let out = rerun.apply_event(tx(TransactionType::Deposit, 1, 2, Some("1.0")));
+        assert_eq!(out, ApplyOutcome::Applied);
+        assert_eq!(rerun.results()[0].available, amt("4.0"));
+
+        drop(rerun);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn metrics_are_a_fold_over_outcomes() {
+        let mut p = Processor::new(HashMapStore::new());
+        let events = vec![
+            tx(TransactionType::Deposit, 1, 1, Some("1.0")),
+            tx(TransactionType::Deposit, 1, 1, Some("1.0")),
+            tx(TransactionType::Dispute, 1, 7, None),
+            malformed(),
+        ];
+
+        let outcomes: Vec<_> = events.into_iter().map(|e| p.apply_event(e)).collect();
+        let folded: Metrics = outcomes.iter().collect();
+
+        assert_eq!(folded.duplicate_tx, p.metrics().duplicate_tx);
+        assert_eq!(folded.tx_not_found, p.metrics().tx_not_found);
+        assert_eq!(folded.malformed_rows, p.metrics().malformed_rows);
+        assert_eq!(folded.duplicate_tx, 1);
+    }
 }
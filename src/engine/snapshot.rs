@@ -0,0 +1,348 @@
+//! Versioned snapshot of `EngineState`, so a later run can pick up where this one stopped.
+//!
+//! The file is CSV with a tag in the first column:
+//!
+//! ```text
+//! snapshot,1,<inline|external>,<events>,<time>
+//! account,<client>,<available>,<held>,<locked>,<asset>
+//! tx,<tx>,<client>,<kind>,<amount>,<disputed>,<asset>,<charged_back>,<stage>,<seq>,<time>
+//! ```
+//!
+//! Times are empty when the input had no timestamps.
+//!
+//! With a durable `TxStore` the tx records already live on disk, so the header says
+//! `external` and only accounts are written. Such a snapshot can only be loaded back
+//! into a durable store.
+//!
+//! Amounts are stored as scaled integers so signed values (a disputed deposit can
+//! push `available` below zero) round-trip exactly.
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use crate::core::types::*;
+use crate::engine::state::{AccountState, DisputeStage, EngineState, TxKind, TxRecord};
+use crate::engine::store::TxStore;
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    MissingHeader,
+    UnsupportedVersion(u32),
+    ExternalTxs,
+    Corrupt { line: u64, msg: String },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot i/o error: {}", e),
+            SnapshotError::MissingHeader => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "unsupported snapshot version {} (expected {})",
+                    v, SNAPSHOT_VERSION
+                )
+            }
+            SnapshotError::ExternalTxs => write!(
+                f,
+                "snapshot keeps its tx records in a durable store, load it with that store"
+            ),
+            SnapshotError::Corrupt { line, msg } => {
+                write!(f, "corrupt snapshot at line {}: {}", line, msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(e: std::io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<csv::Error> for SnapshotError {
+    fn from(e: csv::Error) -> Self {
+        let line = e.position().map(|p| p.line()).unwrap_or_default();
+        match e.into_kind() {
+            csv::ErrorKind::Io(io) => SnapshotError::Io(io),
+            other => SnapshotError::Corrupt {
+                line,
+                msg: format!("{:?}", other),
+            },
+        }
+    }
+}
+
+impl<S: TxStore> EngineState<S> {
+    /// Writes accounts and every stored tx record.
+    pub fn save(&self, out: impl Write) -> Result<(), SnapshotError> {
+        let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(out);
+        let durable = self.store.is_durable();
+        wtr.write_record([
+            "snapshot",
+            &SNAPSHOT_VERSION.to_string(),
+            if durable { "external" } else { "inline" },
+            &self.events.to_string(),
+            &self.time.map(|t| t.to_string()).unwrap_or_default(),
+        ])?;
+
+        let mut keys: Vec<_> = self.accounts.keys().copied().collect();
+        keys.sort_unstable();
+        for key in keys {
+            let acc = &self.accounts[&key];
+            wtr.write_record(&[
+                "account".to_string(),
+                key.0.to_string(),
+                acc.available.as_i64().to_string(),
+                acc.held.as_i64().to_string(),
+                acc.locked.to_string(),
+                key.1.to_string(),
+            ])?;
+        }
+
+        let mut res = Ok(());
+        self.store.for_each(&mut |tx, rec| {
+            if res.is_ok() && !durable {
+                res = wtr.write_record(&[
+                    "tx".to_string(),
+                    tx.to_string(),
+                    rec.client.to_string(),
+                    rec.kind.as_str().to_string(),
+                    rec.amount.as_i64().to_string(),
+                    rec.disputed.as_i64().to_string(),
+                    rec.asset.to_string(),
+                    rec.charged_back.as_i64().to_string(),
+                    rec.stage.as_str().to_string(),
+                    rec.seq.to_string(),
+                    rec.time.map(|t| t.to_string()).unwrap_or_default(),
+                ]);
+            }
+        });
+        res?;
+
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Restores a snapshot written by `save`, filling `store` with its tx records.
+    /// For an `external` snapshot, `store` must be the durable store it was saved with.
+    pub fn load(input: impl Read, store: S) -> Result<Self, SnapshotError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(input);
+        let mut records = rdr.records();
+
+        let header = records.next().ok_or(SnapshotError::MissingHeader)??;
+        if header.get(0) != Some("snapshot") {
+            return Err(SnapshotError::MissingHeader);
+        }
+        let version: u32 = parse(&header, 1)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let external = match header.get(2) {
+            Some("inline") => false,
+            Some("external") => true,
+            other => return Err(corrupt(&header, format!("bad tx location {:?}", other))),
+        };
+        if external && !store.is_durable() {
+            return Err(SnapshotError::ExternalTxs);
+        }
+
+        let mut state = EngineState::new(store);
+        state.events = parse(&header, 3)?;
+        state.time = time(&header, 4)?;
+        for record in records {
+            let record = record?;
+            match record.get(0) {
+                Some("account") => {
+                    let client: ClientId = parse(&record, 1)?;
+                    state.accounts.insert(
+                        (client, asset(&record, 5)?),
+                        AccountState {
+                            available: Amount::from_scaled(parse(&record, 2)?),
+                            held: Amount::from_scaled(parse(&record, 3)?),
+                            locked: parse(&record, 4)?,
+                        },
+                    );
+                }
+                Some("tx") => {
+                    let kind = match record.get(3) {
+                        Some("deposit") => TxKind::Deposit,
+                        Some("withdrawal") => TxKind::Withdrawal,
+                        other => return Err(corrupt(&record, format!("bad tx kind {:?}", other))),
+                    };
+                    let stage = record.get(8).unwrap_or_default();
+                    let stage = DisputeStage::parse(stage).ok_or_else(|| {
+                        corrupt(&record, format!("bad dispute stage {:?}", stage))
+                    })?;
+                    let rec = TxRecord {
+                        client: parse(&record, 2)?,
+                        asset: asset(&record, 6)?,
+                        kind,
+                        amount: Amount::from_scaled(parse(&record, 4)?),
+                        disputed: Amount::from_scaled(parse(&record, 5)?),
+                        charged_back: Amount::from_scaled(parse(&record, 7)?),
+                        stage,
+                        seq: parse(&record, 9)?,
+                        time: time(&record, 10)?,
+                    };
+                    state.store.insert(parse(&record, 1)?, rec);
+                }
+                other => return Err(corrupt(&record, format!("unknown record tag {:?}", other))),
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+fn corrupt(record: &csv::StringRecord, msg: String) -> SnapshotError {
+    SnapshotError::Corrupt {
+        line: record.position().map(|p| p.line()).unwrap_or_default(),
+        msg,
+    }
+}
+
+fn asset(record: &csv::StringRecord, idx: usize) -> Result<Asset, SnapshotError> {
+    let value = record
+        .get(idx)
+        .ok_or_else(|| corrupt(record, format!("missing field {}", idx)))?;
+    Asset::parse(value).map_err(|e| corrupt(record, format!("field {}: {}", idx, e)))
+}
+
+// empty when unknown
+fn time(record: &csv::StringRecord, idx: usize) -> Result<Option<Timestamp>, SnapshotError> {
+    match record.get(idx) {
+        Some("") => Ok(None),
+        _ => parse(record, idx).map(Some),
+    }
+}
+
+fn parse<T: std::str::FromStr>(record: &csv::StringRecord, idx: usize) -> Result<T, SnapshotError> {
+    record
+        .get(idx)
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| corrupt(record, format!("bad or missing field {}", idx)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::store::HashMapStore;
+
+    fn usd() -> Asset {
+        Asset::parse("USD").unwrap()
+    }
+
+    fn sample() -> EngineState<HashMapStore> {
+        let mut state = EngineState::new(HashMapStore::new());
+        state.events = 9;
+        state.time = Some(1_700_000_060);
+        state.accounts.insert(
+            (1, usd()),
+            AccountState {
+                available: Amount::from_scaled(-5_000),
+                held: Amount::from_scaled(20_000),
+                locked: false,
+            },
+        );
+        state.accounts.insert(
+            (2, Asset::default()),
+            AccountState {
+                available: Amount::zero(),
+                held: Amount::zero(),
+                locked: true,
+            },
+        );
+        state.store.insert(
+            7,
+            TxRecord {
+                client: 1,
+                asset: usd(),
+                kind: TxKind::Deposit,
+                amount: Amount::from_scaled(30_000),
+                disputed: Amount::from_scaled(20_000),
+                charged_back: Amount::from_scaled(5_000),
+                stage: DisputeStage::Disputed,
+                seq: 3,
+                time: Some(1_700_000_000),
+            },
+        );
+        state
+    }
+
+    #[test]
+    fn round_trip_preserves_accounts_and_tx_records() {
+        let mut buf = Vec::new();
+        sample().save(&mut buf).unwrap();
+
+        let restored = EngineState::load(buf.as_slice(), HashMapStore::new()).unwrap();
+
+        let acc = &restored.accounts[&(1, usd())];
+        assert_eq!(acc.available.as_i64(), -5_000);
+        assert_eq!(acc.held.as_i64(), 20_000);
+        assert!(restored.accounts[&(2, Asset::default())].locked);
+
+        let rec = restored.store.get(7).unwrap();
+        assert_eq!(rec.client, 1);
+        assert_eq!(rec.asset, usd());
+        assert_eq!(rec.kind, TxKind::Deposit);
+        assert_eq!(rec.disputed.as_i64(), 20_000);
+        assert_eq!(rec.charged_back.as_i64(), 5_000);
+        assert_eq!(rec.stage, DisputeStage::Disputed);
+        assert_eq!(rec.seq, 3);
+        assert_eq!(rec.time, Some(1_700_000_000));
+        assert_eq!(restored.events, 9);
+        assert_eq!(restored.time, Some(1_700_000_060));
+    }
+
+    #[test]
+    fn durable_store_keeps_tx_records_out_of_the_snapshot() {
+        use crate::engine::sqlite_store::SqliteStore;
+
+        let mut state = EngineState::new(SqliteStore::open_in_memory().unwrap());
+        state.accounts.insert((1, usd()), AccountState::new());
+        state.store.insert(7, sample().store.get(7).unwrap());
+
+        let mut buf = Vec::new();
+        state.save(&mut buf).unwrap();
+        let text = String::from_utf8(buf.clone()).unwrap();
+        assert!(text.starts_with("snapshot,1,external,0,\n"));
+        assert!(!text.contains("\ntx,"));
+
+        let res = EngineState::load(buf.as_slice(), HashMapStore::new());
+        assert!(matches!(res, Err(SnapshotError::ExternalTxs)));
+
+        let restored = EngineState::load(buf.as_slice(), state.store).unwrap();
+        assert!(restored.store.get(7).unwrap().is_disputed());
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let res = EngineState::load("snapshot,99\n".as_bytes(), HashMapStore::new());
+        assert!(matches!(res, Err(SnapshotError::UnsupportedVersion(99))));
+
+        let res = EngineState::load("snapshot,2,inline,0,\n".as_bytes(), HashMapStore::new());
+        assert!(matches!(res, Err(SnapshotError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let res = EngineState::load("type,client,tx,amount\n".as_bytes(), HashMapStore::new());
+        assert!(matches!(res, Err(SnapshotError::MissingHeader)));
+
+        let res = EngineState::load(
+            "snapshot,1,inline,0,\naccount,x,0,0,false,\n".as_bytes(),
+            HashMapStore::new(),
+        );
+        assert!(matches!(res, Err(SnapshotError::Corrupt { line: 2, .. })));
+    }
+}
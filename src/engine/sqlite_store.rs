@@ -0,0 +1,347 @@
+use std::fmt;
+use std::path::Path;
+
+use rusqlite::types::Type;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::core::types::*;
+use crate::engine::state::{DisputeStage, TxKind, TxRecord};
+use crate::engine::store::TxStore;
+
+const SCHEMA_VERSION: i32 = 1;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    SchemaVersion(i32),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Sqlite(e) => write!(f, "sqlite tx store: {}", e),
+            StoreError::SchemaVersion(v) => write!(
+                f,
+                "sqlite tx store has schema version {} (expected {})",
+                v, SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+/// `TxStore` backed by an SQLite database file, for tx histories too large to keep in RAM
+/// and for keeping them across runs.
+///
+/// Everything written since the last `commit` (or `flush`) is one transaction, so the file
+/// only ever holds the state as of a commit. Commit together with the snapshot that describes
+/// the same state; dropping the store without committing rolls its writes back.
+/// `TxStore` methods can't return errors, so a failing database panics.
+pub struct SqliteStore {
+    conn: Connection,
+    open: bool,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        Self::init(conn)
+    }
+
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, StoreError> {
+        let version: i32 = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        match version {
+            0 => {
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS txs (
+                        tx           INTEGER PRIMARY KEY,
+                        client       INTEGER NOT NULL,
+                        kind         INTEGER NOT NULL,
+                        amount       INTEGER NOT NULL,
+                        disputed     INTEGER NOT NULL,
+                        asset        TEXT NOT NULL,
+                        charged_back INTEGER NOT NULL,
+                        stage        TEXT NOT NULL,
+                        seq          INTEGER NOT NULL,
+                        time         INTEGER
+                    );",
+                )?;
+                conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+            }
+            SCHEMA_VERSION => {}
+            other => return Err(StoreError::SchemaVersion(other)),
+        }
+
+        Ok(Self { conn, open: false })
+    }
+
+    /// Commits everything written since the last commit.
+    pub fn commit(&mut self) -> Result<(), StoreError> {
+        if self.open {
+            self.conn.execute_batch("COMMIT")?;
+            self.open = false;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, tx: TxId, rec: &TxRecord) -> Result<(), StoreError> {
+        self.begin()?;
+        self.conn
+            .prepare_cached(
+                "INSERT OR REPLACE INTO txs
+                     (tx, client, kind, amount, disputed, asset, charged_back, stage, seq, time)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?
+            .execute(params![
+                tx,
+                rec.client,
+                encode_kind(rec.kind),
+                rec.amount.as_i64(),
+                rec.disputed.as_i64(),
+                rec.asset.as_str(),
+                rec.charged_back.as_i64(),
+                rec.stage.as_str(),
+                rec.seq,
+                rec.time
+            ])?;
+        Ok(())
+    }
+
+    fn delete(&mut self, tx: TxId) -> Result<(), StoreError> {
+        self.begin()?;
+        self.conn
+            .prepare_cached("DELETE FROM txs WHERE tx = ?1")?
+            .execute(params![tx])?;
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<(), StoreError> {
+        if !self.open {
+            self.conn.execute_batch("BEGIN")?;
+            self.open = true;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        // closing the connection would roll back as well; a failure changes nothing on disk
+        if self.open {
+            let _ = self.conn.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+fn encode_kind(kind: TxKind) -> u8 {
+    match kind {
+        TxKind::Deposit => 0,
+        TxKind::Withdrawal => 1,
+    }
+}
+
+fn record_from_row(row: &rusqlite::Row<'_>, offset: usize) -> rusqlite::Result<TxRecord> {
+    let kind = match row.get::<_, u8>(offset + 1)? {
+        0 => TxKind::Deposit,
+        1 => TxKind::Withdrawal,
+        other => {
+            return Err(rusqlite::Error::FromSqlConversionFailure(
+                offset + 1,
+                Type::Integer,
+                format!("unknown tx kind {}", other).into(),
+            ));
+        }
+    };
+    let asset: String = row.get(offset + 4)?;
+    let asset = Asset::parse(&asset).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(offset + 4, Type::Text, Box::new(e))
+    })?;
+    let stage: String = row.get(offset + 6)?;
+    let stage = DisputeStage::parse(&stage).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            offset + 6,
+            Type::Text,
+            format!("unknown dispute stage {:?}", stage).into(),
+        )
+    })?;
+    Ok(TxRecord {
+        client: row.get(offset)?,
+        asset,
+        kind,
+        amount: Amount::from_scaled(row.get(offset + 2)?),
+        disputed: Amount::from_scaled(row.get(offset + 3)?),
+        charged_back: Amount::from_scaled(row.get(offset + 5)?),
+        stage,
+        seq: row.get(offset + 7)?,
+        time: row.get(offset + 8)?,
+    })
+}
+
+fn fatal<T>(res: Result<T, impl Into<StoreError>>) -> T {
+    res.unwrap_or_else(|e| panic!("{}", e.into()))
+}
+
+impl TxStore for SqliteStore {
+    fn get(&self, tx: TxId) -> Option<TxRecord> {
+        let res = self
+            .conn
+            .prepare_cached(
+                "SELECT client, kind, amount, disputed, asset, charged_back, stage, seq, time FROM txs WHERE tx = ?1",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row(params![tx], |row| record_from_row(row, 0))
+                    .optional()
+            });
+        fatal(res)
+    }
+
+    fn insert(&mut self, tx: TxId, rec: TxRecord) {
+        fatal(self.write(tx, &rec));
+    }
+
+    fn remove(&mut self, tx: TxId) {
+        fatal(self.delete(tx));
+    }
+
+    fn contains(&self, tx: TxId) -> bool {
+        let res = self
+            .conn
+            .prepare_cached("SELECT 1 FROM txs WHERE tx = ?1")
+            .and_then(|mut stmt| stmt.exists(params![tx]));
+        fatal(res)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(TxId, &TxRecord)) {
+        let res = self
+            .conn
+            .prepare(
+                "SELECT tx, client, kind, amount, disputed, asset, charged_back, stage, seq, time FROM txs",
+            )
+            .and_then(|mut stmt| {
+                let mut rows = stmt.query([])?;
+                while let Some(row) = rows.next()? {
+                    f(row.get(0)?, &record_from_row(row, 1)?);
+                }
+                Ok(())
+            });
+        fatal(res)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.commit().map_err(std::io::Error::other)
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(client: ClientId, amount: i64, disputed: i64) -> TxRecord {
+        let mut rec = TxRecord {
+            disputed: Amount::from_scaled(disputed),
+            charged_back: Amount::from_scaled(amount - disputed),
+            ..TxRecord::new(
+                client,
+                Asset::parse("EUR").unwrap(),
+                TxKind::Deposit,
+                Amount::from_scaled(amount),
+            )
+        };
+        rec.stage = rec.first_round_stage();
+        rec
+    }
+
+    #[test]
+    fn insert_get_replace() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        assert!(!store.contains(1));
+        assert_eq!(store.get(1), None);
+
+        store.insert(1, rec(3, 15_000, 0));
+        assert!(store.contains(1));
+        assert_eq!(store.get(1), Some(rec(3, 15_000, 0)));
+
+        store.insert(1, rec(3, 15_000, 5_000));
+        assert_eq!(store.get(1), Some(rec(3, 15_000, 5_000)));
+
+        let escalated = TxRecord {
+            stage: DisputeStage::Arbitration,
+            seq: 42,
+            time: Some(1_700_000_000),
+            ..rec(3, 15_000, 0)
+        };
+        store.insert(1, escalated);
+        assert_eq!(store.get(1), Some(escalated));
+        store.insert(1, rec(3, 15_000, 5_000));
+
+        store.insert(2, rec(3, 15_000, 0));
+        store.remove(2);
+        store.remove(3);
+        assert!(!store.contains(2));
+
+        let mut seen = Vec::new();
+        store.for_each(&mut |tx, r| seen.push((tx, *r)));
+        assert_eq!(seen, vec![(1, rec(3, 15_000, 5_000))]);
+    }
+
+    #[test]
+    fn unknown_kinds_are_an_error() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        store.insert(1, rec(3, 15_000, 0));
+        store.commit().unwrap();
+        store
+            .conn
+            .execute("UPDATE txs SET kind = 7 WHERE tx = 1", [])
+            .unwrap();
+
+        let res = store.conn.query_row(
+            "SELECT client, kind, amount, disputed, asset, charged_back, stage, seq, time FROM txs WHERE tx = 1",
+            [],
+            |row| record_from_row(row, 0),
+        );
+        assert!(matches!(
+            res,
+            Err(rusqlite::Error::FromSqlConversionFailure(1, Type::Integer, e)) if e.to_string() == "unknown tx kind 7"
+        ));
+    }
+
+    #[test]
+    fn committed_records_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("tl_store_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let mut store = SqliteStore::open(&path).unwrap();
+            store.insert(7, rec(1, 10_000, 0));
+            store.insert(8, rec(2, 20_000, 15_000));
+            store.commit().unwrap();
+            store.insert(9, rec(3, 30_000, 0));
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.get(7), Some(rec(1, 10_000, 0)));
+        assert_eq!(store.get(8), Some(rec(2, 20_000, 15_000)));
+        assert!(!store.contains(9));
+        assert!(store.is_durable());
+
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+}
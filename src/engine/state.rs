@@ -2,7 +2,7 @@ use crate::core::types::*;
 use crate::engine::store::TxStore;
 use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct AccountState {
     pub available: Amount,
     pub held: Amount,
@@ -11,11 +11,7 @@ pub struct AccountState {
 
 impl AccountState {
     pub fn new() -> Self {
-        Self {
-            available: Amount::zero(),
-            held: Amount::zero(),
-            locked: false,
-        }
+        Self::default()
     }
 
     pub fn total(&self) -> Amount {
@@ -29,18 +25,148 @@ pub enum TxKind {
     Withdrawal,
 }
 
-#[derive(Debug, Clone)]
+impl TxKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxKind::Deposit => "deposit",
+            TxKind::Withdrawal => "withdrawal",
+        }
+    }
+}
+
+/// Where a tx is in the dispute lifecycle.
+///
+/// The first round is dispute, then resolve or chargeback, and may be partial. After a
+/// chargeback the merchant can re-present, the cardholder can charge back a second time
+/// (pre-arbitration), and that can go to arbitration; these later stages cover the whole
+/// charged-back amount. `Upheld` and `Reversed` are final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisputeStage {
+    #[default]
+    Undisputed,
+    /// Some of the amount is under dispute and held.
+    Disputed,
+    /// Some of the amount was charged back and nothing is under dispute now.
+    ChargedBack,
+    /// The merchant re-presented; the charged-back amount is held again.
+    Represented,
+    /// Charged back a second time after representment.
+    PreArbitration,
+    /// Escalated to the card network, waiting for a ruling (resolve or chargeback).
+    Arbitration,
+    /// Final: the tx stands after all.
+    Upheld,
+    /// Final: the charged-back amount stays reversed.
+    Reversed,
+}
+
+impl DisputeStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisputeStage::Undisputed => "undisputed",
+            DisputeStage::Disputed => "disputed",
+            DisputeStage::ChargedBack => "charged_back",
+            DisputeStage::Represented => "represented",
+            DisputeStage::PreArbitration => "prearbitration",
+            DisputeStage::Arbitration => "arbitration",
+            DisputeStage::Upheld => "upheld",
+            DisputeStage::Reversed => "reversed",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [
+            DisputeStage::Undisputed,
+            DisputeStage::Disputed,
+            DisputeStage::ChargedBack,
+            DisputeStage::Represented,
+            DisputeStage::PreArbitration,
+            DisputeStage::Arbitration,
+            DisputeStage::Upheld,
+            DisputeStage::Reversed,
+        ]
+        .into_iter()
+        .find(|stage| stage.as_str() == s)
+    }
+
+    /// Past the first round: representment or later, but not final.
+    pub fn is_escalated(&self) -> bool {
+        matches!(
+            self,
+            DisputeStage::Represented | DisputeStage::PreArbitration | DisputeStage::Arbitration
+        )
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(self, DisputeStage::Upheld | DisputeStage::Reversed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TxRecord {
     pub client: ClientId,
+    pub asset: Asset,
     pub amount: Amount,
     pub kind: TxKind,
-    pub disputed: bool,
+    /// The part currently under dispute. Disputes may cover less than the full amount.
+    pub disputed: Amount,
+    /// The part already charged back; it can't be disputed again.
+    pub charged_back: Amount,
+    pub stage: DisputeStage,
+    /// The event it was applied at (see `EngineState::events`); dispute windows count from here.
+    pub seq: u64,
+    /// Its timestamp, if the input had one.
+    pub time: Option<Timestamp>,
+}
+
+impl TxRecord {
+    pub fn new(client: ClientId, asset: Asset, kind: TxKind, amount: Amount) -> Self {
+        Self {
+            client,
+            asset,
+            amount,
+            kind,
+            disputed: Amount::zero(),
+            charged_back: Amount::zero(),
+            stage: DisputeStage::Undisputed,
+            seq: 0,
+            time: None,
+        }
+    }
+
+    pub fn is_disputed(&self) -> bool {
+        self.stage == DisputeStage::Disputed
+    }
+
+    /// The first-round stage the disputed and charged-back amounts add up to.
+    pub fn first_round_stage(&self) -> DisputeStage {
+        if self.disputed > Amount::zero() {
+            DisputeStage::Disputed
+        } else if self.charged_back > Amount::zero() {
+            DisputeStage::ChargedBack
+        } else {
+            DisputeStage::Undisputed
+        }
+    }
+
+    /// What's left to dispute: neither under dispute now nor charged back.
+    pub fn undisputed(&self) -> Amount {
+        self.amount - self.disputed - self.charged_back
+    }
 }
 
+/// Balances are kept per client and asset; each pair is its own account, locking included.
+pub type AccountKey = (ClientId, Asset);
+
 #[derive(Debug)]
 pub struct EngineState<S: TxStore> {
-    pub accounts: HashMap<ClientId, AccountState>,
+    pub accounts: HashMap<AccountKey, AccountState>,
     pub store: S,
+    /// Events seen so far, rejected ones included. Carried across snapshots so dispute
+    /// windows keep counting.
+    pub events: u64,
+    /// The latest timestamp seen, for dispute windows in seconds.
+    pub time: Option<Timestamp>,
 }
 
 impl<S: TxStore> EngineState<S> {
@@ -48,16 +174,16 @@ impl<S: TxStore> EngineState<S> {
         Self {
             accounts: HashMap::new(),
             store,
+            events: 0,
+            time: None,
         }
     }
 
-    pub fn account_mut(&mut self, client: ClientId) -> &mut AccountState {
-        self.accounts
-            .entry(client)
-            .or_insert_with(AccountState::new)
+    pub fn account_mut(&mut self, client: ClientId, asset: Asset) -> &mut AccountState {
+        self.accounts.entry((client, asset)).or_default()
     }
 
-    pub fn accounts_iter(&self) -> impl Iterator<Item = (&u16, &AccountState)> {
+    pub fn accounts_iter(&self) -> impl Iterator<Item = (&AccountKey, &AccountState)> {
         self.accounts.iter()
     }
 }
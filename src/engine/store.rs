@@ -2,11 +2,25 @@ use crate::core::types::*;
 use crate::engine::state::TxRecord;
 use std::collections::HashMap;
 
+/// Records are handed out by value so backends don't have to keep them in memory.
+/// To change a record, `get` it, modify the copy and `insert` it back.
 pub trait TxStore {
-    fn get(&self, tx: TxId) -> Option<&TxRecord>;
-    fn get_mut(&mut self, tx: TxId) -> Option<&mut TxRecord>;
+    fn get(&self, tx: TxId) -> Option<TxRecord>;
+    /// Inserts or replaces the record for `tx`.
     fn insert(&mut self, tx: TxId, rec: TxRecord);
     fn contains(&self, tx: TxId) -> bool;
+    /// Forgets the record for `tx`, if there is one.
+    fn remove(&mut self, tx: TxId);
+    /// Visits every stored record, in no particular order.
+    fn for_each(&self, f: &mut dyn FnMut(TxId, &TxRecord));
+    /// Makes buffered writes durable. A no-op for in-memory stores.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+    /// True if records outlive the process, so snapshots don't need to copy them.
+    fn is_durable(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Default)]
@@ -23,11 +37,8 @@ impl HashMapStore {
 }
 
 impl TxStore for HashMapStore {
-    fn get(&self, tx: TxId) -> Option<&TxRecord> {
-        self.inner.get(&tx)
-    }
-    fn get_mut(&mut self, tx: TxId) -> Option<&mut TxRecord> {
-        self.inner.get_mut(&tx)
+    fn get(&self, tx: TxId) -> Option<TxRecord> {
+        self.inner.get(&tx).copied()
     }
     fn insert(&mut self, tx: TxId, rec: TxRecord) {
         self.inner.insert(tx, rec);
@@ -35,4 +46,12 @@ impl TxStore for HashMapStore {
     fn contains(&self, tx: TxId) -> bool {
         self.inner.contains_key(&tx)
     }
+    fn remove(&mut self, tx: TxId) {
+        self.inner.remove(&tx);
+    }
+    fn for_each(&self, f: &mut dyn FnMut(TxId, &TxRecord)) {
+        for (&tx, rec) in &self.inner {
+            f(tx, rec);
+        }
+    }
 }
@@ -0,0 +1,135 @@
+//! Feeding a `Processor` from an async stream of events.
+
+use std::pin::pin;
+
+use futures_util::{Stream, StreamExt};
+
+use crate::engine::outcome::{ApplyOutcome, StrictViolation};
+use crate::engine::processor::Processor;
+use crate::engine::store::TxStore;
+use crate::io::IngestEvent;
+
+impl<S: TxStore> Processor<S> {
+    /// Applies events as `events` yields them, like calling `try_apply_event` in a loop,
+    /// and passes each one with its outcome to `on_outcome`.
+    ///
+    /// The next event is only pulled once the last one is applied, so a slow engine holds
+    /// back a channel's senders or a socket's peer rather than buffering. Stops at the
+    /// end of the stream or at a strict violation, which is returned; an error from
+    /// `on_outcome` stops it too. Journal entries still pile up until drained.
+    pub async fn apply_stream<E>(
+        &mut self,
+        events: impl Stream<Item = IngestEvent>,
+        mut on_outcome: impl FnMut(
+            &IngestEvent,
+            &Result<ApplyOutcome, StrictViolation>,
+        ) -> Result<(), E>,
+    ) -> Result<Option<StrictViolation>, E> {
+        let mut events = pin!(events);
+        while let Some(event) = events.next().await {
+            let source = event.clone();
+            let res = self.try_apply_event(event);
+            on_outcome(&source, &res)?;
+            if let Err(violation) = res {
+                return Ok(Some(violation));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::*;
+    use crate::engine::outcome::RejectReason;
+    use crate::engine::store::HashMapStore;
+    use crate::io::formats::csv::CsvIngester;
+    use crate::io::{AsyncIngester, stream};
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn collect(
+        outcomes: &mut Vec<ApplyOutcome>,
+    ) -> impl FnMut(&IngestEvent, &Result<ApplyOutcome, StrictViolation>) -> Result<(), ()> + '_
+    {
+        |_, res| {
+            outcomes.push(*res.as_ref().unwrap());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn csv_over_a_local_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            conn.write_all(b"type,client,tx,amount\ndeposit,1,1,2.0\n")
+                .await
+                .unwrap();
+            conn.write_all(b"withdrawal,1,2,5.0\nwithdrawal,1,3,0.5\n")
+                .await
+                .unwrap();
+        });
+
+        let (conn, _) = listener.accept().await.unwrap();
+        let events = CsvIngester::new().ingest_stream(Box::new(conn));
+
+        let mut p = Processor::new(HashMapStore::new());
+        let mut outcomes = Vec::new();
+        p.apply_stream(events, collect(&mut outcomes))
+            .await
+            .unwrap();
+        client.await.unwrap();
+
+        assert_eq!(outcomes.len(), 3);
+        assert_eq!(
+            outcomes[1],
+            ApplyOutcome::Rejected(RejectReason::Ledger(
+                crate::core::errors::LedgerError::InsufficientFunds
+            ))
+        );
+        assert_eq!(
+            p.results()[0].available,
+            Amount::from_str_4dp("1.5").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_full_channel_holds_back_senders() {
+        let deposit = |tx| Transaction {
+            kind: TransactionType::Deposit,
+            client: 1,
+            tx,
+            amount: Some(Amount::from_str_4dp("1.0").unwrap()),
+            asset: Asset::default(),
+            timestamp: None,
+        };
+
+        let (sender, events) = stream::channel(2);
+        sender.try_send(deposit(1)).unwrap();
+        sender.try_send(deposit(2)).unwrap();
+        assert!(sender.try_send(deposit(3)).is_err());
+
+        let producer = tokio::spawn(async move {
+            for tx in 3..=10 {
+                sender.send(deposit(tx)).await.unwrap();
+            }
+        });
+
+        let mut p = Processor::new(HashMapStore::new());
+        let mut outcomes = Vec::new();
+        p.apply_stream(events, collect(&mut outcomes))
+            .await
+            .unwrap();
+        producer.await.unwrap();
+
+        assert_eq!(outcomes, vec![ApplyOutcome::Applied; 10]);
+        assert_eq!(
+            p.results()[0].available,
+            Amount::from_str_4dp("10").unwrap()
+        );
+    }
+}
@@ -0,0 +1,456 @@
+//! Arrow IPC and Parquet output, for loading results into DuckDB, pandas and the like.
+//!
+//! Amounts are `Decimal128(38, scale)` columns rather than floats. The scale is 4, or the
+//! most places any asset was given with `--precision`, so every asset fits in one column
+//! and is rescaled exactly. The default asset is a null `asset`.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Decimal128Array, RecordBatch, StringArray, UInt16Array, UInt32Array,
+    UInt64Array,
+};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+
+use crate::core::types::{AccountRow, Amount, Asset, Precisions};
+use crate::engine::journal::JournalEntry;
+use crate::io::{Emitter, JournalSink};
+
+/// Journal entries buffered per record batch.
+const BATCH_ROWS: usize = 8192;
+
+/// The container the record batches are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrowFormat {
+    /// The Arrow IPC file format, also known as Feather v2.
+    Ipc,
+    Parquet,
+}
+
+fn other(e: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    std::io::Error::other(e)
+}
+
+/// Turns amounts of any asset into one decimal column.
+#[derive(Debug, Clone)]
+struct Decimals {
+    precisions: Precisions,
+    scale: u32,
+}
+
+impl Decimals {
+    fn new(precisions: &Precisions) -> Self {
+        let scale = precisions
+            .iter()
+            .map(|(_, decimals)| decimals)
+            .fold(Amount::DECIMALS, u32::max);
+        Self {
+            precisions: precisions.clone(),
+            scale,
+        }
+    }
+
+    fn data_type(&self) -> DataType {
+        // at most Amount::MAX_DECIMALS, and an i64 times 10^18 still fits 38 digits
+        DataType::Decimal128(38, self.scale as i8)
+    }
+
+    fn value(&self, amount: Amount, asset: Asset) -> i128 {
+        let shift = self.scale - self.precisions.decimals(asset);
+        amount.as_i64() as i128 * 10i128.pow(shift)
+    }
+
+    fn column(&self, values: impl Iterator<Item = (Option<Amount>, Asset)>) -> ArrayRef {
+        let values: Decimal128Array = values
+            .map(|(amount, asset)| amount.map(|a| self.value(a, asset)))
+            .collect();
+        Arc::new(values.with_data_type(self.data_type()))
+    }
+}
+
+fn assets<'a>(assets: impl Iterator<Item = &'a Asset>) -> ArrayRef {
+    let values: StringArray = assets
+        .map(|a| (!a.is_default()).then(|| a.as_str()))
+        .collect();
+    Arc::new(values)
+}
+
+enum Writer<W: Write + Send> {
+    Ipc(FileWriter<W>),
+    Parquet(Box<ArrowWriter<W>>),
+}
+
+impl<W: Write + Send> Writer<W> {
+    fn new(format: ArrowFormat, out: W, schema: SchemaRef) -> std::io::Result<Self> {
+        Ok(match format {
+            ArrowFormat::Ipc => Writer::Ipc(FileWriter::try_new(out, &schema).map_err(other)?),
+            ArrowFormat::Parquet => Writer::Parquet(Box::new(
+                ArrowWriter::try_new(out, schema, None).map_err(other)?,
+            )),
+        })
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> std::io::Result<()> {
+        match self {
+            Writer::Ipc(w) => w.write(batch).map_err(other),
+            Writer::Parquet(w) => w.write(batch).map_err(other),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Writer::Ipc(w) => w.flush().map_err(other),
+            Writer::Parquet(w) => w.flush().map_err(other),
+        }
+    }
+
+    /// Writes the footer; nothing can be written after it.
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            Writer::Ipc(w) => {
+                w.finish().map_err(other)?;
+                w.flush().map_err(other)
+            }
+            Writer::Parquet(w) => w.finish().map(|_| ()).map_err(other),
+        }
+    }
+}
+
+/// Writes account rows as one Arrow IPC or Parquet file:
+/// `client,asset,available,held,total,locked`, in the same order as `CsvEmitter`.
+#[derive(Debug, Clone)]
+pub struct ArrowEmitter {
+    format: ArrowFormat,
+    precisions: Precisions,
+}
+
+impl ArrowEmitter {
+    pub fn new(format: ArrowFormat) -> Self {
+        Self {
+            format,
+            precisions: Precisions::default(),
+        }
+    }
+
+    /// Decides the decimal scale, see the module docs.
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+
+    pub fn schema(&self) -> SchemaRef {
+        let amount = Decimals::new(&self.precisions).data_type();
+        Arc::new(Schema::new(vec![
+            Field::new("client", DataType::UInt16, false),
+            Field::new("asset", DataType::Utf8, true),
+            Field::new("available", amount.clone(), false),
+            Field::new("held", amount.clone(), false),
+            Field::new("total", amount, false),
+            Field::new("locked", DataType::Boolean, false),
+        ]))
+    }
+}
+
+impl Emitter for ArrowEmitter {
+    fn emit(&self, rows: &[AccountRow], out: &mut dyn Write) -> std::io::Result<()> {
+        let decimals = Decimals::new(&self.precisions);
+        let amounts = |f: fn(&AccountRow) -> Amount| {
+            decimals.column(rows.iter().map(|r| (Some(f(r)), r.asset)))
+        };
+        let batch = RecordBatch::try_new(
+            self.schema(),
+            vec![
+                Arc::new(rows.iter().map(|r| r.client).collect::<UInt16Array>()),
+                assets(rows.iter().map(|r| &r.asset)),
+                amounts(|r| r.available),
+                amounts(|r| r.held),
+                amounts(|r| r.total),
+                Arc::new(
+                    rows.iter()
+                        .map(|r| Some(r.locked))
+                        .collect::<BooleanArray>(),
+                ),
+            ],
+        )
+        .map_err(other)?;
+
+        // the parquet writer wants a Send destination, so the file is built in memory
+        let mut buf = Vec::new();
+        let mut writer = Writer::new(self.format, &mut buf, self.schema())?;
+        writer.write(&batch)?;
+        writer.finish()?;
+        drop(writer);
+        out.write_all(&buf)?;
+        out.flush()
+    }
+}
+
+/// Writes the journal as Arrow IPC or Parquet, with the same columns as `CsvJournalWriter`.
+/// Entries go out in batches; `finish` writes the last one and the footer, which ends the
+/// file, so a journal that isn't finished can't be read.
+pub struct ArrowJournalWriter<W: Write + Send> {
+    writer: Writer<W>,
+    schema: SchemaRef,
+    decimals: Decimals,
+    pending: Vec<JournalEntry>,
+    finished: bool,
+}
+
+impl<W: Write + Send> ArrowJournalWriter<W> {
+    /// `precisions` decides the decimal scale, see the module docs.
+    pub fn new(out: W, format: ArrowFormat, precisions: &Precisions) -> std::io::Result<Self> {
+        let decimals = Decimals::new(precisions);
+        let amount = decimals.data_type();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("seq", DataType::UInt64, false),
+            Field::new("line", DataType::UInt64, false),
+            Field::new("type", DataType::Utf8, false),
+            Field::new("client", DataType::UInt16, false),
+            Field::new("asset", DataType::Utf8, true),
+            Field::new("tx", DataType::UInt32, false),
+            Field::new("amount", amount.clone(), true),
+            Field::new("available_before", amount.clone(), false),
+            Field::new("held_before", amount.clone(), false),
+            Field::new("locked_before", DataType::Boolean, false),
+            Field::new("available_after", amount.clone(), false),
+            Field::new("held_after", amount, false),
+            Field::new("locked_after", DataType::Boolean, false),
+        ]));
+        Ok(Self {
+            writer: Writer::new(format, out, schema.clone())?,
+            schema,
+            decimals,
+            pending: Vec::with_capacity(BATCH_ROWS),
+            finished: false,
+        })
+    }
+
+    fn write_pending(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let entries = &self.pending;
+        let amounts = |f: fn(&JournalEntry) -> Option<Amount>| {
+            self.decimals
+                .column(entries.iter().map(|e| (f(e), e.asset)))
+        };
+        let flags = |f: fn(&JournalEntry) -> bool| -> ArrayRef {
+            Arc::new(entries.iter().map(|e| Some(f(e))).collect::<BooleanArray>())
+        };
+
+        let batch = RecordBatch::try_new(
+            self.schema.clone(),
+            vec![
+                Arc::new(entries.iter().map(|e| e.seq).collect::<UInt64Array>()),
+                Arc::new(entries.iter().map(|e| e.line).collect::<UInt64Array>()),
+                Arc::new(
+                    entries
+                        .iter()
+                        .map(|e| Some(e.kind.as_str()))
+                        .collect::<StringArray>(),
+                ),
+                Arc::new(entries.iter().map(|e| e.client).collect::<UInt16Array>()),
+                assets(entries.iter().map(|e| &e.asset)),
+                Arc::new(entries.iter().map(|e| e.tx).collect::<UInt32Array>()),
+                amounts(|e| e.amount),
+                amounts(|e| Some(e.before.available)),
+                amounts(|e| Some(e.before.held)),
+                flags(|e| e.before.locked),
+                amounts(|e| Some(e.after.available)),
+                amounts(|e| Some(e.after.held)),
+                flags(|e| e.after.locked),
+            ],
+        )
+        .map_err(other)?;
+
+        self.writer.write(&batch)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> JournalSink for ArrowJournalWriter<W> {
+    fn append(&mut self, entry: &JournalEntry) -> std::io::Result<()> {
+        if self.finished {
+            return Err(std::io::Error::other("journal already finished"));
+        }
+        self.pending.push(entry.clone());
+        if self.pending.len() >= BATCH_ROWS {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_pending()?;
+        self.writer.flush()
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_pending()?;
+        self.finished = true;
+        self.writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::TransactionType;
+    use crate::engine::journal::Balances;
+    use arrow_array::Array;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::Decimal128Type;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn read(format: ArrowFormat, data: Vec<u8>) -> Vec<RecordBatch> {
+        match format {
+            ArrowFormat::Ipc => {
+                arrow_ipc::reader::FileReader::try_new(std::io::Cursor::new(data), None)
+                    .unwrap()
+                    .map(Result::unwrap)
+                    .collect()
+            }
+            ArrowFormat::Parquet => {
+                // the reader wants a file, or bytes::Bytes
+                let path = std::env::temp_dir().join(format!(
+                    "tl-arrow-{}-{}.parquet",
+                    std::process::id(),
+                    data.len()
+                ));
+                std::fs::write(&path, data).unwrap();
+                let batches =
+                    ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap())
+                        .unwrap()
+                        .build()
+                        .unwrap()
+                        .map(Result::unwrap)
+                        .collect();
+                std::fs::remove_file(&path).unwrap();
+                batches
+            }
+        }
+    }
+
+    fn decimals(batch: &RecordBatch, column: &str) -> Vec<Option<i128>> {
+        batch
+            .column_by_name(column)
+            .unwrap()
+            .as_primitive::<Decimal128Type>()
+            .iter()
+            .collect()
+    }
+
+    #[test]
+    fn accounts_keep_exact_decimals() {
+        let rows = [
+            AccountRow {
+                client: 1,
+                asset: Asset::default(),
+                available: Amount::from_scaled(15_000),
+                held: Amount::from_scaled(-1),
+                total: Amount::from_scaled(14_999),
+                locked: false,
+            },
+            AccountRow {
+                client: 2,
+                asset: Asset::parse("JPY").unwrap(),
+                available: Amount::from_scaled(500),
+                held: Amount::default(),
+                total: Amount::from_scaled(500),
+                locked: true,
+            },
+        ];
+        let mut precisions = Precisions::new();
+        precisions.insert(Asset::parse("JPY").unwrap(), 0).unwrap();
+
+        for format in [ArrowFormat::Ipc, ArrowFormat::Parquet] {
+            let emitter = ArrowEmitter::new(format).with_precisions(precisions.clone());
+            let mut out = Vec::new();
+            emitter.emit(&rows, &mut out).unwrap();
+
+            let batches = read(format, out);
+            assert_eq!(batches.len(), 1);
+            let batch = &batches[0];
+            assert_eq!(batch.schema(), emitter.schema());
+            assert_eq!(
+                batch.schema().field_with_name("total").unwrap().data_type(),
+                &DataType::Decimal128(38, 4)
+            );
+            assert_eq!(
+                decimals(batch, "available"),
+                [Some(15_000), Some(5_000_000)]
+            );
+            assert_eq!(decimals(batch, "held"), [Some(-1), Some(0)]);
+
+            let asset = batch.column_by_name("asset").unwrap().as_string::<i32>();
+            assert!(asset.is_null(0));
+            assert_eq!(asset.value(1), "JPY");
+            let locked = batch.column_by_name("locked").unwrap().as_boolean();
+            assert!(!locked.value(0) && locked.value(1));
+        }
+    }
+
+    #[test]
+    fn journal_is_written_in_batches() {
+        let mut precisions = Precisions::new();
+        precisions.insert(Asset::parse("BTC").unwrap(), 8).unwrap();
+        let entry = |seq: u64| JournalEntry {
+            seq,
+            line: seq + 1,
+            kind: TransactionType::Deposit,
+            client: 1,
+            asset: Asset::default(),
+            tx: seq as u32,
+            amount: Some(Amount::from_scaled(10_000)),
+            before: Balances {
+                available: Amount::from_scaled((seq as i64 - 1) * 10_000),
+                held: Amount::default(),
+                locked: false,
+            },
+            after: Balances {
+                available: Amount::from_scaled(seq as i64 * 10_000),
+                held: Amount::default(),
+                locked: false,
+            },
+        };
+
+        for format in [ArrowFormat::Ipc, ArrowFormat::Parquet] {
+            let mut out = Vec::new();
+            let mut writer = ArrowJournalWriter::new(&mut out, format, &precisions).unwrap();
+            let count = BATCH_ROWS as u64 + 2;
+            for seq in 1..=count {
+                writer.append(&entry(seq)).unwrap();
+            }
+            writer.flush().unwrap();
+            writer.finish().unwrap();
+            writer.finish().unwrap();
+            assert!(writer.append(&entry(count + 1)).is_err());
+            drop(writer);
+
+            let batches = read(format, out);
+            let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+            assert_eq!(rows as u64, count);
+
+            let last = batches.last().unwrap();
+            let n = last.num_rows();
+            // scale 8 for BTC, so default asset amounts are shifted by 4 places
+            assert_eq!(decimals(last, "amount")[n - 1], Some(100_000_000));
+            assert_eq!(
+                decimals(last, "available_after")[n - 1],
+                Some(count as i128 * 100_000_000)
+            );
+            let kind = last.column_by_name("type").unwrap().as_string::<i32>();
+            assert_eq!(kind.value(0), "deposit");
+        }
+    }
+}
@@ -0,0 +1,619 @@
+//! Fixed-width binary transactions and accounts, for feeds that were validated upstream
+//! and shouldn't pay for text parsing.
+//!
+//! A file starts with a header, then holds fixed-width records. Integers are little-endian.
+//!
+//! ```text
+//! magic    4 bytes   "TXLB"
+//! version  u16       1
+//! content  u8        b'T' for transactions, b'A' for accounts
+//! count    u8        number of precision entries that follow
+//! entries  count x   asset [u8; 8], places u8
+//! ```
+//!
+//! A transaction record is 32 bytes:
+//!
+//! ```text
+//! 0   kind       u8        0 deposit, 1 withdrawal, 2 dispute, 3 resolve, 4 chargeback,
+//!                          5 representment, 6 prearbitration, 7 arbitration, 8 lock, 9 unlock
+//! 1   flags      u8        bit 0: amount present, bit 1: timestamp present
+//! 2   client     u16
+//! 4   tx         u32
+//! 8   amount     i64
+//! 16  asset      [u8; 8]
+//! 24  timestamp  u64
+//! ```
+//!
+//! and an account record 40 bytes: client u16, asset [u8; 8], available, held and total
+//! as i64, locked u8, then 5 zero bytes.
+//!
+//! Amounts are scaled integers in units of their asset's precision, so the header lists the
+//! precisions they were written with. Reading them with different ones is an error rather
+//! than a silent rescale. Assets are upper-case codes padded with zero bytes; all zeros is
+//! the default asset.
+
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use crate::core::errors::CoreError;
+use crate::core::types::{AccountRow, Amount, Asset, Precisions, Transaction, TransactionType};
+use crate::io::formats::strict_cut;
+use crate::io::{Emitter, IngestEvent, Ingester, SourcePos, TransactionSink};
+
+pub const BINARY_VERSION: u16 = 1;
+pub const TX_RECORD_LEN: usize = 32;
+pub const ACCOUNT_RECORD_LEN: usize = 40;
+
+const MAGIC: &[u8; 4] = b"TXLB";
+const TRANSACTIONS: u8 = b'T';
+const ACCOUNTS: u8 = b'A';
+
+const HAS_AMOUNT: u8 = 1;
+const HAS_TIMESTAMP: u8 = 2;
+
+// spelled out rather than derived from the enum's order, since files outlive it
+fn kind_byte(kind: TransactionType) -> u8 {
+    match kind {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Representment => 5,
+        TransactionType::PreArbitration => 6,
+        TransactionType::Arbitration => 7,
+        TransactionType::Lock => 8,
+        TransactionType::Unlock => 9,
+    }
+}
+
+fn kind(byte: u8) -> Option<TransactionType> {
+    Some(match byte {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::Chargeback,
+        5 => TransactionType::Representment,
+        6 => TransactionType::PreArbitration,
+        7 => TransactionType::Arbitration,
+        8 => TransactionType::Lock,
+        9 => TransactionType::Unlock,
+        _ => return None,
+    })
+}
+
+fn code(asset: Asset) -> [u8; Asset::MAX_LEN] {
+    let mut code = [0; Asset::MAX_LEN];
+    let s = asset.as_str().as_bytes();
+    code[..s.len()].copy_from_slice(s);
+    code
+}
+
+fn asset(code: &[u8]) -> Result<Asset, CoreError> {
+    let len = code.iter().position(|&b| b == 0).unwrap_or(code.len());
+    if code[len..].iter().any(|&b| b != 0) {
+        return Err(CoreError::InvalidAsset);
+    }
+    let s = std::str::from_utf8(&code[..len]).map_err(|_| CoreError::InvalidAsset)?;
+    Asset::parse(s)
+}
+
+fn bytes<const N: usize>(record: &[u8], at: usize) -> [u8; N] {
+    record[at..at + N].try_into().unwrap_or([0; N])
+}
+
+fn hex(record: &[u8]) -> String {
+    record.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn write_header(out: &mut impl Write, content: u8, precisions: &Precisions) -> std::io::Result<()> {
+    let mut entries: Vec<_> = precisions.iter().collect();
+    entries.sort_unstable();
+    let count = u8::try_from(entries.len()).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "too many precisions for a binary header",
+        )
+    })?;
+
+    out.write_all(MAGIC)?;
+    out.write_all(&BINARY_VERSION.to_le_bytes())?;
+    out.write_all(&[content, count])?;
+    for (asset, places) in entries {
+        out.write_all(&code(asset))?;
+        // at most Amount::MAX_DECIMALS
+        out.write_all(&[places as u8])?;
+    }
+    Ok(())
+}
+
+/// Reads the header up to the first record; returns the precisions and the header's length.
+fn read_header(input: &mut impl Read, content: u8) -> Result<(Precisions, u64), String> {
+    let truncated = |_| "truncated header".to_string();
+    let mut fixed = [0u8; 8];
+    input.read_exact(&mut fixed).map_err(truncated)?;
+
+    if &fixed[..4] != MAGIC {
+        return Err("not a binary ledger file".to_string());
+    }
+    let version = u16::from_le_bytes(bytes(&fixed, 4));
+    if version != BINARY_VERSION {
+        return Err(format!(
+            "unsupported binary version {} (expected {})",
+            version, BINARY_VERSION
+        ));
+    }
+    if fixed[6] != content {
+        return Err(format!(
+            "holds {} records, expected {}",
+            fixed[6] as char, content as char
+        ));
+    }
+
+    let mut precisions = Precisions::new();
+    let mut entry = [0u8; Asset::MAX_LEN + 1];
+    for _ in 0..fixed[7] {
+        input.read_exact(&mut entry).map_err(truncated)?;
+        let asset = asset(&entry[..Asset::MAX_LEN]).map_err(|e| e.to_string())?;
+        precisions
+            .insert(asset, entry[Asset::MAX_LEN] as u32)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok((
+        precisions,
+        (fixed.len() + fixed[7] as usize * entry.len()) as u64,
+    ))
+}
+
+// every asset either side names must get the same places
+fn agree(a: &Precisions, b: &Precisions) -> bool {
+    a.iter()
+        .chain(b.iter())
+        .all(|(asset, _)| a.decimals(asset) == b.decimals(asset))
+}
+
+/// Reads binary transaction records. Amounts are taken as they are, so there's no rounding;
+/// the precisions only have to match the ones in the file's header.
+/// Line numbers count the header as line 1, like a CSV header, and then one per record.
+#[derive(Debug, Default, Clone)]
+pub struct BinaryIngester {
+    strict: bool,
+    precisions: Precisions,
+}
+
+impl BinaryIngester {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See `CsvIngester::with_strict`.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// The precisions the file must have been written with.
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+}
+
+impl BinaryIngester {
+    /// Like `ingest`, but a bad header is an error instead of a single malformed row,
+    /// so the caller can refuse the whole file.
+    pub fn open<'a>(
+        &self,
+        input: Box<dyn Read + 'a>,
+    ) -> std::io::Result<Box<dyn Iterator<Item = IngestEvent> + 'a>> {
+        let mut input = BufReader::new(input);
+        let (precisions, len) = read_header(&mut input, TRANSACTIONS)
+            .map_err(|msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg))?;
+        if !agree(&precisions, &self.precisions) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "binary file was written with different precisions",
+            ));
+        }
+
+        let iter = Records {
+            input,
+            strict: self.strict,
+            next: SourcePos { line: 2, byte: len },
+            failed: false,
+        };
+        if !self.strict {
+            return Ok(Box::new(iter));
+        }
+        Ok(Box::new(iter.map_while(strict_cut())))
+    }
+}
+
+impl Ingester for BinaryIngester {
+    fn ingest<'a>(&self, input: Box<dyn Read + 'a>) -> Box<dyn Iterator<Item = IngestEvent> + 'a> {
+        self.open(input).unwrap_or_else(|e| {
+            // nothing sensible can follow a bad header
+            Box::new(std::iter::once(IngestEvent::MalformedRow {
+                pos: SourcePos { line: 1, byte: 0 },
+                raw: String::new(),
+                error: CoreError::InvalidRecord(e.to_string()),
+                client: None,
+                tx: None,
+            }))
+        })
+    }
+}
+
+struct Records<R> {
+    input: R,
+    strict: bool,
+    /// Where the next record starts.
+    next: SourcePos,
+    failed: bool,
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = IngestEvent;
+
+    fn next(&mut self) -> Option<IngestEvent> {
+        if self.failed {
+            return None;
+        }
+        let mut record = [0u8; TX_RECORD_LEN];
+        let mut filled = 0;
+        while filled < TX_RECORD_LEN {
+            match self.input.read(&mut record[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.failed = true;
+                    return Some(self.unreadable(e.to_string()));
+                }
+            }
+        }
+
+        match filled {
+            0 => None,
+            TX_RECORD_LEN => {
+                let pos = self.advance();
+                Some(self.event(&record, pos))
+            }
+            _ => {
+                self.failed = true;
+                Some(self.unreadable(format!(
+                    "truncated record, {} of {} bytes",
+                    filled, TX_RECORD_LEN
+                )))
+            }
+        }
+    }
+}
+
+impl<R> Records<R> {
+    fn advance(&mut self) -> SourcePos {
+        let pos = self.next;
+        self.next.line += 1;
+        self.next.byte += TX_RECORD_LEN as u64;
+        pos
+    }
+
+    fn unreadable(&mut self, msg: String) -> IngestEvent {
+        IngestEvent::MalformedRow {
+            pos: self.advance(),
+            raw: String::new(),
+            error: CoreError::InvalidRecord(msg),
+            client: None,
+            tx: None,
+        }
+    }
+
+    fn event(&self, r: &[u8; TX_RECORD_LEN], pos: SourcePos) -> IngestEvent {
+        let client = u16::from_le_bytes(bytes(r, 2));
+        let tx = u32::from_le_bytes(bytes(r, 4));
+        let malformed = |error| IngestEvent::MalformedRow {
+            pos,
+            raw: hex(r),
+            error,
+            client: Some(client),
+            tx: Some(tx),
+        };
+
+        let Some(kind) = kind(r[0]) else {
+            return IngestEvent::UnknownType { pos, client, tx };
+        };
+
+        let flags = r[1];
+        if flags & !(HAS_AMOUNT | HAS_TIMESTAMP) != 0 {
+            return malformed(CoreError::InvalidRecord(format!(
+                "unknown flags {:#04x}",
+                flags
+            )));
+        }
+
+        let asset = match asset(&r[16..24]) {
+            Ok(a) => a,
+            Err(e) => return malformed(e),
+        };
+
+        // same as text rows: an amount on a type that takes none is dropped, or fails strict
+        let units = (flags & HAS_AMOUNT != 0).then(|| i64::from_le_bytes(bytes(r, 8)));
+        let amount = match units {
+            Some(_) if self.strict && !kind.takes_amount() => {
+                return malformed(CoreError::UnexpectedAmount);
+            }
+            Some(_) if !kind.takes_amount() => None,
+            Some(units) if units < 0 => return malformed(CoreError::NegativeAmount),
+            units => units.map(Amount::from_scaled),
+        };
+
+        IngestEvent::Tx {
+            tx: Transaction {
+                kind,
+                client,
+                tx,
+                amount,
+                asset,
+                timestamp: (flags & HAS_TIMESTAMP != 0).then(|| u64::from_le_bytes(bytes(r, 24))),
+            },
+            pos,
+            rounding: None,
+        }
+    }
+}
+
+/// Writes transactions as binary records, e.g. to convert a text feed once ahead of time.
+/// Amounts must be in units of `precisions`, as they are when parsed with them.
+pub struct BinaryTransactionWriter<W: Write> {
+    out: BufWriter<W>,
+}
+
+impl<W: Write> BinaryTransactionWriter<W> {
+    pub fn new(out: W, precisions: &Precisions) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(out);
+        write_header(&mut out, TRANSACTIONS, precisions)?;
+        Ok(Self { out })
+    }
+}
+
+impl<W: Write> TransactionSink for BinaryTransactionWriter<W> {
+    fn append(&mut self, tx: &Transaction) -> std::io::Result<()> {
+        let mut r = [0u8; TX_RECORD_LEN];
+        r[0] = kind_byte(tx.kind);
+        r[2..4].copy_from_slice(&tx.client.to_le_bytes());
+        r[4..8].copy_from_slice(&tx.tx.to_le_bytes());
+        if let Some(amount) = tx.amount {
+            r[1] |= HAS_AMOUNT;
+            r[8..16].copy_from_slice(&amount.as_i64().to_le_bytes());
+        }
+        r[16..24].copy_from_slice(&code(tx.asset));
+        if let Some(t) = tx.timestamp {
+            r[1] |= HAS_TIMESTAMP;
+            r[24..32].copy_from_slice(&t.to_le_bytes());
+        }
+        self.out.write_all(&r)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes account rows as binary records, in the same order as `CsvEmitter`.
+#[derive(Debug, Default, Clone)]
+pub struct BinaryEmitter {
+    precisions: Precisions,
+}
+
+impl BinaryEmitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Recorded in the header, so readers know the amounts' scale.
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+}
+
+impl Emitter for BinaryEmitter {
+    fn emit(&self, rows: &[AccountRow], out: &mut dyn Write) -> std::io::Result<()> {
+        let mut out = BufWriter::new(out);
+        write_header(&mut out, ACCOUNTS, &self.precisions)?;
+        for row in rows {
+            let mut r = [0u8; ACCOUNT_RECORD_LEN];
+            r[0..2].copy_from_slice(&row.client.to_le_bytes());
+            r[2..10].copy_from_slice(&code(row.asset));
+            r[10..18].copy_from_slice(&row.available.as_i64().to_le_bytes());
+            r[18..26].copy_from_slice(&row.held.as_i64().to_le_bytes());
+            r[26..34].copy_from_slice(&row.total.as_i64().to_le_bytes());
+            r[34] = row.locked as u8;
+            out.write_all(&r)?;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::formats::csv::CsvIngester;
+
+    fn precisions() -> Precisions {
+        let mut p = Precisions::new();
+        p.insert(Asset::parse("BTC").unwrap(), 8).unwrap();
+        p
+    }
+
+    fn convert(csv: &str, precisions: &Precisions) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = BinaryTransactionWriter::new(&mut out, precisions).unwrap();
+        let ingester = CsvIngester::new().with_precisions(precisions.clone());
+        for event in ingester.ingest(Box::new(csv.as_bytes())) {
+            if let IngestEvent::Tx { tx, .. } = event {
+                writer.append(&tx).unwrap();
+            }
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        out
+    }
+
+    fn read(ingester: &BinaryIngester, data: Vec<u8>) -> Vec<IngestEvent> {
+        ingester
+            .ingest(Box::new(std::io::Cursor::new(data)))
+            .collect()
+    }
+
+    fn record(kind: u8, flags: u8, amount: i64, asset: &[u8]) -> [u8; TX_RECORD_LEN] {
+        let mut r = [0u8; TX_RECORD_LEN];
+        r[0] = kind;
+        r[1] = flags;
+        r[2..4].copy_from_slice(&7u16.to_le_bytes());
+        r[4..8].copy_from_slice(&9u32.to_le_bytes());
+        r[8..16].copy_from_slice(&amount.to_le_bytes());
+        r[16..16 + asset.len()].copy_from_slice(asset);
+        r
+    }
+
+    #[test]
+    fn round_trips_csv_transactions() {
+        let csv = "type,client,tx,amount,asset,timestamp\n\
+                   deposit,1,1,1.5,,\n\
+                   deposit,2,2,0.00000001,btc,1700000000\n\
+                   dispute,1,1,,,\n\
+                   chargeback,1,1,0.5,,\n\
+                   lock,2,0,,,1700000005\n\
+                   withdrawal,65535,4294967295,12345.6789,,\n";
+        let data = convert(csv, &precisions());
+        assert_eq!(data.len(), 8 + 9 + 6 * TX_RECORD_LEN);
+
+        let expected: Vec<_> = CsvIngester::new()
+            .with_precisions(precisions())
+            .ingest(Box::new(csv.as_bytes()))
+            .collect();
+        let events = read(&BinaryIngester::new().with_precisions(precisions()), data);
+        assert_eq!(events.len(), expected.len());
+        for (event, expected) in events.iter().zip(&expected) {
+            let (IngestEvent::Tx { tx, pos, .. }, IngestEvent::Tx { tx: want, .. }) =
+                (event, expected)
+            else {
+                panic!("expected transactions, got {:?}", event);
+            };
+            assert_eq!(format!("{:?}", tx), format!("{:?}", want));
+            assert_eq!(pos.line, expected.pos().line);
+        }
+        assert_eq!(events[1].pos().byte, 17 + TX_RECORD_LEN as u64);
+    }
+
+    #[test]
+    fn bad_records_are_unknown_or_malformed() {
+        let mut data = convert("type,client,tx,amount\n", &Precisions::new());
+        data.extend(record(42, 0, 0, b""));
+        data.extend(record(0, 0x80, 1, b""));
+        data.extend(record(0, HAS_AMOUNT, -1, b""));
+        data.extend(record(0, HAS_AMOUNT, 1, b"U$D"));
+        data.extend(record(8, HAS_AMOUNT, 1, b""));
+        data.extend(&record(0, HAS_AMOUNT, 1, b"")[..10]);
+
+        let events = read(&BinaryIngester::new(), data.clone());
+        assert!(matches!(
+            events[0],
+            IngestEvent::UnknownType {
+                client: 7,
+                tx: 9,
+                ..
+            }
+        ));
+        let errors: Vec<_> = events[1..4]
+            .iter()
+            .map(|e| match e {
+                IngestEvent::MalformedRow { error, .. } => error.clone(),
+                other => panic!("expected a malformed row, got {:?}", other),
+            })
+            .collect();
+        assert!(matches!(errors[0], CoreError::InvalidRecord(_)));
+        assert_eq!(errors[1], CoreError::NegativeAmount);
+        assert_eq!(errors[2], CoreError::InvalidAsset);
+        // the lock's amount is dropped, as in a CSV row
+        assert!(matches!(&events[4], IngestEvent::Tx { tx, .. } if tx.amount.is_none()));
+        assert!(matches!(
+            &events[5],
+            IngestEvent::MalformedRow { pos, error: CoreError::InvalidRecord(msg), .. }
+                if pos.line == 7 && msg.contains("truncated")
+        ));
+        assert_eq!(events.len(), 6);
+
+        let strict = read(&BinaryIngester::new().with_strict(true), data);
+        assert_eq!(strict.len(), 1);
+    }
+
+    #[test]
+    fn header_must_match() {
+        let ingester = BinaryIngester::new();
+        let mut wrong_version = convert("type,client,tx,amount\n", &Precisions::new());
+        wrong_version[4] = 2;
+        let mut accounts = Vec::new();
+        BinaryEmitter::new().emit(&[], &mut accounts).unwrap();
+
+        for data in [
+            b"type,client,tx,amount\n".to_vec(),
+            b"TX".to_vec(),
+            wrong_version,
+            accounts,
+            convert("type,client,tx,amount\n", &precisions()),
+        ] {
+            assert!(
+                ingester
+                    .open(Box::new(std::io::Cursor::new(data.clone())))
+                    .is_err()
+            );
+            let events = read(&ingester, data);
+            assert_eq!(events.len(), 1);
+            assert!(matches!(
+                events[0],
+                IngestEvent::MalformedRow {
+                    error: CoreError::InvalidRecord(_),
+                    ..
+                }
+            ));
+        }
+
+        // naming the default places explicitly is no difference
+        let mut explicit = Precisions::new();
+        explicit.insert(Asset::default(), Amount::DECIMALS).unwrap();
+        let data = convert("type,client,tx,amount\ndeposit,1,1,1\n", &explicit);
+        assert!(matches!(
+            read(&ingester, data)[..],
+            [IngestEvent::Tx { .. }]
+        ));
+    }
+
+    #[test]
+    fn accounts_are_fixed_width_records() {
+        let rows = [AccountRow {
+            client: 3,
+            asset: Asset::parse("BTC").unwrap(),
+            available: Amount::from_scaled(150_000_000),
+            held: Amount::from_scaled(-1),
+            total: Amount::from_scaled(149_999_999),
+            locked: true,
+        }];
+        let mut out = Vec::new();
+        BinaryEmitter::new()
+            .with_precisions(precisions())
+            .emit(&rows, &mut out)
+            .unwrap();
+
+        let (header, record) = out.split_at(17);
+        assert_eq!(&header[..8], b"TXLB\x01\x00A\x01");
+        assert_eq!(&header[8..], b"BTC\0\0\0\0\0\x08");
+        assert_eq!(record.len(), ACCOUNT_RECORD_LEN);
+        assert_eq!(u16::from_le_bytes(bytes(record, 0)), 3);
+        assert_eq!(&record[2..10], b"BTC\0\0\0\0\0");
+        assert_eq!(i64::from_le_bytes(bytes(record, 18)), -1);
+        assert_eq!(i64::from_le_bytes(bytes(record, 26)), 149_999_999);
+        assert_eq!(record[34], 1);
+    }
+}
@@ -1,93 +1,1311 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
+use std::str::FromStr;
 
 use serde::Deserialize;
 
 use crate::core::errors::CoreError;
-use crate::core::types::{AccountRow, Amount, ClientId, Transaction, TransactionType, TxId};
-use crate::io::{Emitter, IngestEvent, Ingester};
+use crate::core::types::{AccountRow, Precisions, RoundingPolicy, Transaction};
+use crate::engine::journal::JournalEntry;
+use crate::io::formats::{Row, Rules, parse_kind, strict_cut};
+use crate::io::{
+    Emitter, IngestEvent, Ingester, JournalSink, RejectSink, Rejection, RoundingEntry,
+    RoundingSink, SourcePos, TransactionSink,
+};
 
-#[derive(Debug, Deserialize)]
-struct CsvRow {
-    #[serde(rename = "type")]
-    kind: String,
-    client: ClientId,
-    tx: TxId,
-    amount: Option<String>,
+// best effort lookup so the rejects report can still name the client/tx of a row that failed to deserialize
+fn field<T: FromStr>(
+    headers: &csv::StringRecord,
+    record: &csv::StringRecord,
+    name: &str,
+) -> Option<T> {
+    let idx = headers.iter().position(|h| h == name)?;
+    record.get(idx)?.parse().ok()
 }
 
-fn parse_kind(s: &str) -> Result<TransactionType, CoreError> {
-    match s.trim() {
-        "deposit" => Ok(TransactionType::Deposit),
-        "withdrawal" => Ok(TransactionType::Withdrawal),
-        "dispute" => Ok(TransactionType::Dispute),
-        "resolve" => Ok(TransactionType::Resolve),
-        "chargeback" => Ok(TransactionType::Chargeback),
-        _ => Err(CoreError::UnknownTransactionType),
+fn source_pos(p: Option<&csv::Position>) -> SourcePos {
+    p.map(|p| SourcePos {
+        line: p.line(),
+        byte: p.byte(),
+    })
+    .unwrap_or_default()
+}
+
+// csv::Error's Display repeats the position, which we already carry separately
+fn describe(e: &csv::Error) -> CoreError {
+    let msg = match e.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        csv::ErrorKind::Utf8 { err, .. } => err.to_string(),
+        _ => e.to_string(),
+    };
+    CoreError::InvalidRecord(msg)
+}
+
+// quoted where needed, so the raw record reads back as the same fields
+fn raw(record: &csv::StringRecord, dialect: &CsvDialect) -> String {
+    let style = match dialect.quote {
+        Some(_) => csv::QuoteStyle::Necessary,
+        None => csv::QuoteStyle::Never,
+    };
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(dialect.delimiter)
+        .quote(dialect.quote.unwrap_or(b'"'))
+        .quote_style(style)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(Vec::new());
+    if wtr.write_record(record).is_err() {
+        return String::new();
     }
+    let mut line = wtr.into_inner().unwrap_or_default();
+    line.pop();
+    String::from_utf8(line).unwrap_or_default()
 }
 
-pub struct CsvIngester;
+#[derive(Debug, Default, Clone)]
+pub struct CsvIngester {
+    strict: bool,
+    precisions: Precisions,
+    rounding: RoundingPolicy,
+    dialect: CsvDialect,
+}
+
+impl CsvIngester {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// In strict mode an amount on a row whose type takes no amount is malformed
+    /// rather than ignored, and the stream ends right after the first bad row.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Amounts are parsed at their asset's precision; more places than that is malformed.
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+
+    /// With anything but `RoundingPolicy::Reject`, excess places are rounded and the
+    /// delta travels with the event instead of the row being malformed.
+    pub fn with_rounding(mut self, rounding: RoundingPolicy) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Reads files laid out some other way than `type,client,tx,amount` with commas.
+    pub fn with_dialect(mut self, dialect: CsvDialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+}
 
 impl Ingester for CsvIngester {
     fn ingest<'a>(&self, input: Box<dyn Read + 'a>) -> Box<dyn Iterator<Item = IngestEvent> + 'a> {
-        let rdr = csv::ReaderBuilder::new()
+        let mut rdr = self.dialect.reader_builder().from_reader(input);
+
+        let rows = self.rows(
+            self.dialect
+                .headers(&rdr.headers().cloned().unwrap_or_default()),
+        );
+        let iter = rdr.into_records().map(move |res| match res {
+            Ok(record) => rows.event(&record, source_pos(record.position())),
+            Err(e) => unreadable(&e, source_pos(e.position())),
+        });
+
+        if !self.strict {
+            return Box::new(iter);
+        }
+        Box::new(iter.map_while(strict_cut()))
+    }
+}
+
+impl CsvIngester {
+    /// Parses one headerless line with the columns `type,client,tx,amount,asset,timestamp`,
+    /// for line protocols. `None` if it's blank. The dialect's column names don't apply.
+    pub fn parse_line(&self, line: &str, pos: SourcePos) -> Option<IngestEvent> {
+        let rows = self.rows(csv::StringRecord::from(COLUMNS.to_vec()));
+        let mut rdr = self
+            .dialect
+            .reader_builder()
+            .has_headers(false)
+            .from_reader(line.as_bytes());
+        Some(match rdr.records().next()? {
+            Ok(record) => rows.event(&record, pos),
+            Err(e) => unreadable(&e, pos),
+        })
+    }
+
+    fn rows(&self, headers: csv::StringRecord) -> RowParser {
+        RowParser {
+            headers,
+            dialect: self.dialect.clone(),
+            rules: Rules {
+                strict: self.strict,
+                precisions: self.precisions.clone(),
+                policy: self.rounding,
+            },
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl crate::io::AsyncIngester for CsvIngester {
+    fn ingest_stream<'a>(
+        &self,
+        input: Box<dyn tokio::io::AsyncRead + Send + Unpin + 'a>,
+    ) -> crate::io::EventStream<'a> {
+        use futures_util::{StreamExt, future, stream};
+
+        let records = AsyncRecords::new(input, self.dialect.clone());
+        let events = stream::unfold(
+            (records, None::<RowParser>, self.clone()),
+            |(mut records, mut rows, ingester)| async move {
+                loop {
+                    let (res, pos) = records.next().await?;
+                    let Some(parser) = &rows else {
+                        let headers = ingester.dialect.headers(&res.unwrap_or_default());
+                        rows = Some(ingester.rows(headers));
+                        continue;
+                    };
+                    let event = match res {
+                        Ok(record) => parser.event(&record, pos),
+                        Err(e) => unreadable(&e, pos),
+                    };
+                    return Some((event, (records, rows, ingester)));
+                }
+            },
+        );
+
+        if !self.strict {
+            return Box::pin(events);
+        }
+        let mut cut = strict_cut();
+        Box::pin(events.scan((), move |_, ev| future::ready(cut(ev))))
+    }
+}
+
+/// Splits async input into CSV records, one line at a time, or more while a quoted field
+/// runs on.
+///
+/// Positions match the blocking reader's, which places a record where the previous one
+/// ended: blank lines before it aren't counted, and after a `\r\n` it starts at the `\n`.
+#[cfg(feature = "async")]
+struct AsyncRecords<R> {
+    input: tokio::io::BufReader<R>,
+    dialect: CsvDialect,
+    line: u64,
+    byte: u64,
+    next: SourcePos,
+    done: bool,
+}
+
+#[cfg(feature = "async")]
+impl<R: tokio::io::AsyncRead + Unpin> AsyncRecords<R> {
+    fn new(input: R, dialect: CsvDialect) -> Self {
+        Self {
+            input: tokio::io::BufReader::new(input),
+            dialect,
+            line: 1,
+            byte: 0,
+            next: SourcePos { line: 1, byte: 0 },
+            done: false,
+        }
+    }
+
+    async fn next(&mut self) -> Option<(Result<csv::StringRecord, csv::Error>, SourcePos)> {
+        use tokio::io::AsyncBufReadExt;
+
+        while !self.done {
+            let pos = self.next;
+            let mut chunk = Vec::new();
+            loop {
+                match self.input.read_until(b'\n', &mut chunk).await {
+                    Ok(0) => {
+                        self.done = true;
+                        break;
+                    }
+                    Ok(n) => {
+                        self.byte += n as u64;
+                        if chunk.ends_with(b"\n") {
+                            self.line += 1;
+                        }
+                    }
+                    // nothing sensible can follow a failed read
+                    Err(e) => {
+                        self.done = true;
+                        return Some((Err(e.into()), pos));
+                    }
+                }
+                // an odd number of quotes so far means a quoted field spans the newline
+                let quotes = match self.dialect.quote {
+                    Some(quote) => chunk.iter().filter(|&&b| b == quote).count(),
+                    None => 0,
+                };
+                if quotes % 2 == 0 {
+                    break;
+                }
+            }
+
+            // blank lines come back as no record and are skipped
+            let mut rdr = self
+                .dialect
+                .reader_builder()
+                .has_headers(false)
+                .from_reader(&chunk[..]);
+            if let Some(res) = rdr.records().next() {
+                let crlf = u64::from(chunk.ends_with(b"\r\n"));
+                self.next = SourcePos {
+                    line: self.line - crlf,
+                    byte: self.byte - crlf,
+                };
+                return Some((res, pos));
+            }
+        }
+        None
+    }
+}
+
+/// The columns a row is read from, in the line protocol's order.
+const COLUMNS: [&str; 6] = ["type", "client", "tx", "amount", "asset", "timestamp"];
+
+/// How a CSV file is laid out, for feeds that don't use `type,client,tx,amount` with
+/// commas. The default reads that layout. Columns are found by their header, so their
+/// order doesn't matter and columns we don't know are ignored. A UTF-8 byte order mark
+/// before the header is always skipped.
+///
+/// Loaded from TOML with `from_toml`:
+///
+/// ```toml
+/// delimiter = ";"
+/// case_insensitive = true
+///
+/// [columns]  # ours = theirs
+/// tx = "txn_id"
+/// client = "customer"
+///
+/// [types]  # theirs = ours
+/// dep = "deposit"
+/// wd = "withdrawal"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvDialect {
+    delimiter: u8,
+    quote: Option<u8>,
+    columns: BTreeMap<String, String>,
+    types: BTreeMap<String, String>,
+    case_insensitive: bool,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: Some(b'"'),
+            columns: BTreeMap::new(),
+            types: BTreeMap::new(),
+            case_insensitive: false,
+        }
+    }
+}
+
+/// The file form of a dialect, before it's checked.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DialectFile {
+    delimiter: Option<String>,
+    quote: Option<String>,
+    quoting: Option<bool>,
+    columns: BTreeMap<String, String>,
+    types: BTreeMap<String, String>,
+    case_insensitive: bool,
+}
+
+impl CsvDialect {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// `None` turns quoting off, so quote characters are read as data.
+    pub fn with_quote(mut self, quote: Option<u8>) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    /// Reads the `column` we know (`type`, `client`, `tx`, `amount`, `asset` or
+    /// `timestamp`) from the file's column `name`.
+    pub fn with_column(mut self, column: &str, name: &str) -> Self {
+        self.columns.insert(column.to_string(), name.to_string());
+        self
+    }
+
+    /// Reads the type `alias` as `keyword`, e.g. `DEP` as `deposit`.
+    pub fn with_type_alias(mut self, alias: &str, keyword: &str) -> Self {
+        self.types.insert(alias.to_string(), keyword.to_string());
+        self
+    }
+
+    /// Matches column names, type keywords and aliases regardless of ASCII case.
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Parses a dialect from TOML; see the type's docs for the layout. Everything is
+    /// optional, and unknown keys, columns or type keywords are errors rather than ignored.
+    pub fn from_toml(s: &str) -> Result<Self, String> {
+        let file: DialectFile = toml::from_str(s).map_err(|e| e.message().to_string())?;
+
+        let mut dialect = Self::new().with_case_insensitive(file.case_insensitive);
+        if let Some(delimiter) = &file.delimiter {
+            dialect.delimiter = ascii("delimiter", delimiter)?;
+        }
+        if let Some(quote) = &file.quote {
+            dialect.quote = Some(ascii("quote", quote)?);
+        }
+        if file.quoting == Some(false) {
+            dialect.quote = None;
+        }
+        if dialect.quote == Some(dialect.delimiter) {
+            return Err("delimiter and quote must differ".to_string());
+        }
+
+        for (column, name) in &file.columns {
+            if !COLUMNS.contains(&column.as_str()) {
+                return Err(format!(
+                    "unknown column {}, expected one of {}",
+                    column,
+                    COLUMNS.join(", ")
+                ));
+            }
+            dialect = dialect.with_column(column, name);
+        }
+        for (alias, keyword) in &file.types {
+            if parse_kind(keyword).is_err() {
+                return Err(format!("{} is aliased to unknown type {}", alias, keyword));
+            }
+            dialect = dialect.with_type_alias(alias, keyword);
+        }
+        Ok(dialect)
+    }
+
+    /// Reads and parses a TOML dialect file.
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let s = std::fs::read_to_string(path)?;
+        Self::from_toml(&s).map_err(|msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+    }
+
+    fn reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder
             .trim(csv::Trim::All)
             .flexible(true)
-            .from_reader(input);
-
-        let iter = rdr.into_deserialize::<CsvRow>().map(|res| {
-            let row = match res {
-                Ok(r) => r,
-                Err(_) => return IngestEvent::MalformedRow,
-            };
-
-            let kind = match parse_kind(&row.kind) {
-                Ok(k) => k,
-                Err(CoreError::UnknownTransactionType) => return IngestEvent::UnknownType,
-                Err(_) => return IngestEvent::MalformedRow,
-            };
-
-            let amount = match (kind, row.amount) {
-                (TransactionType::Deposit | TransactionType::Withdrawal, Some(a)) => {
-                    match Amount::from_str_4dp(&a) {
-                        Ok(v) => Some(v),
-                        Err(_) => None,
-                    }
+            .delimiter(self.delimiter)
+            .quoting(self.quote.is_some());
+        if let Some(quote) = self.quote {
+            builder.quote(quote);
+        }
+        builder
+    }
+
+    fn same(&self, a: &str, b: &str) -> bool {
+        match self.case_insensitive {
+            true => a.eq_ignore_ascii_case(b),
+            false => a == b,
+        }
+    }
+
+    /// Renames the file's headers to ours. A column that has our name but isn't the one
+    /// we read it from is blanked, so it can't clash with the one we do.
+    fn headers(&self, file: &csv::StringRecord) -> csv::StringRecord {
+        file.iter()
+            .enumerate()
+            .map(|(i, name)| {
+                // csv only skips a byte order mark that arrives in one read
+                let name = match i {
+                    0 => name.trim_start_matches('\u{feff}'),
+                    _ => name,
+                };
+                let ours = COLUMNS.iter().find(|column| {
+                    let theirs = self.columns.get(**column).map_or(**column, String::as_str);
+                    self.same(name, theirs)
+                });
+                match ours {
+                    Some(column) => column,
+                    None if COLUMNS.iter().any(|column| self.same(name, column)) => "",
+                    None => name,
                 }
-                _ => None,
-            };
-
-            IngestEvent::Tx(Transaction {
-                kind,
-                client: row.client,
-                tx: row.tx,
-                amount,
             })
-        });
+            .collect()
+    }
+
+    /// The keyword for a type as the file spells it, if that's not already ours.
+    fn keyword(&self, kind: &str) -> Option<String> {
+        let kind = kind.trim();
+        if let Some((_, keyword)) = self.types.iter().find(|(alias, _)| self.same(kind, alias)) {
+            return Some(keyword.clone());
+        }
+        match self.case_insensitive {
+            true => Some(kind.to_ascii_lowercase()),
+            false => None,
+        }
+    }
+}
+
+fn ascii(key: &str, s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        [b] if b.is_ascii() && !matches!(b, b'\n' | b'\r') => Ok(*b),
+        _ => Err(format!("{} must be a single character, got {:?}", key, s)),
+    }
+}
 
-        Box::new(iter)
+fn unreadable(e: &csv::Error, pos: SourcePos) -> IngestEvent {
+    IngestEvent::MalformedRow {
+        pos,
+        raw: String::new(),
+        error: describe(e),
+        client: None,
+        tx: None,
     }
 }
 
-pub struct CsvEmitter;
+/// Turns one record into an event, the same way whichever reader it came from.
+struct RowParser {
+    headers: csv::StringRecord,
+    dialect: CsvDialect,
+    rules: Rules,
+}
+
+impl RowParser {
+    fn event(&self, record: &csv::StringRecord, pos: SourcePos) -> IngestEvent {
+        let headers = &self.headers;
+        let malformed = |error: CoreError| IngestEvent::MalformedRow {
+            pos,
+            raw: raw(record, &self.dialect),
+            error,
+            client: field(headers, record, "client"),
+            tx: field(headers, record, "tx"),
+        };
+
+        match record.deserialize::<Row>(Some(headers)) {
+            Ok(mut row) => {
+                if let Some(keyword) = self.dialect.keyword(&row.kind) {
+                    row.kind = keyword;
+                }
+                self.rules.event(row, pos, &malformed)
+            }
+            Err(e) => malformed(describe(&e)),
+        }
+    }
+}
+
+/// Writes one row per client, or per client and asset once any account is in a named
+/// asset. Single-asset input without an asset column keeps the original layout.
+#[derive(Debug, Default, Clone)]
+pub struct CsvEmitter {
+    precisions: Precisions,
+}
+
+impl CsvEmitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prints each account's amounts with its asset's number of places.
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+}
 
 impl Emitter for CsvEmitter {
     fn emit(&self, rows: &[AccountRow], out: &mut dyn Write) -> std::io::Result<()> {
         let mut wtr = csv::WriterBuilder::new().has_headers(true).from_writer(out);
 
-        wtr.write_record(["client", "available", "held", "total", "locked"])?;
+        let with_asset = rows.iter().any(|r| !r.asset.is_default());
+        if with_asset {
+            wtr.write_record(["client", "asset", "available", "held", "total", "locked"])?;
+        } else {
+            wtr.write_record(["client", "available", "held", "total", "locked"])?;
+        }
 
         for r in rows {
-            wtr.write_record(&[
-                r.client.to_string(),
-                r.available.to_string(),
-                r.held.to_string(),
-                r.total.to_string(),
+            let mut record = vec![r.client.to_string()];
+            if with_asset {
+                record.push(r.asset.to_string());
+            }
+            let dp = self.precisions.decimals(r.asset);
+            record.extend([
+                r.available.display(dp).to_string(),
+                r.held.display(dp).to_string(),
+                r.total.display(dp).to_string(),
                 r.locked.to_string(),
-            ])?;
+            ]);
+            wtr.write_record(&record)?;
         }
 
         wtr.flush()?;
         Ok(())
     }
 }
+
+/// Writes one CSV row per rejected input row: `line,client,tx,reason,detail`.
+/// Client and tx are left empty when the row was too broken to read them.
+pub struct CsvRejectWriter<W: Write> {
+    wtr: csv::Writer<W>,
+}
+
+impl<W: Write> CsvRejectWriter<W> {
+    pub fn new(out: W) -> std::io::Result<Self> {
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(out);
+        wtr.write_record(["line", "client", "tx", "reason", "detail"])?;
+        Ok(Self { wtr })
+    }
+}
+
+impl<W: Write> RejectSink for CsvRejectWriter<W> {
+    fn reject(&mut self, r: &Rejection) -> std::io::Result<()> {
+        self.wtr.write_record(&[
+            r.line.to_string(),
+            r.client.map(|c| c.to_string()).unwrap_or_default(),
+            r.tx.map(|t| t.to_string()).unwrap_or_default(),
+            r.reason.code().to_string(),
+            r.detail.clone().unwrap_or_default(),
+        ])?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.wtr.flush()
+    }
+}
+
+/// Writes the journal as CSV, one row per applied event, with the account's
+/// balances before and after it.
+pub struct CsvJournalWriter<W: Write> {
+    wtr: csv::Writer<W>,
+    precisions: Precisions,
+}
+
+impl<W: Write> CsvJournalWriter<W> {
+    pub fn new(out: W) -> std::io::Result<Self> {
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(out);
+        wtr.write_record([
+            "seq",
+            "line",
+            "type",
+            "client",
+            "asset",
+            "tx",
+            "amount",
+            "available_before",
+            "held_before",
+            "locked_before",
+            "available_after",
+            "held_after",
+            "locked_after",
+        ])?;
+        Ok(Self {
+            wtr,
+            precisions: Precisions::default(),
+        })
+    }
+
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+}
+
+impl<W: Write> JournalSink for CsvJournalWriter<W> {
+    fn append(&mut self, e: &JournalEntry) -> std::io::Result<()> {
+        let dp = self.precisions.decimals(e.asset);
+        self.wtr.write_record(&[
+            e.seq.to_string(),
+            e.line.to_string(),
+            e.kind.as_str().to_string(),
+            e.client.to_string(),
+            e.asset.to_string(),
+            e.tx.to_string(),
+            e.amount
+                .map(|a| a.display(dp).to_string())
+                .unwrap_or_default(),
+            e.before.available.display(dp).to_string(),
+            e.before.held.display(dp).to_string(),
+            e.before.locked.to_string(),
+            e.after.available.display(dp).to_string(),
+            e.after.held.display(dp).to_string(),
+            e.after.locked.to_string(),
+        ])?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.wtr.flush()
+    }
+}
+
+/// Writes transactions in the input layout, `type,client,tx,amount,asset,timestamp`, so
+/// the file reads back as the same transactions with the same precisions.
+pub struct CsvTransactionWriter<W: Write> {
+    wtr: csv::Writer<W>,
+    precisions: Precisions,
+}
+
+impl<W: Write> CsvTransactionWriter<W> {
+    pub fn new(out: W) -> std::io::Result<Self> {
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(out);
+        wtr.write_record(["type", "client", "tx", "amount", "asset", "timestamp"])?;
+        Ok(Self {
+            wtr,
+            precisions: Precisions::default(),
+        })
+    }
+
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+}
+
+impl<W: Write> TransactionSink for CsvTransactionWriter<W> {
+    fn append(&mut self, tx: &Transaction) -> std::io::Result<()> {
+        let dp = self.precisions.decimals(tx.asset);
+        self.wtr.write_record(&[
+            tx.kind.as_str().to_string(),
+            tx.client.to_string(),
+            tx.tx.to_string(),
+            tx.amount
+                .map(|a| a.display(dp).to_string())
+                .unwrap_or_default(),
+            tx.asset.to_string(),
+            tx.timestamp.map(|t| t.to_string()).unwrap_or_default(),
+        ])?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.wtr.flush()
+    }
+}
+
+/// Writes one CSV row per rounded amount: `line,client,tx,asset,amount,delta,applied`,
+/// where delta is the rounded amount minus the exact input.
+pub struct CsvRoundingWriter<W: Write> {
+    wtr: csv::Writer<W>,
+    precisions: Precisions,
+}
+
+impl<W: Write> CsvRoundingWriter<W> {
+    pub fn new(out: W) -> std::io::Result<Self> {
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(out);
+        wtr.write_record([
+            "line", "client", "tx", "asset", "amount", "delta", "applied",
+        ])?;
+        Ok(Self {
+            wtr,
+            precisions: Precisions::default(),
+        })
+    }
+
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+}
+
+impl<W: Write> RoundingSink for CsvRoundingWriter<W> {
+    fn record(&mut self, e: &RoundingEntry) -> std::io::Result<()> {
+        self.wtr.write_record(&[
+            e.line.to_string(),
+            e.client.to_string(),
+            e.tx.to_string(),
+            e.asset.to_string(),
+            e.amount
+                .display(self.precisions.decimals(e.asset))
+                .to_string(),
+            e.delta.to_string(),
+            e.applied.to_string(),
+        ])?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.wtr.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{Amount, Asset, ClientId, TransactionType};
+    use crate::engine::outcome::RejectReason;
+
+    fn ingest(input: &str) -> Vec<IngestEvent> {
+        CsvIngester::new()
+            .ingest(Box::new(input.as_bytes()))
+            .collect()
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_ingestion_matches_blocking() {
+        use crate::io::AsyncIngester;
+        use futures_util::StreamExt;
+
+        let input = "\u{feff}type,client,tx,amount\r\ndeposit,1,1,1.0\r\n\r\n\ndeposit, 2 ,2,\"2.\n0\"\nbogus,1,3,\nwithdrawal,x,4,1.0\ndeposit,1,5,\"1\"\"\"\n\n";
+        let fixture = include_str!("../../../fixtures/complex/input_complex_10_clients.csv");
+        let partner = "\u{feff}TXN_ID;Customer;Type;Amount\r\n1;1;DEP;'1.0'\n2;1;Withdrawal;0.5\n";
+        for (input, ingester) in [
+            (input, CsvIngester::new()),
+            (input, CsvIngester::new().with_strict(true)),
+            (fixture, CsvIngester::new()),
+            (partner, CsvIngester::new().with_dialect(partner_dialect())),
+        ] {
+            let blocking: Vec<_> = ingester.ingest(Box::new(input.as_bytes())).collect();
+            let streamed: Vec<_> = ingester
+                .ingest_stream(Box::new(input.as_bytes()))
+                .collect()
+                .await;
+            assert_eq!(format!("{:?}", streamed), format!("{:?}", blocking));
+        }
+    }
+
+    fn partner_dialect() -> CsvDialect {
+        CsvDialect::new()
+            .with_delimiter(b';')
+            .with_quote(Some(b'\''))
+            .with_column("tx", "txn_id")
+            .with_column("client", "customer")
+            .with_type_alias("dep", "deposit")
+            .with_case_insensitive(true)
+    }
+
+    #[test]
+    fn dialect_reads_a_partner_layout() {
+        // an extra column, a column named like one of ours, and a byte order mark that
+        // arrives a byte at a time
+        let bom = "\u{feff}".as_bytes();
+        let rest = "Customer;TXN_ID;tx;TYPE;Amount;Note\n\
+                    1;1;9;DEP;1.5;first\n\
+                    1;2;9;Withdrawal;'0.5';'a;b'\n\
+                    1;3;9;refund;1.0;\n";
+        let events: Vec<_> = CsvIngester::new()
+            .with_dialect(partner_dialect())
+            .ingest(Box::new(bom[..1].chain(&bom[1..]).chain(rest.as_bytes())))
+            .collect();
+
+        let expected =
+            ingest("type,client,tx,amount\ndeposit,1,1,1.5\nwithdrawal,1,2,0.5\nrefund,1,3,1.0\n");
+        let kinds = |events: &[IngestEvent]| -> Vec<_> {
+            events
+                .iter()
+                .map(|e| match e {
+                    IngestEvent::Tx { tx, .. } => Ok((tx.kind, tx.client, tx.tx, tx.amount)),
+                    IngestEvent::UnknownType { client, tx, .. } => Err((*client, *tx)),
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect()
+        };
+        assert_eq!(kinds(&events), kinds(&expected));
+        assert_eq!(kinds(&events)[2], Err((1, 3)));
+        assert!(
+            matches!(events[1], IngestEvent::Tx { tx: ref t, .. } if t.amount == Some(Amount::from_str_4dp("0.5").unwrap()))
+        );
+
+        // the default dialect is as strict as ever
+        let events = ingest("type,client,tx,amount\nDEPOSIT,1,1,1.0\n");
+        assert!(matches!(events[0], IngestEvent::UnknownType { .. }));
+    }
+
+    #[test]
+    fn dialect_loads_from_toml() {
+        let dialect = CsvDialect::from_toml(
+            "delimiter = \";\"\nquote = \"'\"\ncase_insensitive = true\n\n\
+             [columns]\ntx = \"txn_id\"\nclient = \"customer\"\n\n\
+             [types]\ndep = \"deposit\"\n",
+        );
+        assert_eq!(dialect, Ok(partner_dialect()));
+
+        assert_eq!(CsvDialect::from_toml(""), Ok(CsvDialect::default()));
+        assert_eq!(
+            CsvDialect::from_toml("quoting = false"),
+            Ok(CsvDialect::new().with_quote(None))
+        );
+
+        for (toml, error) in [
+            ("delimiter = \";;\"", "delimiter must be a single character"),
+            (
+                "delimiter = \"'\"\nquote = \"'\"",
+                "delimiter and quote must differ",
+            ),
+            ("[columns]\nid = \"txn_id\"", "unknown column id"),
+            (
+                "[types]\nrefund = \"credit\"",
+                "refund is aliased to unknown type credit",
+            ),
+            ("seperator = \";\"", "unknown field `seperator`"),
+        ] {
+            let res = CsvDialect::from_toml(toml);
+            assert!(
+                matches!(&res, Err(e) if e.starts_with(error)),
+                "{}: {:?}",
+                toml,
+                res
+            );
+        }
+    }
+
+    #[test]
+    fn events_carry_their_source_line() {
+        let events =
+            ingest("type,client,tx,amount\ndeposit,1,1,1.0\nbogus,1,2,\nwithdrawal,1,2,0.5\n");
+
+        let lines: Vec<u64> = events.iter().map(|e| e.pos().line).collect();
+        assert_eq!(lines, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn malformed_row_keeps_whatever_ids_it_can() {
+        let events = ingest("type,client,tx,amount\ndeposit,1,notanumber,1.0\nbogus,2,3,\n");
+
+        match &events[0] {
+            IngestEvent::MalformedRow {
+                pos, client, tx, ..
+            } => {
+                assert_eq!(pos.line, 2);
+                assert_eq!(*client, Some(1));
+                assert_eq!(*tx, None);
+            }
+            other => panic!("expected malformed row, got {:?}", other),
+        }
+        assert!(matches!(
+            events[1],
+            IngestEvent::UnknownType {
+                client: 2,
+                tx: 3,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn bad_amount_is_malformed_not_missing() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.23456\n";
+        let events = ingest(input);
+
+        match &events[1] {
+            IngestEvent::MalformedRow {
+                pos, raw, error, ..
+            } => {
+                assert_eq!(pos.line, 3);
+                assert_eq!(pos.byte, 38);
+                assert_eq!(raw, "deposit,1,2,1.23456");
+                assert_eq!(*error, CoreError::TooManyDecimals);
+            }
+            other => panic!("expected malformed row, got {:?}", other),
+        }
+        assert_eq!(
+            events[1].to_string(),
+            "too many decimal places at line 3 (byte 38): \"deposit,1,2,1.23456\""
+        );
+    }
+
+    #[test]
+    fn raw_record_reads_back_as_the_same_fields() {
+        let events =
+            ingest("type,client,tx,amount\ndeposit,1,1,\"1,000\"\ndeposit,1,2,\"1\"\"\"\n");
+        let raws: Vec<_> = events
+            .iter()
+            .map(|e| match e {
+                IngestEvent::MalformedRow { raw, .. } => raw.as_str(),
+                other => panic!("expected malformed row, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(raws, ["deposit,1,1,\"1,000\"", "deposit,1,2,\"1\"\"\""]);
+
+        let events: Vec<_> = CsvIngester::new()
+            .with_dialect(partner_dialect())
+            .ingest(Box::new(
+                "type;client;tx;amount\ndeposit;1;1;'1;0'\n".as_bytes(),
+            ))
+            .collect();
+        assert!(
+            matches!(&events[0], IngestEvent::MalformedRow { raw, .. } if raw == "deposit;1;1;'1;0'")
+        );
+    }
+
+    #[test]
+    fn deserialize_errors_keep_the_csv_detail() {
+        let events = ingest("type,client,tx,amount\ndeposit,x,1,1.0\n");
+
+        match &events[0] {
+            IngestEvent::MalformedRow { error, .. } => {
+                assert!(matches!(error, CoreError::InvalidRecord(m) if m.contains("field 1")));
+            }
+            other => panic!("expected malformed row, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn strict_mode_stops_after_first_bad_row() {
+        let input =
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.23456\ndeposit,1,3,1.0\n";
+
+        let lenient = ingest(input);
+        assert_eq!(lenient.len(), 3);
+        assert!(matches!(lenient[2], IngestEvent::Tx { .. }));
+
+        let strict: Vec<_> = CsvIngester::new()
+            .with_strict(true)
+            .ingest(Box::new(input.as_bytes()))
+            .collect();
+        assert_eq!(strict.len(), 2);
+        assert!(matches!(
+            strict[1],
+            IngestEvent::MalformedRow {
+                error: CoreError::TooManyDecimals,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn lock_rows_take_no_amount() {
+        let input = "type,client,tx,amount\nlock,1,0,\nunlock,1,0,1.0\n";
+
+        let kinds: Vec<_> = ingest(input)
+            .iter()
+            .map(|e| match e {
+                IngestEvent::Tx { tx, .. } => (tx.kind, tx.amount),
+                other => panic!("expected a tx, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (TransactionType::Lock, None),
+                (TransactionType::Unlock, None)
+            ]
+        );
+
+        let strict: Vec<_> = CsvIngester::new()
+            .with_strict(true)
+            .ingest(Box::new(input.as_bytes()))
+            .collect();
+        assert!(matches!(
+            strict[1],
+            IngestEvent::MalformedRow {
+                error: CoreError::UnexpectedAmount,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn reads_optional_timestamps() {
+        let events = ingest(
+            "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,1700000000\ndeposit,1,2,1.0,\ndeposit,1,3,1.0,yesterday\n",
+        );
+
+        let times: Vec<_> = events
+            .iter()
+            .map(|e| match e {
+                IngestEvent::Tx { tx, .. } => Ok(tx.timestamp),
+                IngestEvent::MalformedRow { error, .. } => Err(error.clone()),
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            times,
+            vec![
+                Ok(Some(1_700_000_000)),
+                Ok(None),
+                Err(CoreError::InvalidTimestamp)
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_escalation_rows() {
+        let input =
+            "type,client,tx,amount\nrepresentment,1,1,\nprearbitration,1,1,2.0\narbitration,1,1,\n";
+
+        let kinds: Vec<_> = ingest(input)
+            .iter()
+            .map(|e| match e {
+                IngestEvent::Tx { tx, .. } => (tx.kind, tx.amount),
+                other => panic!("expected a tx, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (TransactionType::Representment, None),
+                (TransactionType::PreArbitration, None),
+                (TransactionType::Arbitration, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn dispute_rows_may_carry_a_partial_amount() {
+        let events = ingest("type,client,tx,amount\ndispute,1,1,2.5\nresolve,1,1,\n");
+
+        match (&events[0], &events[1]) {
+            (IngestEvent::Tx { tx: a, .. }, IngestEvent::Tx { tx: b, .. }) => {
+                assert_eq!(a.amount, Some(Amount::from_str_4dp("2.5").unwrap()));
+                assert_eq!(b.amount, None);
+            }
+            other => panic!("expected two txs, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn journal_writer_writes_before_and_after() {
+        use crate::engine::journal::Balances;
+
+        let entry = JournalEntry {
+            seq: 1,
+            line: 2,
+            kind: TransactionType::Deposit,
+            client: 1,
+            asset: Asset::default(),
+            tx: 1,
+            amount: Some(Amount::from_str_4dp("1.5").unwrap()),
+            before: Balances::default(),
+            after: Balances {
+                available: Amount::from_str_4dp("1.5").unwrap(),
+                held: Amount::zero(),
+                locked: false,
+            },
+        };
+
+        let mut buf = Vec::new();
+        {
+            let mut w = CsvJournalWriter::new(&mut buf).unwrap();
+            w.append(&entry).unwrap();
+            w.flush().unwrap();
+        }
+
+        let out = String::from_utf8(buf).unwrap();
+        let mut lines = out.lines();
+        assert!(
+            lines
+                .next()
+                .unwrap()
+                .starts_with("seq,line,type,client,asset,tx,amount,")
+        );
+        assert_eq!(
+            lines.next().unwrap(),
+            "1,2,deposit,1,,1,1.5000,0.0000,0.0000,false,1.5000,0.0000,false"
+        );
+    }
+
+    #[test]
+    fn asset_column_is_optional_and_normalised() {
+        let events = ingest("type,client,tx,amount,asset\ndeposit,1,1,1.0,eur\ndeposit,1,2,1.0,\n");
+
+        match (&events[0], &events[1]) {
+            (IngestEvent::Tx { tx: a, .. }, IngestEvent::Tx { tx: b, .. }) => {
+                assert_eq!(a.asset.as_str(), "EUR");
+                assert!(b.asset.is_default());
+            }
+            other => panic!("expected two txs, got {:?}", other),
+        }
+
+        let events = ingest("type,client,tx,amount,asset\ndeposit,1,1,1.0,NOT-AN-ASSET\n");
+        assert!(matches!(
+            events[0],
+            IngestEvent::MalformedRow {
+                error: CoreError::InvalidAsset,
+                ..
+            }
+        ));
+    }
+
+    fn btc_8dp_jpy_0dp() -> Precisions {
+        let mut p = Precisions::new();
+        p.insert(Asset::parse("BTC").unwrap(), 8).unwrap();
+        p.insert(Asset::parse("JPY").unwrap(), 0).unwrap();
+        p
+    }
+
+    #[test]
+    fn amounts_are_parsed_at_their_assets_precision() {
+        let events: Vec<_> = CsvIngester::new()
+            .with_precisions(btc_8dp_jpy_0dp())
+            .ingest(Box::new(
+                "type,client,tx,amount,asset\n\
+                 deposit,1,1,0.00000001,btc\n\
+                 deposit,1,2,1500,jpy\n\
+                 deposit,1,3,1500.5,jpy\n\
+                 deposit,1,4,1.00001,usd\n"
+                    .as_bytes(),
+            ))
+            .collect();
+
+        let amounts: Vec<_> = events
+            .iter()
+            .map(|e| match e {
+                IngestEvent::Tx { tx, .. } => Ok(tx.amount.unwrap().as_i64()),
+                IngestEvent::MalformedRow { error, .. } => Err(error.clone()),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                Ok(1),
+                Ok(1500),
+                Err(CoreError::TooManyDecimals),
+                Err(CoreError::TooManyDecimals),
+            ]
+        );
+    }
+
+    #[test]
+    fn emitter_prints_each_asset_at_its_precision() {
+        let rows = [
+            AccountRow {
+                client: 1,
+                asset: Asset::parse("BTC").unwrap(),
+                available: Amount::from_scaled(150_000_000),
+                held: Amount::zero(),
+                total: Amount::from_scaled(150_000_000),
+                locked: false,
+            },
+            AccountRow {
+                client: 1,
+                asset: Asset::parse("JPY").unwrap(),
+                available: Amount::from_scaled(1500),
+                held: Amount::zero(),
+                total: Amount::from_scaled(1500),
+                locked: false,
+            },
+        ];
+
+        let mut buf = Vec::new();
+        CsvEmitter::new()
+            .with_precisions(btc_8dp_jpy_0dp())
+            .emit(&rows, &mut buf)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,asset,available,held,total,locked\n\
+             1,BTC,1.50000000,0.00000000,1.50000000,false\n\
+             1,JPY,1500,0,1500,false\n"
+        );
+    }
+
+    fn row(client: ClientId, asset: &str, available: &str) -> AccountRow {
+        let available = Amount::from_str_4dp(available).unwrap();
+        AccountRow {
+            client,
+            asset: Asset::parse(asset).unwrap(),
+            available,
+            held: Amount::zero(),
+            total: available,
+            locked: false,
+        }
+    }
+
+    fn emit(rows: &[AccountRow]) -> String {
+        let mut buf = Vec::new();
+        CsvEmitter::new().emit(rows, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn emitter_adds_asset_column_only_when_needed() {
+        assert_eq!(
+            emit(&[row(1, "", "1.0")]),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+        assert_eq!(
+            emit(&[row(1, "EUR", "1.0"), row(1, "USD", "2.0")]),
+            "client,asset,available,held,total,locked\n\
+             1,EUR,1.0000,0.0000,1.0000,false\n\
+             1,USD,2.0000,0.0000,2.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn rounded_amounts_carry_their_delta() {
+        let input =
+            "type,client,tx,amount\ndeposit,1,1,1.00005\ndeposit,1,2,1.00004\ndeposit,1,3,1.0\n";
+
+        let rejected = ingest(input);
+        assert!(matches!(
+            rejected[0],
+            IngestEvent::MalformedRow {
+                error: CoreError::TooManyDecimals,
+                ..
+            }
+        ));
+
+        let events: Vec<_> = CsvIngester::new()
+            .with_rounding(RoundingPolicy::HalfEven)
+            .ingest(Box::new(input.as_bytes()))
+            .collect();
+
+        let entries: Vec<_> = events.iter().filter_map(|e| e.rounding(true)).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            (entries[0].tx, entries[0].amount.to_string()),
+            (1, "1.0000".to_string())
+        );
+        assert_eq!(entries[0].delta.to_string(), "-0.00005");
+        assert_eq!(entries[1].delta.to_string(), "-0.00004");
+
+        let mut buf = Vec::new();
+        {
+            let mut w = CsvRoundingWriter::new(&mut buf).unwrap();
+            w.record(&entries[0]).unwrap();
+            w.flush().unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "line,client,tx,asset,amount,delta,applied\n2,1,1,,1.0000,-0.00005,true\n"
+        );
+    }
+
+    #[test]
+    fn reject_writer_writes_codes() {
+        let mut buf = Vec::new();
+        {
+            let mut w = CsvRejectWriter::new(&mut buf).unwrap();
+            let ev = &ingest("type,client,tx,amount\nbogus,2,3,\n")[0];
+            w.reject(&ev.rejection(RejectReason::UnknownType)).unwrap();
+            w.reject(&Rejection {
+                line: 9,
+                client: None,
+                tx: None,
+                reason: RejectReason::MalformedRow,
+                detail: Some("too many decimal places".to_string()),
+            })
+            .unwrap();
+            w.flush().unwrap();
+        }
+
+        let out = String::from_utf8(buf).unwrap();
+        assert_eq!(
+            out,
+            "line,client,tx,reason,detail\n2,2,3,unknown_type,\n9,,,malformed_row,too many decimal places\n"
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use std::io::{BufRead, BufReader, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::errors::CoreError;
+use crate::core::types::{AccountRow, ClientId, Precisions, RoundingPolicy, Timestamp, TxId};
+use crate::io::formats::{Row, Rules, strict_cut};
+use crate::io::{Emitter, IngestEvent, Ingester, SourcePos};
+
+/// One object per line. Amounts are strings, so they're read as exactly as CSV ones.
+#[derive(Debug, Deserialize)]
+struct JsonRow {
+    #[serde(rename = "type")]
+    kind: String,
+    client: ClientId,
+    tx: TxId,
+    #[serde(default)]
+    amount: Option<String>,
+    #[serde(default)]
+    asset: Option<String>,
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+}
+
+impl From<JsonRow> for Row {
+    fn from(r: JsonRow) -> Self {
+        Row {
+            kind: r.kind,
+            client: r.client,
+            tx: r.tx,
+            amount: r.amount,
+            asset: r.asset,
+            timestamp: r.timestamp.map(|t| t.to_string()),
+        }
+    }
+}
+
+// best effort lookup so the rejects report can still name the client/tx of a bad line
+fn field<T: TryFrom<u64>>(value: &serde_json::Value, name: &str) -> Option<T> {
+    value.get(name)?.as_u64()?.try_into().ok()
+}
+
+/// Reads newline-delimited JSON (NDJSON), e.g.
+/// `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`. `asset` and `timestamp` are
+/// optional, as in CSV. Blank lines are skipped but counted. A byte order mark at the
+/// start of the input is ignored.
+#[derive(Debug, Default, Clone)]
+pub struct JsonLinesIngester {
+    strict: bool,
+    precisions: Precisions,
+    rounding: RoundingPolicy,
+}
+
+impl JsonLinesIngester {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See `CsvIngester::with_strict`.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// See `CsvIngester::with_precisions`.
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+
+    /// See `CsvIngester::with_rounding`.
+    pub fn with_rounding(mut self, rounding: RoundingPolicy) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    fn rules(&self) -> Rules {
+        Rules {
+            strict: self.strict,
+            precisions: self.precisions.clone(),
+            policy: self.rounding,
+        }
+    }
+
+    /// Parses one line; `None` if it's blank.
+    pub fn parse_line(&self, line: &str, pos: SourcePos) -> Option<IngestEvent> {
+        parse(&self.rules(), line, pos)
+    }
+}
+
+fn parse(rules: &Rules, line: &str, pos: SourcePos) -> Option<IngestEvent> {
+    let line = match pos.line {
+        1 => line.strip_prefix('\u{feff}').unwrap_or(line),
+        _ => line,
+    };
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    let malformed = |error: CoreError| {
+        let value = serde_json::from_str::<serde_json::Value>(line).unwrap_or_default();
+        IngestEvent::MalformedRow {
+            pos,
+            raw: line.to_string(),
+            error,
+            client: field(&value, "client"),
+            tx: field(&value, "tx"),
+        }
+    };
+
+    Some(match serde_json::from_str::<JsonRow>(line) {
+        Ok(row) => rules.event(row.into(), pos, &malformed),
+        Err(e) => malformed(CoreError::InvalidRecord(e.to_string())),
+    })
+}
+
+impl Ingester for JsonLinesIngester {
+    fn ingest<'a>(&self, input: Box<dyn Read + 'a>) -> Box<dyn Iterator<Item = IngestEvent> + 'a> {
+        let rules = self.rules();
+        let mut lines = BufReader::new(input).split(b'\n');
+        let mut pos = SourcePos { line: 0, byte: 0 };
+        let mut failed = false;
+
+        let iter = std::iter::from_fn(move || {
+            while !failed {
+                pos.line += 1;
+                let at = pos;
+                let line = match lines.next()? {
+                    Ok(line) => line,
+                    // nothing sensible can follow a failed read
+                    Err(e) => {
+                        failed = true;
+                        return Some(IngestEvent::MalformedRow {
+                            pos: at,
+                            raw: String::new(),
+                            error: CoreError::InvalidRecord(e.to_string()),
+                            client: None,
+                            tx: None,
+                        });
+                    }
+                };
+                pos.byte += line.len() as u64 + 1;
+
+                let event = match std::str::from_utf8(&line) {
+                    Ok(text) => parse(&rules, text, at),
+                    Err(e) => Some(IngestEvent::MalformedRow {
+                        pos: at,
+                        raw: String::from_utf8_lossy(&line).into_owned(),
+                        error: CoreError::InvalidRecord(e.to_string()),
+                        client: None,
+                        tx: None,
+                    }),
+                };
+                if event.is_some() {
+                    return event;
+                }
+            }
+            None
+        });
+
+        if !self.strict {
+            return Box::new(iter);
+        }
+        Box::new(iter.map_while(strict_cut()))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonAccount {
+    client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asset: Option<String>,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+/// Writes one JSON object per account and line, in the same order as `CsvEmitter`, e.g.
+/// `{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}`.
+/// Amounts are strings at their asset's precision; `asset` is left out for the default one.
+#[derive(Debug, Default, Clone)]
+pub struct JsonEmitter {
+    precisions: Precisions,
+}
+
+impl JsonEmitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prints each account's amounts with its asset's number of places.
+    pub fn with_precisions(mut self, precisions: Precisions) -> Self {
+        self.precisions = precisions;
+        self
+    }
+
+    fn record(&self, r: &AccountRow) -> JsonAccount {
+        let dp = self.precisions.decimals(r.asset);
+        JsonAccount {
+            client: r.client,
+            asset: (!r.asset.is_default()).then(|| r.asset.to_string()),
+            available: r.available.display(dp).to_string(),
+            held: r.held.display(dp).to_string(),
+            total: r.total.display(dp).to_string(),
+            locked: r.locked,
+        }
+    }
+
+    /// One account as a JSON value, for embedding in other documents such as serve replies.
+    pub fn account(&self, row: &AccountRow) -> serde_json::Value {
+        serde_json::to_value(self.record(row)).unwrap_or_default()
+    }
+}
+
+impl Emitter for JsonEmitter {
+    fn emit(&self, rows: &[AccountRow], out: &mut dyn Write) -> std::io::Result<()> {
+        let mut out = std::io::BufWriter::new(out);
+        for r in rows {
+            serde_json::to_writer(&mut out, &self.record(r))?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{Amount, TransactionType};
+    use crate::io::formats::csv::CsvIngester;
+
+    fn ingest(input: &str) -> Vec<IngestEvent> {
+        JsonLinesIngester::new()
+            .ingest(Box::new(input.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn lines_become_the_same_events_as_csv_rows() {
+        let json = ingest(concat!(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5","asset":"BTC","timestamp":7}"#,
+            "\n\n",
+            r#"{"type":"dispute","client":1,"tx":1}"#,
+            "\n",
+        ));
+        let csv: Vec<_> = CsvIngester::new()
+            .ingest(Box::new(
+                "type,client,tx,amount,asset,timestamp\ndeposit,1,1,1.5,BTC,7\ndispute,1,1,,,\n"
+                    .as_bytes(),
+            ))
+            .collect();
+
+        let tx = |e: &IngestEvent| match e {
+            IngestEvent::Tx { tx, .. } => *tx,
+            other => panic!("expected a tx, got {:?}", other),
+        };
+        assert_eq!(json.len(), 2);
+        for (j, c) in json.iter().zip(&csv) {
+            assert_eq!(format!("{:?}", tx(j)), format!("{:?}", tx(c)));
+        }
+        assert_eq!(json[1].pos().line, 3);
+        assert_eq!(
+            tx(&json[0]).amount,
+            Some(Amount::from_str_4dp("1.5").unwrap())
+        );
+    }
+
+    #[test]
+    fn a_leading_byte_order_mark_is_skipped() {
+        let events = ingest(concat!(
+            "\u{feff}",
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}"#,
+            "\n\u{feff}\n",
+        ));
+
+        assert!(matches!(events[0], IngestEvent::Tx { .. }));
+        assert!(matches!(
+            &events[1],
+            IngestEvent::MalformedRow { pos, .. } if pos.line == 2
+        ));
+    }
+
+    #[test]
+    fn bad_lines_are_malformed_or_unknown() {
+        let events = ingest(concat!(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":1.5}"#,
+            "\n",
+            r#"{"type":"refund","client":2,"tx":2}"#,
+            "\n",
+            "not json\n",
+            r#"{"type":"lock","client":3,"tx":3}"#,
+        ));
+
+        assert!(matches!(
+            events[0],
+            IngestEvent::MalformedRow {
+                client: Some(1),
+                tx: Some(1),
+                ..
+            }
+        ));
+        assert!(matches!(
+            events[1],
+            IngestEvent::UnknownType { client: 2, .. }
+        ));
+        assert!(matches!(
+            events[2],
+            IngestEvent::MalformedRow { client: None, .. }
+        ));
+        assert!(matches!(
+            &events[3],
+            IngestEvent::Tx { tx, .. } if tx.kind == TransactionType::Lock
+        ));
+
+        let strict: Vec<_> = JsonLinesIngester::new()
+            .with_strict(true)
+            .ingest(Box::new("not json\n{}\n".as_bytes()))
+            .collect();
+        assert_eq!(strict.len(), 1);
+    }
+
+    #[test]
+    fn accounts_are_written_one_per_line() {
+        let row = |client, asset: &str, available: &str| AccountRow {
+            client,
+            asset: crate::core::types::Asset::parse(asset).unwrap(),
+            available: Amount::from_str_4dp(available).unwrap(),
+            held: Amount::zero(),
+            total: Amount::from_str_4dp(available).unwrap(),
+            locked: false,
+        };
+        let mut out = Vec::new();
+        JsonEmitter::new()
+            .emit(&[row(1, "", "1.5"), row(1, "BTC", "0.25")], &mut out)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}"#,
+                "\n",
+                r#"{"client":1,"asset":"BTC","available":"0.2500","held":"0.0000","total":"0.2500","locked":false}"#,
+                "\n",
+            )
+        );
+    }
+}
@@ -1 +1,125 @@
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod binary;
 pub mod csv;
+pub mod json;
+
+use serde::Deserialize;
+
+use crate::core::errors::CoreError;
+use crate::core::types::{
+    Amount, Asset, ClientId, Precisions, RoundingPolicy, Timestamp, Transaction, TransactionType,
+    TxId,
+};
+use crate::io::{IngestEvent, SourcePos};
+
+/// A transaction row as read from a text format, before its fields are interpreted.
+#[derive(Debug, Deserialize)]
+struct Row {
+    #[serde(rename = "type")]
+    kind: String,
+    client: ClientId,
+    tx: TxId,
+    amount: Option<String>,
+    #[serde(default)]
+    asset: Option<String>,
+    #[serde(default)]
+    timestamp: Option<String>,
+}
+
+fn parse_kind(s: &str) -> Result<TransactionType, CoreError> {
+    match s.trim() {
+        "deposit" => Ok(TransactionType::Deposit),
+        "withdrawal" => Ok(TransactionType::Withdrawal),
+        "dispute" => Ok(TransactionType::Dispute),
+        "resolve" => Ok(TransactionType::Resolve),
+        "chargeback" => Ok(TransactionType::Chargeback),
+        "representment" => Ok(TransactionType::Representment),
+        "prearbitration" => Ok(TransactionType::PreArbitration),
+        "arbitration" => Ok(TransactionType::Arbitration),
+        "lock" => Ok(TransactionType::Lock),
+        "unlock" => Ok(TransactionType::Unlock),
+        _ => Err(CoreError::UnknownTransactionType),
+    }
+}
+
+/// How rows become events; shared by every text format so they agree on what's malformed.
+#[derive(Debug, Clone)]
+struct Rules {
+    strict: bool,
+    precisions: Precisions,
+    policy: RoundingPolicy,
+}
+
+impl Rules {
+    fn event(
+        &self,
+        row: Row,
+        pos: SourcePos,
+        malformed: &dyn Fn(CoreError) -> IngestEvent,
+    ) -> IngestEvent {
+        let kind = match parse_kind(&row.kind) {
+            Ok(k) => k,
+            Err(_) => {
+                return IngestEvent::UnknownType {
+                    pos,
+                    client: row.client,
+                    tx: row.tx,
+                };
+            }
+        };
+
+        let asset = match row.asset.as_deref().map(Asset::parse) {
+            None => Asset::default(),
+            Some(Ok(a)) => a,
+            Some(Err(e)) => return malformed(e),
+        };
+
+        let timestamp = match row.timestamp.as_deref().map(str::parse::<Timestamp>) {
+            None => None,
+            Some(Ok(t)) => Some(t),
+            Some(Err(_)) => return malformed(CoreError::InvalidTimestamp),
+        };
+
+        // on dispute, resolve and chargeback an amount makes them partial;
+        // lock, unlock and the escalation steps don't take one
+        let (amount, rounding) = match (kind, row.amount) {
+            (kind, Some(_)) if self.strict && !kind.takes_amount() => {
+                return malformed(CoreError::UnexpectedAmount);
+            }
+            (_, None) => (None, None),
+            (kind, Some(_)) if !kind.takes_amount() => (None, None),
+            (_, Some(a)) => {
+                match Amount::parse_rounded(&a, self.precisions.decimals(asset), self.policy) {
+                    Ok((v, delta)) => (Some(v), delta),
+                    Err(e) => return malformed(e),
+                }
+            }
+        };
+
+        IngestEvent::Tx {
+            tx: Transaction {
+                kind,
+                client: row.client,
+                tx: row.tx,
+                amount,
+                asset,
+                timestamp,
+            },
+            pos,
+            rounding,
+        }
+    }
+}
+
+// yields the offending row so the caller can report it, then stops
+fn strict_cut() -> impl FnMut(IngestEvent) -> Option<IngestEvent> {
+    let mut failed = false;
+    move |ev| {
+        if failed {
+            return None;
+        }
+        failed = !matches!(ev, IngestEvent::Tx { .. });
+        Some(ev)
+    }
+}
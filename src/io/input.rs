@@ -0,0 +1,215 @@
+//! Where an ingester's bytes come from: files or stdin, compressed or not, and several of
+//! them one after another.
+
+use std::cell::Cell;
+use std::io::Read;
+use std::rc::Rc;
+
+use crate::io::IngestEvent;
+
+/// The input path that means standard input.
+pub const STDIN: &str = "-";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Opens `path`, or stdin for `-`, decompressing it if need be.
+pub fn open(path: &str) -> std::io::Result<Box<dyn Read>> {
+    if path == STDIN {
+        return decompressed(Box::new(std::io::stdin().lock()));
+    }
+    decompressed(Box::new(std::fs::File::open(path)?))
+}
+
+/// Decompresses gzip or zstd input and passes anything else through. Goes by the first
+/// bytes rather than an extension, so piped input works too.
+pub fn decompressed<'a>(mut input: Box<dyn Read + 'a>) -> std::io::Result<Box<dyn Read + 'a>> {
+    let mut head = [0u8; 4];
+    let mut len = 0;
+    while len < head.len() {
+        match input.read(&mut head[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let head = &head[..len];
+    let whole = std::io::Cursor::new(head.to_vec()).chain(input);
+
+    if head.starts_with(GZIP_MAGIC) {
+        return gzip(whole);
+    }
+    if head.starts_with(ZSTD_MAGIC) {
+        return zstd(whole);
+    }
+    Ok(Box::new(whole))
+}
+
+#[cfg(feature = "compression")]
+fn gzip<'a>(input: impl Read + 'a) -> std::io::Result<Box<dyn Read + 'a>> {
+    // concatenated members, as `cat a.gz b.gz` makes, are one stream
+    Ok(Box::new(flate2::read::MultiGzDecoder::new(input)))
+}
+
+#[cfg(feature = "compression")]
+fn zstd<'a>(input: impl Read + 'a) -> std::io::Result<Box<dyn Read + 'a>> {
+    Ok(Box::new(zstd::Decoder::new(input)?))
+}
+
+#[cfg(not(feature = "compression"))]
+fn gzip<'a>(_: impl Read + 'a) -> std::io::Result<Box<dyn Read + 'a>> {
+    Err(std::io::Error::other(
+        "gzip input needs the compression feature",
+    ))
+}
+
+#[cfg(not(feature = "compression"))]
+fn zstd<'a>(_: impl Read + 'a) -> std::io::Result<Box<dyn Read + 'a>> {
+    Err(std::io::Error::other(
+        "zstd input needs the compression feature",
+    ))
+}
+
+/// Counts the bytes read through it, for the next input's offsets.
+struct Counting<'a> {
+    inner: Box<dyn Read + 'a>,
+    count: Rc<Cell<u64>>,
+}
+
+impl Read for Counting<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+type Events<'a> = Box<dyn Iterator<Item = IngestEvent> + 'a>;
+
+/// Ingests several inputs as one stream, in order, so later inputs can refer to txs from
+/// earlier ones. `ingest` is handed each input with its index, and all of them are started
+/// before the first event, so a missing file or bad header fails before anything is applied.
+///
+/// Positions run on as if the inputs had been concatenated: an input's lines continue
+/// after the last line read from the one before, and its bytes after all of that one's.
+pub fn concat<'a>(
+    inputs: Vec<Box<dyn Read + 'a>>,
+    mut ingest: impl FnMut(usize, Box<dyn Read + 'a>) -> std::io::Result<Events<'a>>,
+) -> std::io::Result<Events<'a>> {
+    let mut streams = Vec::with_capacity(inputs.len());
+    for (i, inner) in inputs.into_iter().enumerate() {
+        let count = Rc::new(Cell::new(0));
+        let counting = Counting {
+            inner,
+            count: count.clone(),
+        };
+        streams.push((ingest(i, Box::new(counting))?, count));
+    }
+
+    let mut streams = streams.into_iter();
+    let mut current = streams.next();
+    let (mut lines, mut bytes, mut last) = (0, 0, 0);
+    Ok(Box::new(std::iter::from_fn(move || {
+        loop {
+            let (events, count) = current.as_mut()?;
+            if let Some(mut event) = events.next() {
+                let pos = event.pos_mut();
+                last = last.max(pos.line);
+                pos.line += lines;
+                pos.byte += bytes;
+                return Some(event);
+            }
+            lines += std::mem::take(&mut last);
+            bytes += count.get();
+            current = streams.next();
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::formats::csv::CsvIngester;
+    use crate::io::{Ingester, SourcePos};
+
+    fn read(input: Box<dyn Read>) -> String {
+        let mut s = String::new();
+        decompressed(input).unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn plain_input_passes_through() {
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        assert_eq!(read(Box::new(csv.as_bytes())), csv);
+        assert_eq!(read(Box::new("ab".as_bytes())), "ab");
+        assert_eq!(read(Box::new(std::io::empty())), "");
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_input_is_sniffed() {
+        use std::io::Write;
+
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(csv.as_bytes()).unwrap();
+        let mut gz = gz.finish().unwrap();
+        // a second member, as appending to a .gz makes
+        let mut more = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        more.write_all(b"deposit,1,2,2.0\n").unwrap();
+        gz.extend(more.finish().unwrap());
+        assert_eq!(
+            read(Box::new(std::io::Cursor::new(gz))),
+            format!("{}deposit,1,2,2.0\n", csv)
+        );
+
+        let zst = zstd::encode_all(csv.as_bytes(), 0).unwrap();
+        assert_eq!(read(Box::new(std::io::Cursor::new(zst))), csv);
+    }
+
+    #[test]
+    fn inputs_run_on_as_one_stream() {
+        let first = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\n";
+        let second = "type,client,tx,amount\ndispute,1,1,\n";
+        let inputs: Vec<Box<dyn Read>> = vec![
+            Box::new(first.as_bytes()),
+            Box::new("type,client,tx,amount\n".as_bytes()),
+            Box::new(second.as_bytes()),
+        ];
+
+        let mut seen = Vec::new();
+        let events: Vec<_> = concat(inputs, |i, input| {
+            seen.push(i);
+            Ok(CsvIngester::new().ingest(input))
+        })
+        .unwrap()
+        .collect();
+        assert_eq!(seen, [0, 1, 2]);
+
+        let pos: Vec<_> = events.iter().map(|e| e.pos()).collect();
+        // the header-only input reached no line, so doesn't move the count
+        let byte = (first.len() + "type,client,tx,amount\n".len() * 2) as u64;
+        assert_eq!(
+            pos,
+            [
+                SourcePos { line: 2, byte: 22 },
+                SourcePos { line: 3, byte: 38 },
+                SourcePos { line: 5, byte }
+            ]
+        );
+        assert!(matches!(&events[2], IngestEvent::Tx { tx, .. } if tx.tx == 1));
+    }
+
+    #[test]
+    fn a_failing_input_fails_up_front() {
+        let inputs: Vec<Box<dyn Read>> = vec![Box::new("a".as_bytes()), Box::new("b".as_bytes())];
+        let res = concat(inputs, |i, _| match i {
+            0 => Ok(Box::new(std::iter::empty())),
+            _ => Err(std::io::Error::other("bad header")),
+        });
+        assert!(res.is_err());
+    }
+}
@@ -1,11 +1,166 @@
-use crate::core::types::{AccountRow, Transaction};
+use crate::core::errors::CoreError;
+use crate::core::types::{AccountRow, Amount, Asset, ClientId, RoundingDelta, Transaction, TxId};
+use crate::engine::journal::JournalEntry;
+use crate::engine::outcome::RejectReason;
+use std::fmt;
 use std::io::{Read, Write};
 
-#[derive(Debug)]
+/// Where an event came from in the input. `line` is 1-based and counts the header.
+/// Blank lines are skipped by the CSV reader and don't advance the count.
+/// `byte` is the offset of the start of the row.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SourcePos {
+    pub line: u64,
+    pub byte: u64,
+}
+
+#[derive(Debug, Clone)]
 pub enum IngestEvent {
-    Tx(Transaction),
-    MalformedRow,
-    UnknownType,
+    Tx {
+        tx: Transaction,
+        pos: SourcePos,
+        /// Set when the amount had excess places and the rounding policy rounded it.
+        rounding: Option<RoundingDelta>,
+    },
+    MalformedRow {
+        pos: SourcePos,
+        raw: String,
+        error: CoreError,
+        client: Option<ClientId>,
+        tx: Option<TxId>,
+    },
+    UnknownType {
+        pos: SourcePos,
+        client: ClientId,
+        tx: TxId,
+    },
+    /// Timestamped further back than the reordering window allows; see `Reorder`.
+    Late { tx: Transaction, pos: SourcePos },
+}
+
+impl IngestEvent {
+    pub fn pos(&self) -> SourcePos {
+        match self {
+            IngestEvent::Tx { pos, .. }
+            | IngestEvent::MalformedRow { pos, .. }
+            | IngestEvent::UnknownType { pos, .. }
+            | IngestEvent::Late { pos, .. } => *pos,
+        }
+    }
+
+    pub(crate) fn pos_mut(&mut self) -> &mut SourcePos {
+        match self {
+            IngestEvent::Tx { pos, .. }
+            | IngestEvent::MalformedRow { pos, .. }
+            | IngestEvent::UnknownType { pos, .. }
+            | IngestEvent::Late { pos, .. } => pos,
+        }
+    }
+
+    pub fn client(&self) -> Option<ClientId> {
+        match self {
+            IngestEvent::Tx { tx, .. } | IngestEvent::Late { tx, .. } => Some(tx.client),
+            IngestEvent::MalformedRow { client, .. } => *client,
+            IngestEvent::UnknownType { client, .. } => Some(*client),
+        }
+    }
+
+    pub fn tx_id(&self) -> Option<TxId> {
+        match self {
+            IngestEvent::Tx { tx, .. } | IngestEvent::Late { tx, .. } => Some(tx.tx),
+            IngestEvent::MalformedRow { tx, .. } => *tx,
+            IngestEvent::UnknownType { tx, .. } => Some(*tx),
+        }
+    }
+
+    /// Builds the rejects-report entry for this event.
+    pub fn rejection(&self, reason: RejectReason) -> Rejection {
+        let detail = match self {
+            IngestEvent::MalformedRow { error, .. } => Some(error.to_string()),
+            _ => None,
+        };
+
+        Rejection {
+            line: self.pos().line,
+            client: self.client(),
+            tx: self.tx_id(),
+            reason,
+            detail,
+        }
+    }
+
+    /// Builds the rounding-report entry for this event, if its amount was rounded.
+    pub fn rounding(&self, applied: bool) -> Option<RoundingEntry> {
+        match self {
+            IngestEvent::Tx {
+                tx,
+                pos,
+                rounding: Some(delta),
+            } => Some(RoundingEntry {
+                line: pos.line,
+                client: tx.client,
+                tx: tx.tx,
+                asset: tx.asset,
+                amount: tx.amount?,
+                delta: *delta,
+                applied,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IngestEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestEvent::Tx { tx, pos, .. } => {
+                write!(f, "{:?} tx {} at line {}", tx.kind, tx.tx, pos.line)
+            }
+            IngestEvent::MalformedRow {
+                pos, raw, error, ..
+            } => {
+                write!(
+                    f,
+                    "{} at line {} (byte {}): {:?}",
+                    error, pos.line, pos.byte, raw
+                )
+            }
+            IngestEvent::UnknownType { pos, tx, .. } => {
+                write!(
+                    f,
+                    "unknown transaction type for tx {} at line {}",
+                    tx, pos.line
+                )
+            }
+            IngestEvent::Late { tx, pos } => {
+                write!(f, "late {:?} tx {} at line {}", tx.kind, tx.tx, pos.line)
+            }
+        }
+    }
+}
+
+/// One dropped input row, as written to the rejects report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub line: u64,
+    pub client: Option<ClientId>,
+    pub tx: Option<TxId>,
+    pub reason: RejectReason,
+    pub detail: Option<String>,
+}
+
+/// One rounded amount, as written to the rounding report. `amount` is what was used;
+/// the exact input value is `amount - delta`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoundingEntry {
+    pub line: u64,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub asset: Asset,
+    pub amount: Amount,
+    pub delta: RoundingDelta,
+    /// Whether the event went on to be applied. Rejected rows moved no money.
+    pub applied: bool,
 }
 
 pub trait Ingester {
@@ -16,5 +171,50 @@ pub trait Emitter {
     fn emit(&self, rows: &[AccountRow], out: &mut dyn Write) -> std::io::Result<()>;
 }
 
+/// Streaming destination for rejected rows.
+pub trait RejectSink {
+    fn reject(&mut self, rejection: &Rejection) -> std::io::Result<()>;
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+/// Streaming destination for rounded amounts.
+pub trait RoundingSink {
+    fn record(&mut self, entry: &RoundingEntry) -> std::io::Result<()>;
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+/// Streaming destination for transactions, e.g. when converting between formats.
+pub trait TransactionSink {
+    fn append(&mut self, tx: &Transaction) -> std::io::Result<()>;
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+/// Streaming destination for journal entries.
+pub trait JournalSink {
+    fn append(&mut self, entry: &JournalEntry) -> std::io::Result<()>;
+    /// Writes out what's buffered; can be called any number of times.
+    fn flush(&mut self) -> std::io::Result<()>;
+
+    /// Ends the journal. Formats with a footer write it here, once; for the rest it's a
+    /// flush. Nothing can be appended afterwards.
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
 pub mod formats;
-pub use formats::csv::{CsvEmitter, CsvIngester};
+pub mod input;
+pub mod reorder;
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(feature = "arrow")]
+pub use formats::arrow::{ArrowEmitter, ArrowFormat, ArrowJournalWriter};
+pub use formats::binary::{BinaryEmitter, BinaryIngester, BinaryTransactionWriter};
+pub use formats::csv::{
+    CsvDialect, CsvEmitter, CsvIngester, CsvJournalWriter, CsvRejectWriter, CsvRoundingWriter,
+    CsvTransactionWriter,
+};
+pub use formats::json::{JsonEmitter, JsonLinesIngester};
+pub use reorder::Reorder;
+#[cfg(feature = "async")]
+pub use stream::{AsyncIngester, EventStream};
@@ -0,0 +1,237 @@
+//! Puts timestamped events back in chronological order before they reach the `Processor`.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+
+use crate::core::types::Timestamp;
+use crate::io::IngestEvent;
+
+/// Buffered events beyond this are released early, oldest first.
+pub const DEFAULT_REORDER_CAPACITY: usize = 100_000;
+
+/// Sorts a stream of events by timestamp, for inputs whose rows can be up to `lateness`
+/// seconds out of order.
+///
+/// Each event is held until the stream has moved `lateness` seconds past it, then released
+/// in timestamp order; ties keep their input order. An event timestamped more than
+/// `lateness` behind the latest one seen, or behind one already released, can't be put in
+/// place any more and comes out straight away as `IngestEvent::Late`.
+///
+/// Events without a timestamp, malformed rows included, keep their place relative to their
+/// neighbours: they pass straight through when nothing is buffered and queue behind
+/// whatever is otherwise.
+pub struct Reorder<I> {
+    events: I,
+    lateness: u64,
+    capacity: usize,
+    buffer: BinaryHeap<Reverse<Pending>>,
+    ready: VecDeque<IngestEvent>,
+    latest: Option<Timestamp>,
+    released: Option<Timestamp>,
+    arrivals: u64,
+    done: bool,
+}
+
+// ordered by timestamp, then arrival
+struct Pending {
+    key: (Timestamp, u64),
+    event: IngestEvent,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl<I: Iterator<Item = IngestEvent>> Reorder<I> {
+    pub fn new(events: I, lateness: u64) -> Self {
+        Self {
+            events,
+            lateness,
+            capacity: DEFAULT_REORDER_CAPACITY,
+            buffer: BinaryHeap::new(),
+            ready: VecDeque::new(),
+            latest: None,
+            released: None,
+            arrivals: 0,
+            done: false,
+        }
+    }
+
+    /// Caps how many events are buffered. When it's exceeded the oldest is released early,
+    /// so anything arriving behind it afterwards is late.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    fn accept(&mut self, event: IngestEvent) {
+        let seq = self.arrivals;
+        self.arrivals += 1;
+
+        let timestamp = match &event {
+            IngestEvent::Tx { tx, .. } => tx.timestamp,
+            _ => None,
+        };
+        let ts = match (timestamp, self.latest) {
+            (Some(ts), _) => ts,
+            (None, Some(latest)) if !self.buffer.is_empty() => latest,
+            (None, _) => {
+                self.ready.push_back(event);
+                return;
+            }
+        };
+
+        let behind = self
+            .latest
+            .is_some_and(|latest| ts.saturating_add(self.lateness) < latest);
+        if behind || self.released.is_some_and(|released| ts < released) {
+            if let IngestEvent::Tx { tx, pos, .. } = event {
+                self.ready.push_back(IngestEvent::Late { tx, pos });
+            }
+            return;
+        }
+
+        self.latest = Some(self.latest.map_or(ts, |latest| latest.max(ts)));
+        self.buffer.push(Reverse(Pending {
+            key: (ts, seq),
+            event,
+        }));
+        self.release();
+    }
+
+    fn release(&mut self) {
+        let latest = self.latest.unwrap_or_default();
+        while let Some(Reverse(next)) = self.buffer.peek() {
+            let due = next.key.0.saturating_add(self.lateness) <= latest;
+            if !due && self.buffer.len() <= self.capacity {
+                break;
+            }
+            if let Some(Reverse(next)) = self.buffer.pop() {
+                self.released = Some(next.key.0);
+                self.ready.push_back(next.event);
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = IngestEvent>> Iterator for Reorder<I> {
+    type Item = IngestEvent;
+
+    fn next(&mut self) -> Option<IngestEvent> {
+        loop {
+            if let Some(event) = self.ready.pop_front() {
+                return Some(event);
+            }
+            if self.done {
+                return self.buffer.pop().map(|Reverse(p)| p.event);
+            }
+            match self.events.next() {
+                Some(event) => self.accept(event),
+                None => self.done = true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::errors::CoreError;
+    use crate::core::types::{Asset, Transaction, TransactionType};
+    use crate::io::SourcePos;
+
+    fn at(tx: u32, timestamp: Option<Timestamp>) -> IngestEvent {
+        IngestEvent::Tx {
+            tx: Transaction {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx,
+                amount: None,
+                asset: Asset::default(),
+                timestamp,
+            },
+            pos: SourcePos::default(),
+            rounding: None,
+        }
+    }
+
+    fn order(events: impl Iterator<Item = IngestEvent>) -> Vec<(u32, bool)> {
+        events
+            .map(|e| match e {
+                IngestEvent::Tx { tx, .. } => (tx.tx, false),
+                IngestEvent::Late { tx, .. } => (tx.tx, true),
+                other => panic!("expected a tx, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sorts_within_the_window_and_flags_late_events() {
+        let events = vec![
+            at(1, Some(100)),
+            at(2, Some(130)),
+            at(3, Some(110)),
+            at(4, Some(110)),
+            at(5, Some(200)),
+            // 200 - 60 = 140: too far behind
+            at(6, Some(120)),
+            at(7, Some(150)),
+        ];
+
+        assert_eq!(
+            order(Reorder::new(events.into_iter(), 60)),
+            vec![
+                (1, false),
+                (3, false),
+                (4, false),
+                (2, false),
+                (6, true),
+                (7, false),
+                (5, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn events_without_timestamps_keep_their_place() {
+        let malformed = IngestEvent::MalformedRow {
+            pos: SourcePos::default(),
+            raw: String::new(),
+            error: CoreError::ParseAmount,
+            client: None,
+            tx: None,
+        };
+        let events = vec![at(1, None), at(2, Some(50)), malformed, at(3, Some(40))];
+
+        let out: Vec<_> = Reorder::new(events.into_iter(), 30)
+            .map(|e| e.tx_id())
+            .collect();
+        assert_eq!(out, vec![Some(1), Some(3), Some(2), None]);
+    }
+
+    #[test]
+    fn a_full_buffer_releases_early() {
+        let events = vec![at(1, Some(10)), at(2, Some(20)), at(3, Some(5))];
+
+        assert_eq!(
+            order(Reorder::new(events.into_iter(), 100).with_capacity(1)),
+            vec![(1, false), (3, true), (2, false)]
+        );
+    }
+}
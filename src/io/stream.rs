@@ -0,0 +1,43 @@
+//! Async counterparts of `Ingester`, for feeding the engine from tokio code.
+
+use std::pin::Pin;
+
+use futures_util::{Stream, StreamExt, stream};
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc;
+
+use crate::core::types::Transaction;
+use crate::io::{IngestEvent, SourcePos};
+
+pub type EventStream<'a> = Pin<Box<dyn Stream<Item = IngestEvent> + Send + 'a>>;
+
+/// Reads events from an async source, e.g. a `TcpStream`.
+///
+/// Input is only read as the stream is polled, so a consumer that falls behind stops the
+/// reads and the socket's buffers push back on the sender.
+pub trait AsyncIngester {
+    fn ingest_stream<'a>(&self, input: Box<dyn AsyncRead + Send + Unpin + 'a>) -> EventStream<'a>;
+}
+
+/// Wraps already-parsed transactions as events. `pos.line` counts them from 1.
+pub fn transactions<'a>(txs: impl Stream<Item = Transaction> + Send + 'a) -> EventStream<'a> {
+    Box::pin(
+        txs.zip(stream::iter(1..))
+            .map(|(tx, line)| IngestEvent::Tx {
+                tx,
+                pos: SourcePos { line, byte: 0 },
+                rounding: None,
+            }),
+    )
+}
+
+/// A bounded channel into the engine. Sending waits while `capacity` transactions are
+/// queued and not yet applied; the stream ends once every sender is dropped.
+pub fn channel(capacity: usize) -> (mpsc::Sender<Transaction>, EventStream<'static>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let txs = stream::unfold(
+        rx,
+        |mut rx| async move { rx.recv().await.map(|tx| (tx, rx)) },
+    );
+    (tx, transactions(txs))
+}
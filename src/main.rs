@@ -1,31 +1,383 @@
 use std::env;
 use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::process::ExitCode;
 
-use transactions_ledger::engine::{HashMapStore, Processor};
-use transactions_ledger::io::{CsvEmitter, CsvIngester, Emitter, Ingester};
+use transactions_ledger::core::types::AccountRow;
+use transactions_ledger::engine::{
+    ApplyOutcome, DepositsAndWithdrawals, EngineState, HashMapStore, ParallelProcessor, Processor,
+    RejectReason, SqliteStore, StrictViolation, TxStore,
+};
+use transactions_ledger::io::input;
+use transactions_ledger::io::{
+    BinaryEmitter, BinaryIngester, BinaryTransactionWriter, CsvEmitter, CsvIngester,
+    CsvJournalWriter, CsvRejectWriter, CsvRoundingWriter, CsvTransactionWriter, Emitter,
+    IngestEvent, Ingester, JournalSink, JsonEmitter, JsonLinesIngester, RejectSink, Reorder,
+    RoundingSink, TransactionSink,
+};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let input_path = env::args()
-        .nth(1)
-        .expect("usage: transactions_ledger <input.csv>");
-    let file = File::open(input_path)?;
+#[cfg(feature = "arrow")]
+use transactions_ledger::io::{ArrowEmitter, ArrowFormat, ArrowJournalWriter};
 
-    let ingester = CsvIngester;
-    let emitter = CsvEmitter;
+use cli::Format;
 
-    let mut processor = Processor::new(HashMapStore::new());
+mod cli;
+#[cfg(feature = "async")]
+mod serve;
 
-    for event in ingester.ingest(Box::new(file)) {
-        processor.apply_event(event);
+fn main() -> ExitCode {
+    let args = cli::Args::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, cli::USAGE);
+        std::process::exit(2);
+    });
+
+    if let Some(addr) = &args.serve {
+        return serve(&args, addr);
+    }
+
+    if let Some(output) = &args.convert {
+        return convert(&args, output).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        });
+    }
+
+    let res = match (&args.store, args.workers) {
+        (_, Some(workers)) => run_parallel(&args, workers),
+        (Some(path), None) => SqliteStore::open(path)
+            .map_err(Into::into)
+            .and_then(|store| run(&args, store)),
+        (None, None) => run(&args, HashMapStore::new()),
+    };
+
+    res.unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        ExitCode::FAILURE
+    })
+}
+
+/// The per-event outputs, written in input order by either kind of run.
+struct Reports {
+    rejects: Option<CsvRejectWriter<BufWriter<File>>>,
+    rounding: Option<CsvRoundingWriter<BufWriter<File>>>,
+}
+
+impl Reports {
+    fn open(args: &cli::Args) -> std::io::Result<Self> {
+        let rejects = match &args.rejects {
+            Some(path) => Some(CsvRejectWriter::new(BufWriter::new(File::create(path)?))?),
+            None => None,
+        };
+        let rounding = match &args.rounding_log {
+            Some(path) => Some(
+                CsvRoundingWriter::new(BufWriter::new(File::create(path)?))?
+                    .with_precisions(args.precisions.clone()),
+            ),
+            None => None,
+        };
+        Ok(Self { rejects, rounding })
+    }
+
+    /// Whether `record` needs to see the events.
+    fn wanted(&self) -> bool {
+        self.rejects.is_some() || self.rounding.is_some()
+    }
+
+    fn record(
+        &mut self,
+        event: &IngestEvent,
+        res: &Result<ApplyOutcome, StrictViolation>,
+    ) -> std::io::Result<()> {
+        let outcome = match res {
+            Ok(outcome) => *outcome,
+            Err(violation) => {
+                if let Some(sink) = self.rejects.as_mut() {
+                    sink.reject(&event.rejection(violation.reason))?;
+                }
+                return Ok(());
+            }
+        };
+
+        if let (Some(sink), ApplyOutcome::Rejected(reason)) = (self.rejects.as_mut(), outcome) {
+            sink.reject(&event.rejection(reason))?;
+        }
+
+        if let (Some(sink), Some(entry)) =
+            (self.rounding.as_mut(), event.rounding(outcome.is_applied()))
+        {
+            sink.record(&entry)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(sink) = self.rejects.as_mut() {
+            sink.flush()?;
+        }
+        if let Some(sink) = self.rounding.as_mut() {
+            sink.flush()?;
+        }
+        Ok(())
     }
+}
+
+fn events(args: &cli::Args) -> std::io::Result<Box<dyn Iterator<Item = IngestEvent>>> {
+    let inputs = args
+        .inputs
+        .iter()
+        .map(|path| input::open(path).map_err(naming(path)))
+        .collect::<std::io::Result<_>>()?;
+    let mut events = input::concat(inputs, |i, input| {
+        let path = &args.inputs[i];
+        ingest(args, path, input).map_err(naming(path))
+    })?;
 
-    let rows = processor.results();
+    if let Some(lateness) = args.reorder {
+        events = Box::new(Reorder::new(events, lateness));
+    }
+    Ok(events)
+}
+
+fn naming(path: &str) -> impl Fn(std::io::Error) -> std::io::Error + '_ {
+    move |e| std::io::Error::new(e.kind(), format!("{}: {}", path, e))
+}
+
+fn ingest(
+    args: &cli::Args,
+    path: &str,
+    input: Box<dyn Read>,
+) -> std::io::Result<Box<dyn Iterator<Item = IngestEvent>>> {
+    Ok(match args.input_format(path) {
+        Format::Csv => CsvIngester::new()
+            .with_strict(args.strict)
+            .with_precisions(args.precisions.clone())
+            .with_rounding(args.rounding)
+            .with_dialect(args.csv_dialect.clone())
+            .ingest(input),
+        Format::JsonLines => JsonLinesIngester::new()
+            .with_strict(args.strict)
+            .with_precisions(args.precisions.clone())
+            .with_rounding(args.rounding)
+            .ingest(input),
+        // refuse a file that isn't binary, or has amounts at other precisions, outright
+        Format::Binary => BinaryIngester::new()
+            .with_strict(args.strict)
+            .with_precisions(args.precisions.clone())
+            .open(input)?,
+        // the parser refuses these already
+        Format::Arrow | Format::Parquet => {
+            return Err(std::io::Error::other(
+                "arrow and parquet are output formats",
+            ));
+        }
+    })
+}
 
+fn emit(args: &cli::Args, rows: &[AccountRow]) -> std::io::Result<()> {
+    let emitter: Box<dyn Emitter> = match args.output_format {
+        Format::Csv => Box::new(CsvEmitter::new().with_precisions(args.precisions.clone())),
+        Format::JsonLines => Box::new(JsonEmitter::new().with_precisions(args.precisions.clone())),
+        Format::Binary => Box::new(BinaryEmitter::new().with_precisions(args.precisions.clone())),
+        #[cfg(feature = "arrow")]
+        Format::Arrow => {
+            Box::new(ArrowEmitter::new(ArrowFormat::Ipc).with_precisions(args.precisions.clone()))
+        }
+        #[cfg(feature = "arrow")]
+        Format::Parquet => Box::new(
+            ArrowEmitter::new(ArrowFormat::Parquet).with_precisions(args.precisions.clone()),
+        ),
+        // the parser refuses these without the feature
+        #[cfg(not(feature = "arrow"))]
+        Format::Arrow | Format::Parquet => {
+            return Err(std::io::Error::other(
+                "arrow output needs the arrow feature",
+            ));
+        }
+    };
     let mut out = std::io::stdout();
-    emitter.emit(&rows, &mut out)?;
+    emitter.emit(rows, &mut out)
+}
+
+/// Picks the journal's format by its extension.
+fn journal(args: &cli::Args, path: &str) -> std::io::Result<Box<dyn JournalSink>> {
+    let out = BufWriter::new(File::create(path)?);
+    Ok(match Format::of_path(path) {
+        #[cfg(feature = "arrow")]
+        Format::Arrow => Box::new(ArrowJournalWriter::new(
+            out,
+            ArrowFormat::Ipc,
+            &args.precisions,
+        )?),
+        #[cfg(feature = "arrow")]
+        Format::Parquet => Box::new(ArrowJournalWriter::new(
+            out,
+            ArrowFormat::Parquet,
+            &args.precisions,
+        )?),
+        _ => Box::new(CsvJournalWriter::new(out)?.with_precisions(args.precisions.clone())),
+    })
+}
+
+/// Rewrites the input's transactions in the output format. Rows that can't be read are
+/// left out and reported like rejected ones.
+fn convert(args: &cli::Args, output: &str) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let events = events(args)?;
+    let mut reports = Reports::open(args)?;
+
+    let out = BufWriter::new(File::create(output)?);
+    let mut sink: Box<dyn TransactionSink> = match args.output_format {
+        Format::Binary => Box::new(BinaryTransactionWriter::new(out, &args.precisions)?),
+        // the parser only lets csv through otherwise
+        _ => Box::new(CsvTransactionWriter::new(out)?.with_precisions(args.precisions.clone())),
+    };
+
+    let mut skipped = 0u64;
+    for event in events {
+        let outcome = match &event {
+            IngestEvent::Tx { tx, .. } => {
+                sink.append(tx)?;
+                ApplyOutcome::Applied
+            }
+            IngestEvent::MalformedRow { .. } => ApplyOutcome::Rejected(RejectReason::MalformedRow),
+            IngestEvent::UnknownType { .. } => ApplyOutcome::Rejected(RejectReason::UnknownType),
+            IngestEvent::Late { .. } => ApplyOutcome::Rejected(RejectReason::Late),
+        };
+        reports.record(&event, &Ok(outcome))?;
+
+        if !outcome.is_applied() {
+            if args.strict {
+                reports.flush()?;
+                eprintln!("error: {}", event);
+                return Ok(ExitCode::FAILURE);
+            }
+            skipped += 1;
+        }
+    }
+
+    sink.flush()?;
+    reports.flush()?;
+    if skipped > 0 {
+        eprintln!("skipped {} rows", skipped);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(feature = "async")]
+fn serve(args: &cli::Args, addr: &str) -> ExitCode {
+    serve::serve(args, addr).map_or_else(
+        |e| {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        },
+        |()| ExitCode::SUCCESS,
+    )
+}
+
+#[cfg(not(feature = "async"))]
+fn serve(_: &cli::Args, _: &str) -> ExitCode {
+    eprintln!("error: serve needs the async feature");
+    ExitCode::FAILURE
+}
+
+fn run<S: TxStore>(args: &cli::Args, store: S) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let events = events(args)?;
+    let mut reports = Reports::open(args)?;
+
+    let mut journal = match &args.journal {
+        Some(path) => Some(journal(args, path)?),
+        None => None,
+    };
+
+    let state = match &args.resume {
+        Some(path) => EngineState::load(BufReader::new(File::open(path)?), store)?,
+        None => EngineState::new(store),
+    };
+
+    let mut processor = Processor::from_state(state).with_strict(args.strict);
+    if journal.is_some() {
+        processor = processor.with_journal();
+    }
+    if args.withdrawal_disputes {
+        processor = processor.with_dispute_policy(DepositsAndWithdrawals);
+    }
+    if let Some(window) = args.dispute_window {
+        processor = processor.with_dispute_window(window);
+    }
+    if args.evict_expired {
+        processor = processor.with_eviction();
+    }
+
+    for event in events {
+        let source = reports.wanted().then(|| event.clone());
+
+        let res = processor.try_apply_event(event);
+        if let Some(ev) = &source {
+            reports.record(ev, &res)?;
+        }
+        if let (Some(sink), Some(entries)) = (journal.as_mut(), processor.journal_mut()) {
+            for entry in entries.drain() {
+                sink.append(&entry)?;
+            }
+        }
+
+        if let Err(violation) = res {
+            reports.flush()?;
+            // what was applied before the violation stays in a readable journal
+            if let Some(sink) = journal.as_mut() {
+                sink.finish()?;
+            }
+            eprintln!("error: {}", violation);
+            return Ok(ExitCode::FAILURE);
+        }
+    }
+
+    reports.flush()?;
+    if let Some(sink) = journal.as_mut() {
+        sink.finish()?;
+    }
+
+    // the store commits only once the snapshot describing it is written, so a run that
+    // stops early leaves both as the last run left them
+    match &args.snapshot {
+        Some(path) => {
+            let tmp = format!("{}.tmp", path);
+            processor
+                .state()
+                .save(BufWriter::new(File::create(&tmp)?))?;
+            processor.flush_store()?;
+            std::fs::rename(&tmp, path)?;
+        }
+        None => processor.flush_store()?,
+    }
+
+    emit(args, &processor.results())?;
 
     // for debugging or later dashboards/observability
     // eprintln!("metrics: {:?}", processor.metrics());
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_parallel(args: &cli::Args, workers: usize) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let events = events(args)?;
+    let mut reports = Reports::open(args)?;
+
+    let mut processor = ParallelProcessor::new(workers).with_strict(args.strict);
+    if args.withdrawal_disputes {
+        processor = processor.with_dispute_policy(DepositsAndWithdrawals);
+    }
+    if let Some(window) = args.dispute_window {
+        processor = processor.with_dispute_window(window);
+    }
+
+    let results = processor.run(events, |event, res| reports.record(event, res))?;
+    reports.flush()?;
+
+    if let Some(violation) = results.violation {
+        eprintln!("error: {}", violation);
+        return Ok(ExitCode::FAILURE);
+    }
+
+    emit(args, &results.rows)?;
+    Ok(ExitCode::SUCCESS)
 }
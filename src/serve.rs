@@ -0,0 +1,493 @@
+//! `serve`: a long-running engine behind a line protocol on a TCP socket.
+//!
+//! Each line a client sends is a CSV row (`type,client,tx,amount[,asset[,timestamp]]`, no
+//! header), a JSON object like the JSON Lines input, or a balance query: `balance,<client>`
+//! or `{"query":"balance","client":<client>}`. Every non-blank line gets one JSON line back.
+
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+
+use transactions_ledger::core::types::{AccountRow, ClientId};
+use transactions_ledger::engine::{
+    ApplyOutcome, DepositsAndWithdrawals, EngineState, HashMapStore, Processor,
+};
+use transactions_ledger::io::{
+    CsvIngester, IngestEvent, JsonEmitter, JsonLinesIngester, SourcePos,
+};
+
+use crate::cli;
+
+/// Checkpoints are written this often by default, counted in applied or rejected lines.
+pub const DEFAULT_CHECKPOINT_EVERY: u64 = 1000;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Lines queued for the engine before connections wait their turn.
+const QUEUE: usize = 1024;
+
+/// Longer lines are answered with an error instead of being buffered.
+const MAX_LINE: usize = 64 * 1024;
+
+enum Request {
+    Apply(IngestEvent),
+    Balance(ClientId),
+    Invalid(String),
+}
+
+/// Owns the processor; every connection's requests are applied here, one at a time.
+struct Engine {
+    processor: Processor<HashMapStore>,
+    /// Formats balance replies like JSON Lines output.
+    accounts: JsonEmitter,
+    checkpoint: Option<PathBuf>,
+    every: u64,
+    pending: u64,
+}
+
+impl Engine {
+    /// Picks up from the checkpoint, if there is one yet.
+    fn open(args: &cli::Args) -> Result<Self, Error> {
+        let checkpoint = args.checkpoint.as_ref().map(PathBuf::from);
+        let state = match &checkpoint {
+            Some(path) if path.exists() => EngineState::load(
+                io::BufReader::new(std::fs::File::open(path)?),
+                HashMapStore::new(),
+            )?,
+            _ => EngineState::new(HashMapStore::new()),
+        };
+
+        let mut processor = Processor::from_state(state);
+        if args.withdrawal_disputes {
+            processor = processor.with_dispute_policy(DepositsAndWithdrawals);
+        }
+        if let Some(window) = args.dispute_window {
+            processor = processor.with_dispute_window(window);
+        }
+        if args.evict_expired {
+            processor = processor.with_eviction();
+        }
+
+        Ok(Self {
+            processor,
+            accounts: JsonEmitter::new().with_precisions(args.precisions.clone()),
+            checkpoint,
+            every: args.checkpoint_every.unwrap_or(DEFAULT_CHECKPOINT_EVERY),
+            pending: 0,
+        })
+    }
+
+    /// A failed checkpoint is reported with the line that triggered it, and tried again
+    /// after the next batch; the line itself was applied.
+    fn handle(&mut self, request: Request) -> String {
+        let response = match request {
+            Request::Apply(event) => {
+                let source = event.clone();
+                let response = match self.processor.apply_event(event) {
+                    ApplyOutcome::Applied => json!({ "outcome": "applied" }),
+                    ApplyOutcome::Rejected(reason) => {
+                        let mut response =
+                            json!({ "outcome": "rejected", "reason": reason.code() });
+                        if let Some(detail) = source.rejection(reason).detail {
+                            response["detail"] = json!(detail);
+                        }
+                        response
+                    }
+                };
+                self.pending += 1;
+                if self.pending >= self.every
+                    && let Err(e) = self.save()
+                {
+                    eprintln!("checkpoint failed: {}", e);
+                    let mut response = response;
+                    response["error"] = json!(format!("checkpoint failed: {}", e));
+                    return response.to_string();
+                }
+                response
+            }
+            Request::Balance(client) => self.balance(client),
+            Request::Invalid(error) => json!({ "error": error }),
+        };
+        response.to_string()
+    }
+
+    fn balance(&self, client: ClientId) -> serde_json::Value {
+        let mut rows: Vec<_> = self
+            .processor
+            .state()
+            .accounts_iter()
+            .filter(|((c, _), _)| *c == client)
+            .map(|(&(client, asset), acc)| AccountRow {
+                client,
+                asset,
+                available: acc.available,
+                held: acc.held,
+                total: acc.total(),
+                locked: acc.locked,
+            })
+            .collect();
+        rows.sort_by_key(|r| r.asset);
+
+        let accounts: Vec<_> = rows.iter().map(|r| self.accounts.account(r)).collect();
+        json!({ "client": client, "accounts": accounts })
+    }
+
+    /// Writes the state next to the checkpoint and moves it into place, so a crash part
+    /// way leaves the previous one intact.
+    fn save(&mut self) -> Result<(), Error> {
+        self.pending = 0;
+        let Some(path) = &self.checkpoint else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        let file = io::BufWriter::new(std::fs::File::create(&tmp)?);
+        self.processor.state().save(file)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Listens on `addr` until Ctrl-C, then writes a last checkpoint.
+pub fn serve(args: &cli::Args, addr: &str) -> Result<(), Error> {
+    let engine = Engine::open(args)?;
+    let parsers = Parsers::new(args);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let listener = TcpListener::bind(addr).await?;
+        eprintln!("listening on {}", listener.local_addr()?);
+        run(listener, engine, parsers, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+    })?;
+    Ok(())
+}
+
+async fn run(
+    listener: TcpListener,
+    mut engine: Engine,
+    parsers: Parsers,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Error> {
+    let (queue, mut requests) = mpsc::channel::<(Request, oneshot::Sender<String>)>(QUEUE);
+    let mut shutdown = std::pin::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            Some((request, reply)) = requests.recv() => {
+                // the client may have hung up meanwhile; its line still counts
+                let _ = reply.send(engine.handle(request));
+            }
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(connection(stream, queue.clone(), parsers.clone()));
+                }
+                // e.g. out of file descriptors; the next accept may well succeed
+                Err(e) => eprintln!("accept failed: {}", e),
+            },
+            _ = &mut shutdown => break,
+        }
+    }
+
+    engine.save()
+}
+
+#[derive(Clone)]
+struct Parsers {
+    csv: CsvIngester,
+    json: JsonLinesIngester,
+}
+
+impl Parsers {
+    fn new(args: &cli::Args) -> Self {
+        Self {
+            csv: CsvIngester::new()
+                .with_precisions(args.precisions.clone())
+                .with_rounding(args.rounding),
+            json: JsonLinesIngester::new()
+                .with_precisions(args.precisions.clone())
+                .with_rounding(args.rounding),
+        }
+    }
+
+    fn request(&self, line: &str, pos: SourcePos) -> Option<Request> {
+        let line = line.trim();
+        if line.starts_with('{') {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap_or_default();
+            return match value.get("query") {
+                None => self.json.parse_line(line, pos).map(Request::Apply),
+                Some(query) if query == "balance" => {
+                    Some(balance(value.get("client").and_then(|c| c.as_u64())))
+                }
+                Some(query) => Some(Request::Invalid(format!("unknown query {}", query))),
+            };
+        }
+        match line.strip_prefix("balance,") {
+            Some(client) => Some(balance(client.trim().parse().ok())),
+            None => self.csv.parse_line(line, pos).map(Request::Apply),
+        }
+    }
+}
+
+fn balance(client: Option<u64>) -> Request {
+    match client.and_then(|c| ClientId::try_from(c).ok()) {
+        Some(client) => Request::Balance(client),
+        None => Request::Invalid("balance needs a client id".to_string()),
+    }
+}
+
+async fn connection(
+    stream: TcpStream,
+    queue: mpsc::Sender<(Request, oneshot::Sender<String>)>,
+    parsers: Parsers,
+) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = Lines::new(read);
+
+    while let Some((line, pos)) = lines.next().await? {
+        let request = match line {
+            Ok(line) => match parsers.request(&line, pos) {
+                Some(request) => request,
+                None => continue,
+            },
+            Err(error) => Request::Invalid(error),
+        };
+
+        let (reply, response) = oneshot::channel();
+        // a full queue holds the connection here, and so its sender
+        if queue.send((request, reply)).await.is_err() {
+            break;
+        }
+        let Ok(response) = response.await else {
+            break;
+        };
+        write.write_all(response.as_bytes()).await?;
+        write.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+/// Splits a connection into lines of at most `MAX_LINE` bytes, with where each starts.
+struct Lines<R> {
+    input: BufReader<R>,
+    next: SourcePos,
+}
+
+impl<R: AsyncRead + Unpin> Lines<R> {
+    fn new(input: R) -> Self {
+        Self {
+            input: BufReader::new(input),
+            next: SourcePos { line: 1, byte: 0 },
+        }
+    }
+
+    /// An `Err` line is one that can't be read, with what to reply instead.
+    async fn next(&mut self) -> io::Result<Option<(Result<String, String>, SourcePos)>> {
+        let pos = self.next;
+        let mut buf = Vec::new();
+        let mut len = (&mut self.input)
+            .take(MAX_LINE as u64 + 1)
+            .read_until(b'\n', &mut buf)
+            .await? as u64;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let line = if buf.ends_with(b"\n") || buf.len() <= MAX_LINE {
+            if buf.ends_with(b"\n") {
+                buf.pop();
+                if buf.ends_with(b"\r") {
+                    buf.pop();
+                }
+            }
+            String::from_utf8(buf).map_err(|_| "line isn't valid UTF-8".to_string())
+        } else {
+            // skip the rest of it, a bounded piece at a time
+            loop {
+                buf.clear();
+                let n = (&mut self.input)
+                    .take(MAX_LINE as u64)
+                    .read_until(b'\n', &mut buf)
+                    .await?;
+                len += n as u64;
+                if n == 0 || buf.ends_with(b"\n") {
+                    break;
+                }
+            }
+            Err(format!("line longer than {} bytes", MAX_LINE))
+        };
+
+        self.next = SourcePos {
+            line: pos.line + 1,
+            byte: pos.byte + len,
+        };
+        Ok(Some((line, pos)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn start(
+        args: cli::Args,
+    ) -> (
+        std::net::SocketAddr,
+        oneshot::Sender<()>,
+        tokio::task::JoinHandle<Result<(), Error>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = Engine::open(&args).unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run(listener, engine, Parsers::new(&args), async {
+            let _ = stopped.await;
+        }));
+        (addr, stop, server)
+    }
+
+    async fn send(addr: std::net::SocketAddr, lines: &[&str]) -> Vec<serde_json::Value> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        for line in lines {
+            write
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .unwrap();
+        }
+        write.shutdown().await.unwrap();
+
+        let mut responses = Vec::new();
+        let mut read = BufReader::new(read).lines();
+        while let Some(line) = read.next_line().await.unwrap() {
+            responses.push(serde_json::from_str(&line).unwrap());
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn clients_share_one_ledger() {
+        let (addr, stop, server) = start(cli::Args::default()).await;
+
+        let (a, b) = tokio::join!(
+            send(addr, &["deposit,1,1,2.0", "", "withdrawal,1,2,5.0"]),
+            send(
+                addr,
+                &[
+                    r#"{"type":"deposit","client":2,"tx":3,"amount":"1.25"}"#,
+                    "bogus,2,4,"
+                ]
+            ),
+        );
+        assert_eq!(a[0], json!({ "outcome": "applied" }));
+        assert_eq!(a[1]["reason"], "insufficient_funds");
+        assert_eq!(b[0], json!({ "outcome": "applied" }));
+        assert_eq!(b[1]["reason"], "unknown_type");
+
+        let balances = send(
+            addr,
+            &[
+                "balance,1",
+                r#"{"query":"balance","client":2}"#,
+                "balance,x",
+            ],
+        )
+        .await;
+        assert_eq!(balances[0]["accounts"][0]["available"], "2.0000");
+        assert_eq!(balances[1]["accounts"][0]["total"], "1.2500");
+        assert!(balances[2]["error"].is_string());
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn lines_are_bounded_and_placed() {
+        let long = "x".repeat(MAX_LINE + 10);
+        let input = format!("deposit,1,1,1.0\r\n{}\n\n", long).into_bytes();
+        let input = [&input[..], b"\xff\n", b"balance,1"].concat();
+
+        let mut lines = Lines::new(&input[..]);
+        let mut read = Vec::new();
+        while let Some((line, pos)) = lines.next().await.unwrap() {
+            read.push((
+                line.map_err(|e| e.split(' ').next().unwrap().to_string()),
+                pos,
+            ));
+        }
+
+        let at = |line, byte| SourcePos { line, byte };
+        let long_end = 17 + long.len() as u64 + 1;
+        assert_eq!(
+            read,
+            [
+                (Ok("deposit,1,1,1.0".to_string()), at(1, 0)),
+                (Err("line".to_string()), at(2, 17)),
+                (Ok(String::new()), at(3, long_end)),
+                (Err("line".to_string()), at(4, long_end + 1)),
+                (Ok("balance,1".to_string()), at(5, long_end + 3)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_checkpoint_keeps_the_server_up() {
+        let args = cli::Args {
+            checkpoint: Some("/nonexistent/dir/state.snap".to_string()),
+            checkpoint_every: Some(1),
+            ..Default::default()
+        };
+        let (addr, stop, server) = start(args).await;
+
+        let responses = send(addr, &["deposit,1,1,1.0", "deposit,1,2,1.0"]).await;
+        for response in &responses {
+            assert_eq!(response["outcome"], "applied");
+            assert!(
+                response["error"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("checkpoint failed")
+            );
+        }
+        let balance = send(addr, &["balance,1"]).await;
+        assert_eq!(balance[0]["accounts"][0]["available"], "2.0000");
+
+        // the last checkpoint on the way out still fails loudly
+        stop.send(()).unwrap();
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn state_survives_a_restart() {
+        let dir = std::env::temp_dir().join(format!("tl-serve-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let checkpoint = dir.join("state.snap");
+        let _ = std::fs::remove_file(&checkpoint);
+        let args = || cli::Args {
+            checkpoint: Some(checkpoint.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+
+        let (addr, stop, server) = start(args()).await;
+        send(addr, &["deposit,7,1,3.0"]).await;
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        let (addr, stop, server) = start(args()).await;
+        let responses = send(addr, &["deposit,7,1,3.0", "balance,7"]).await;
+        assert_eq!(responses[0]["reason"], "duplicate_tx");
+        assert_eq!(responses[1]["accounts"][0]["available"], "3.0000");
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,12 @@
 use proptest::prelude::*;
 use std::collections::HashMap;
 
-use transactions_ledger::core::types::{Amount, Transaction, TransactionType};
-use transactions_ledger::engine::{HashMapStore, Processor};
-use transactions_ledger::io::IngestEvent;
+use transactions_ledger::core::types::{Amount, Asset, Transaction, TransactionType};
+use transactions_ledger::engine::{
+    AccountKey, DepositsAndWithdrawals, HashMapStore, ParallelProcessor, Processor, SqliteStore,
+    TxStore,
+};
+use transactions_ledger::io::{IngestEvent, SourcePos};
 
 // --------- helpers to generate amounts/events ---------
 
@@ -14,7 +17,7 @@ fn amount_strategy() -> impl Strategy<Value = Amount> {
         // scaled means: 123456 -> "12.3456"
         let whole = scaled / 10_000;
         let frac = (scaled % 10_000).abs();
-        let s = format!("{}.{}", whole, format!("{:04}", frac));
+        let s = format!("{}.{:04}", whole, frac);
         Amount::from_str_4dp(&s).unwrap()
     })
 }
@@ -26,51 +29,97 @@ fn tx_kind_strategy() -> impl Strategy<Value = TransactionType> {
         Just(TransactionType::Dispute),
         Just(TransactionType::Resolve),
         Just(TransactionType::Chargeback),
+        // no unlock: locked accounts must stay immutable
+        Just(TransactionType::Lock),
+    ]
+}
+
+// escalation rows go through locks, so these only feed the balance invariants
+fn escalating_kind_strategy() -> impl Strategy<Value = TransactionType> {
+    prop_oneof![
+        4 => tx_kind_strategy(),
+        1 => Just(TransactionType::Representment),
+        1 => Just(TransactionType::PreArbitration),
+        1 => Just(TransactionType::Arbitration),
+    ]
+}
+
+fn asset_strategy() -> impl Strategy<Value = Asset> {
+    prop_oneof![
+        Just(Asset::default()),
+        Just(Asset::parse("USD").unwrap()),
+        Just(Asset::parse("BTC").unwrap()),
     ]
 }
 
 fn event_strategy(
+    kinds: impl Strategy<Value = TransactionType>,
     max_clients: u16,
     tx_id_pool: std::sync::Arc<std::sync::Mutex<Vec<u32>>>,
 ) -> impl Strategy<Value = IngestEvent> {
     // We want some disputes/resolves/chargebacks to reference real tx ids,
     // but also allow random/bogus ids.
     (
-        tx_kind_strategy(),
+        kinds,
         1u16..=max_clients,
         1u32..=50_000u32,
         prop::option::of(amount_strategy()),
+        asset_strategy(),
     )
-        .prop_map(move |(kind, client, tx, amount)| {
+        .prop_map(move |(kind, client, tx, amount, asset)| {
             match kind {
                 TransactionType::Deposit | TransactionType::Withdrawal => {
                     // store this txid as something that can be referenced later
                     tx_id_pool.lock().unwrap().push(tx);
-                    IngestEvent::Tx(Transaction {
+                    IngestEvent::Tx {
+                        tx: Transaction {
+                            kind,
+                            client,
+                            tx,
+                            amount,
+                            asset,
+                            timestamp: None,
+                        },
+                        pos: SourcePos::default(),
+                        rounding: None,
+                    }
+                }
+                TransactionType::Lock | TransactionType::Unlock => IngestEvent::Tx {
+                    tx: Transaction {
                         kind,
                         client,
                         tx,
-                        amount,
-                    })
-                }
-                TransactionType::Dispute
-                | TransactionType::Resolve
-                | TransactionType::Chargeback => {
+                        amount: None,
+                        asset,
+                        timestamp: None,
+                    },
+                    pos: SourcePos::default(),
+                    rounding: None,
+                },
+                _ => {
                     // pick a reference id sometimes from pool, sometimes random
                     let ref_tx = {
                         let pool = tx_id_pool.lock().unwrap();
-                        if !pool.is_empty() && rand::random::<u8>() % 2 == 0 {
+                        if !pool.is_empty() && rand::random::<u8>().is_multiple_of(2) {
                             pool[rand::random::<usize>() % pool.len()]
                         } else {
                             tx
                         }
                     };
-                    IngestEvent::Tx(Transaction {
-                        kind,
-                        client,
-                        tx: ref_tx,
-                        amount: None,
-                    })
+                    IngestEvent::Tx {
+                        tx: Transaction {
+                            kind,
+                            client,
+                            tx: ref_tx,
+                            // partial when given; mostly more than is disputable, which is fine
+                            amount: amount.filter(|_| kind.takes_amount()),
+                            // default means "the original tx's asset"; others may mismatch
+                            asset,
+                            timestamp: None,
+                        },
+                        pos: SourcePos::default(),
+                        rounding: None,
+                    }
                 }
             }
         })
@@ -78,13 +127,39 @@ fn event_strategy(
 
 fn stream_strategy(max_clients: u16) -> impl Strategy<Value = Vec<IngestEvent>> {
     let pool = std::sync::Arc::new(std::sync::Mutex::new(Vec::<u32>::new()));
-    prop::collection::vec(event_strategy(max_clients, pool), 1..500)
+    prop::collection::vec(
+        event_strategy(tx_kind_strategy(), max_clients, pool),
+        1..500,
+    )
+}
+
+fn escalating_stream_strategy(max_clients: u16) -> impl Strategy<Value = Vec<IngestEvent>> {
+    let pool = std::sync::Arc::new(std::sync::Mutex::new(Vec::<u32>::new()));
+    prop::collection::vec(
+        event_strategy(escalating_kind_strategy(), max_clients, pool),
+        1..500,
+    )
+}
+
+// few tx ids, so clients keep running into each other's
+fn crowded_stream_strategy(max_clients: u16) -> impl Strategy<Value = Vec<IngestEvent>> {
+    escalating_stream_strategy(max_clients).prop_map(|events| {
+        events
+            .into_iter()
+            .map(|mut ev| {
+                if let IngestEvent::Tx { tx, .. } = &mut ev {
+                    tx.tx %= 32;
+                }
+                ev
+            })
+            .collect()
+    })
 }
 
 // --------- invariants ---------
 
-fn assert_invariants(proc: &Processor<HashMapStore>) -> Result<(), TestCaseError> {
-    for (_client, acct) in proc.state().accounts_iter() {
+fn assert_invariants<S: TxStore>(proc: &Processor<S>) -> Result<(), TestCaseError> {
+    for (_key, acct) in proc.state().accounts_iter() {
         prop_assert_eq!(acct.total(), acct.available + acct.held);
         prop_assert!(acct.held.as_i64() >= 0);
         prop_assert_eq!(
@@ -95,46 +170,132 @@ fn assert_invariants(proc: &Processor<HashMapStore>) -> Result<(), TestCaseError
     Ok(())
 }
 
+fn check_invariants_hold<S: TxStore>(
+    mut proc: Processor<S>,
+    events: Vec<IngestEvent>,
+) -> Result<(), TestCaseError> {
+    for ev in events {
+        proc.apply_event(ev);
+
+        // invariants must hold after every event
+        assert_invariants(&proc)?;
+    }
+    Ok(())
+}
+
+fn check_locked_accounts_are_immutable<S: TxStore>(
+    store: S,
+    events: Vec<IngestEvent>,
+) -> Result<(), TestCaseError> {
+    let mut proc = Processor::new(store);
+    let mut locked_snapshots: HashMap<AccountKey, (Amount, Amount, bool)> = HashMap::new();
+
+    for ev in events {
+        proc.apply_event(ev);
+
+        for (&key, acct) in proc.state().accounts_iter() {
+            if acct.locked {
+                locked_snapshots
+                    .entry(key)
+                    .or_insert((acct.available, acct.held, acct.locked));
+            }
+        }
+
+        // verify locked accounts haven't drifted
+        for (key, (avail, held, locked)) in locked_snapshots.iter() {
+            let acct = proc.state().accounts.get(key).unwrap();
+            prop_assert_eq!(acct.available, *avail);
+            prop_assert_eq!(acct.held, *held);
+            prop_assert_eq!(acct.locked, *locked);
+        }
+    }
+    Ok(())
+}
+
+fn check_parallel_matches_sequential(
+    workers: usize,
+    events: Vec<IngestEvent>,
+    strict: bool,
+) -> Result<(), TestCaseError> {
+    let mut sequential = Processor::new(HashMapStore::new())
+        .with_dispute_policy(DepositsAndWithdrawals)
+        .with_strict(strict);
+    let mut expected = Vec::new();
+    for ev in &events {
+        let res = sequential.try_apply_event(ev.clone());
+        let stop = res.is_err();
+        expected.push(res);
+        if stop {
+            break;
+        }
+    }
+
+    let mut outcomes = Vec::new();
+    let results = ParallelProcessor::new(workers)
+        .with_dispute_policy(DepositsAndWithdrawals)
+        .with_strict(strict)
+        .with_capacity(8)
+        .run(events.into_iter(), |_, res| {
+            outcomes.push(res.clone());
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+
+    let violation = expected.last().and_then(|res| res.clone().err());
+    prop_assert_eq!(outcomes, expected);
+    // shards may have gone on past a violation, so only a full run has to match
+    if violation.is_none() {
+        prop_assert_eq!(results.rows, sequential.results());
+    }
+    prop_assert_eq!(results.violation, violation);
+    Ok(())
+}
+
 // --------- property tests ---------
 
 proptest! {
     #[test]
     fn invariants_hold_for_random_streams(events in stream_strategy(20)) {
-        let mut proc = Processor::new(HashMapStore::new());
+        check_invariants_hold(Processor::new(HashMapStore::new()), events)?;
+    }
 
-        for ev in events {
-            proc.apply_event(ev);
+    #[test]
+    fn invariants_hold_with_withdrawal_disputes(events in stream_strategy(20)) {
+        let proc = Processor::new(HashMapStore::new()).with_dispute_policy(DepositsAndWithdrawals);
+        check_invariants_hold(proc, events)?;
+    }
 
-            // invariants must hold after every event
-            assert_invariants(&proc)?;
-        }
+    #[test]
+    fn invariants_hold_through_escalated_disputes(events in escalating_stream_strategy(5)) {
+        let proc = Processor::new(HashMapStore::new()).with_dispute_policy(DepositsAndWithdrawals);
+        check_invariants_hold(proc, events)?;
+    }
+
+    #[test]
+    fn invariants_hold_for_random_streams_sqlite(events in stream_strategy(20)) {
+        check_invariants_hold(Processor::new(SqliteStore::open_in_memory().unwrap()), events)?;
     }
 }
 
 proptest! {
     #[test]
     fn locked_accounts_are_immutable(events in stream_strategy(10)) {
-        let mut proc = Processor::new(HashMapStore::new());
-        let mut locked_snapshots: HashMap<u16, (Amount, Amount, bool)> = HashMap::new();
-
-        for ev in events {
-            proc.apply_event(ev);
+        check_locked_accounts_are_immutable(HashMapStore::new(), events)?;
+    }
 
-            for (&client, acct) in proc.state().accounts_iter() {
-                if acct.locked {
-                    locked_snapshots.entry(client).or_insert(
-                        (acct.available, acct.held, acct.locked)
-                    );
-                }
-            }
+    #[test]
+    fn locked_accounts_are_immutable_sqlite(events in stream_strategy(10)) {
+        check_locked_accounts_are_immutable(SqliteStore::open_in_memory().unwrap(), events)?;
+    }
+}
 
-            // verify locked accounts haven't drifted
-            for (&client, (avail, held, locked)) in locked_snapshots.iter() {
-                let acct = proc.state().accounts.get(&client).unwrap();
-                prop_assert_eq!(acct.available, *avail);
-                prop_assert_eq!(acct.held, *held);
-                prop_assert_eq!(acct.locked, *locked);
-            }
-        }
+proptest! {
+    #[test]
+    fn parallel_runs_match_sequential_ones(
+        workers in 1usize..6,
+        events in crowded_stream_strategy(10),
+        strict in any::<bool>(),
+    ) {
+        check_parallel_matches_sequential(workers, events, strict)?;
     }
 }